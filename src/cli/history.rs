@@ -0,0 +1,131 @@
+//! `shovel owner-at` and `shovel balance-at`, answer ownership questions at a past block from
+//! `postgres::history`
+use color_eyre::eyre::{self, eyre};
+use sqlx::{Pool, Postgres};
+use starknet::core::types::FieldElement;
+
+use super::parse_token_id;
+use crate::db::postgres::history::{self, AtBlock};
+
+const OWNER_AT_USAGE: &str = "owner-at <contract_address> <block> [token_id]";
+const BALANCE_AT_USAGE: &str = "balance-at <contract_address> <block> <token_id> [account]";
+
+/// Prints the owner of an ERC721 token at a block, or every token with its owner if no token id
+/// is given
+pub async fn owner_at(args: &[String], pool: &Pool<Postgres>) -> eyre::Result<()> {
+    let (contract_address, block_number, rest) = parse_args(args, OWNER_AT_USAGE)?;
+    if rest.len() > 1 {
+        eyre::bail!(OWNER_AT_USAGE);
+    }
+
+    match rest.get(0) {
+        Some(token_id) => {
+            let token_id = parse_token_id(token_id)?;
+            match history::erc721_owner_at(pool, contract_address, token_id, block_number).await? {
+                AtBlock::Known(Some(owner)) => println!("{owner:#x}"),
+                AtBlock::Known(None) => println!("not minted at block {block_number}"),
+                AtBlock::Unknown { bootstrapped_block } => {
+                    println!("{}", unknown(bootstrapped_block));
+                }
+            }
+        }
+        None => {
+            let owners = history::erc721_owners_at(pool, contract_address, block_number).await?;
+            for (token_id, owner) in &owners {
+                match owner {
+                    AtBlock::Known(owner) => println!("{} {owner:#x}", token_id.to_decimal()),
+                    AtBlock::Unknown { bootstrapped_block } => {
+                        println!("{} {}", token_id.to_decimal(), unknown(*bootstrapped_block));
+                    }
+                }
+            }
+            println!("{} tokens at block {block_number}", owners.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the balance of an account for an ERC1155 token id at a block, or every account holding
+/// the token if no account is given
+pub async fn balance_at(args: &[String], pool: &Pool<Postgres>) -> eyre::Result<()> {
+    let (contract_address, block_number, rest) = parse_args(args, BALANCE_AT_USAGE)?;
+    let token_id = parse_token_id(rest.get(0).ok_or(eyre!(BALANCE_AT_USAGE))?)?;
+    if rest.len() > 2 {
+        eyre::bail!(BALANCE_AT_USAGE);
+    }
+
+    match rest.get(1) {
+        Some(account) => {
+            let account = FieldElement::from_hex_be(account)?;
+            let balance = history::erc1155_balance_at(
+                pool,
+                contract_address,
+                token_id,
+                account,
+                block_number,
+            )
+            .await?;
+            match balance {
+                AtBlock::Known(balance) => println!("{}", balance.to_decimal()),
+                AtBlock::Unknown { bootstrapped_block } => {
+                    println!("{}", unknown(bootstrapped_block));
+                }
+            }
+        }
+        None => {
+            let balances =
+                history::erc1155_balances_at(pool, contract_address, token_id, block_number)
+                    .await?;
+            let balances = match balances {
+                AtBlock::Known(balances) => balances,
+                AtBlock::Unknown { bootstrapped_block } => {
+                    println!("{}", unknown(bootstrapped_block));
+                    return Ok(());
+                }
+            };
+            for (account, balance) in &balances {
+                println!("{account:#x} {}", balance.to_decimal());
+            }
+            println!("{} holders at block {block_number}", balances.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn unknown(bootstrapped_block: u64) -> String {
+    format!("unknown, bootstrapped from chain state at block {bootstrapped_block}")
+}
+
+/// Parses `<contract_address> <block>` and returns the arguments following them
+fn parse_args<'a>(
+    args: &'a [String],
+    usage: &'static str,
+) -> eyre::Result<(FieldElement, u64, &'a [String])> {
+    let contract_address = args.get(0).ok_or(eyre!(usage))?;
+    let block_number = args.get(1).ok_or(eyre!(usage))?;
+
+    Ok((FieldElement::from_hex_be(contract_address)?, block_number.parse()?, &args[2..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_string()).collect()
+    }
+
+    #[test]
+    fn test_parses_point_in_time_args() {
+        let args = to_args(&["0x12", "630000", "7"]);
+        let (contract_address, block_number, rest) = parse_args(&args, OWNER_AT_USAGE).unwrap();
+        assert_eq!(contract_address, FieldElement::from(0x12_u32));
+        assert_eq!(block_number, 630_000);
+        assert_eq!(rest, ["7"]);
+
+        assert!(parse_args(&to_args(&["0x12"]), OWNER_AT_USAGE).is_err());
+        assert!(parse_args(&to_args(&["0x12", "latest"]), OWNER_AT_USAGE).is_err());
+    }
+}
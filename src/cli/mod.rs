@@ -0,0 +1,66 @@
+mod history;
+mod verify;
+
+use color_eyre::eyre::{self, eyre};
+use sqlx::{Pool, Postgres};
+use starknet::core::types::FieldElement;
+
+use crate::{common::types::CairoUint256, db::postgres, refresh};
+
+/// Runs a one-off command instead of the indexer
+///
+/// ```text
+/// shovel refresh <contract_address> [token_id]
+/// shovel query <contract_address> <json_path>
+/// shovel verify <contract_address> [--sample <count>] [--repair]
+/// shovel owner-at <contract_address> <block> [token_id]
+/// shovel balance-at <contract_address> <block> <token_id> [account]
+/// ```
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the command is unknown, its arguments are
+/// malformed or it fails
+pub async fn run(command: &str, args: &[String], pool: &Pool<Postgres>) -> eyre::Result<()> {
+    match command {
+        "refresh" => self::refresh(args, pool).await,
+        "query" => query(args, pool).await,
+        "verify" => verify::verify(args, pool).await,
+        "owner-at" => history::owner_at(args, pool).await,
+        "balance-at" => history::balance_at(args, pool).await,
+        _ => eyre::bail!("unknown command {command}"),
+    }
+}
+
+/// Queues a metadata refresh for a token, or for the whole contract if no token id is given
+async fn refresh(args: &[String], pool: &Pool<Postgres>) -> eyre::Result<()> {
+    let contract_address = args.get(0).ok_or(eyre!("refresh <contract_address> [token_id]"))?;
+    let contract_address = FieldElement::from_hex_be(contract_address)?;
+    let token_id = args.get(1).map(|token_id| parse_token_id(token_id)).transpose()?;
+
+    refresh::token::request_refresh(pool, contract_address, token_id).await?;
+    println!("Refresh queued");
+
+    Ok(())
+}
+
+/// Lists tokens of a contract whose raw metadata document matches a SQL/JSON path
+async fn query(args: &[String], pool: &Pool<Postgres>) -> eyre::Result<()> {
+    let usage = "query <contract_address> <json_path>";
+    let contract_address = args.get(0).ok_or(eyre!(usage))?;
+    let contract_address = FieldElement::from_hex_be(contract_address)?;
+    let json_path = args.get(1).ok_or(eyre!(usage))?;
+
+    let token_ids =
+        postgres::token::find_tokens_by_json_path(contract_address, json_path, pool).await?;
+    for token_id in &token_ids {
+        println!("{}", token_id.to_decimal());
+    }
+    println!("{} tokens match", token_ids.len());
+
+    Ok(())
+}
+
+/// Parses a decimal token id that fits in a felt into a `CairoUint256`
+fn parse_token_id(token_id: &str) -> eyre::Result<CairoUint256> {
+    Ok(CairoUint256::from_felt(FieldElement::from_dec_str(token_id)?))
+}
@@ -0,0 +1,467 @@
+//! `shovel verify`, audits stored ownership of a contract against chain state
+use color_eyre::eyre::{self, eyre};
+use sqlx::{Pool, Postgres, Transaction};
+use starknet::core::types::{BlockId, FieldElement};
+use std::str::FromStr;
+
+use crate::{
+    common::types::{CairoUint256, ContractType},
+    db::postgres,
+    rpc::{
+        provider::{is_node_error, RpcProvider},
+        state, StarknetRpc,
+    },
+};
+
+const USAGE: &str = "verify <contract_address> [--sample <count>] [--repair]";
+
+/// A stored value that doesn't match chain state
+#[derive(Debug)]
+enum Drift {
+    Owner {
+        erc721_id: i32,
+        token_id: CairoUint256,
+        stored: Option<FieldElement>,
+        on_chain: FieldElement,
+    },
+    Balance {
+        erc1155_id: i32,
+        token_id: CairoUint256,
+        account: FieldElement,
+        stored: CairoUint256,
+        on_chain: CairoUint256,
+    },
+}
+
+/// Compares `ownerOf` / `balanceOf` at the last synced block with stored owners or balances of
+/// a contract, every token or a random sample of them, and writes a drift report
+///
+/// Rows updated after the last synced block are skipped, the chain state we read doesn't know
+/// about them yet. With `--repair`, mismatched rows are overwritten with chain state.
+pub async fn verify(args: &[String], pool: &Pool<Postgres>) -> eyre::Result<()> {
+    let contract_address = args.get(0).ok_or(eyre!(USAGE))?;
+    let contract_address = FieldElement::from_hex_be(contract_address)?;
+
+    let mut sample_size = None;
+    let mut repair = false;
+    let mut flags = args.iter().skip(1);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--sample" => sample_size = Some(flags.next().ok_or(eyre!(USAGE))?.parse::<i64>()?),
+            "--repair" => repair = true,
+            _ => eyre::bail!(USAGE),
+        }
+    }
+
+    let rpc = StarknetRpc::mainnet()?;
+    let block_number = postgres::last_synced_block(pool).await?;
+
+    let contract_type = sqlx::query!(
+        r#"
+            SELECT contract_type::TEXT AS "contract_type!"
+            FROM contract_metadata
+            WHERE contract_address = $1
+        "#,
+        format!("{contract_address:#x}")
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(eyre!("{contract_address:#x} isn't indexed"))?
+    .contract_type;
+    let contract_type = ContractType::from_str(&contract_type)?;
+
+    println!("[verify] checking {contract_address:#x} at block {block_number}");
+    let mut transaction = pool.begin().await?;
+
+    let (checked, drifts) = match contract_type {
+        ContractType::Erc721 => {
+            verify_erc721(
+                contract_address,
+                block_number,
+                sample_size,
+                rpc.inner(),
+                &mut transaction,
+            )
+            .await?
+        }
+        ContractType::Erc1155 => {
+            verify_erc1155(
+                contract_address,
+                block_number,
+                sample_size,
+                rpc.inner(),
+                &mut transaction,
+            )
+            .await?
+        }
+    };
+
+    if repair {
+        for drift in &drifts {
+            repair_drift(drift, block_number, &mut transaction).await?;
+        }
+    }
+
+    let report_id =
+        insert_report(contract_address, block_number, checked, &drifts, repair, &mut transaction)
+            .await?;
+    transaction.commit().await?;
+
+    for drift in &drifts {
+        match drift {
+            Drift::Owner { token_id, stored, on_chain, .. } => println!(
+                "[verify] token #{} owner, stored: {}, on chain: {on_chain:#x}",
+                token_id.low,
+                stored.map(|owner| format!("{owner:#x}")).unwrap_or_default(),
+            ),
+            Drift::Balance { token_id, account, stored, on_chain, .. } => println!(
+                "[verify] token #{} balance of {account:#x}, stored: {}, on chain: {}",
+                token_id.low, stored.low, on_chain.low,
+            ),
+        }
+    }
+    println!(
+        "[verify] report #{report_id}: checked {checked}, {} mismatched{}",
+        drifts.len(),
+        if repair { ", repaired" } else { "" }
+    );
+
+    Ok(())
+}
+
+/// Compares `erc721_token.latest_owner` with `ownerOf`, returns the number of checked tokens and
+/// the mismatches
+async fn verify_erc721(
+    contract_address: FieldElement,
+    block_number: u64,
+    sample_size: Option<i64>,
+    rpc: &impl RpcProvider,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<(i32, Vec<Drift>)> {
+    // A NULL limit checks every token
+    let records = sqlx::query!(
+        r#"
+            SELECT id, token_id_low, token_id_high, latest_owner
+            FROM erc721_token
+            WHERE contract_address = $1 AND COALESCE(last_updated_block, 0) <= $2
+            ORDER BY RANDOM()
+            LIMIT $3
+        "#,
+        format!("{contract_address:#x}"),
+        i64::try_from(block_number)?,
+        sample_size
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let block_id = BlockId::Number(block_number);
+    let mut checked = 0;
+    let mut drifts = Vec::new();
+
+    for record in records {
+        let token_id = CairoUint256::new(
+            FieldElement::from_dec_str(&record.token_id_low)?,
+            FieldElement::from_dec_str(&record.token_id_high)?,
+        );
+        let stored = record.latest_owner.as_deref().map(FieldElement::from_hex_be).transpose()?;
+
+        let on_chain = match state::owner_of(contract_address, token_id, &block_id, rpc).await {
+            Ok(owner) => owner,
+            // Burned tokens don't have an owner anymore, the contract reverts
+            Err(e) if stored == Some(FieldElement::ZERO) && is_node_error(&e) => FieldElement::ZERO,
+            Err(e) => {
+                println!("[verify] can't get owner of token #{}: {e}", token_id.low);
+                continue;
+            }
+        };
+
+        checked += 1;
+        if stored != Some(on_chain) {
+            drifts.push(Drift::Owner { erc721_id: record.id, token_id, stored, on_chain });
+        }
+    }
+
+    Ok((checked, drifts))
+}
+
+/// Compares `erc1155_balances` with `balanceOf`, returns the number of checked balances and the
+/// mismatches
+///
+/// Balances still waiting for their bootstrap job are skipped, they miss what the account held
+/// before we saw it and the job will add it to them.
+async fn verify_erc1155(
+    contract_address: FieldElement,
+    block_number: u64,
+    sample_size: Option<i64>,
+    rpc: &impl RpcProvider,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<(i32, Vec<Drift>)> {
+    // A NULL limit checks every balance
+    let records = sqlx::query!(
+        r#"
+            SELECT
+                erc1155_balances.erc1155_id,
+                erc1155_balances.account,
+                erc1155_balances.balance_low,
+                erc1155_balances.balance_high,
+                erc1155_token.token_id_low,
+                erc1155_token.token_id_high
+            FROM erc1155_balances
+            JOIN erc1155_token ON erc1155_token.id = erc1155_balances.erc1155_id
+            WHERE
+                erc1155_token.contract_address = $1 AND
+                COALESCE(erc1155_balances.last_updated_block, 0) <= $2 AND
+                NOT EXISTS (
+                    SELECT 1
+                    FROM bootstrap_jobs
+                    WHERE
+                        bootstrap_jobs.task = 'ERC1155_BALANCE' AND
+                        bootstrap_jobs.contract_address = erc1155_token.contract_address AND
+                        bootstrap_jobs.token_id_low = erc1155_token.token_id_low AND
+                        bootstrap_jobs.token_id_high = erc1155_token.token_id_high AND
+                        bootstrap_jobs.account = erc1155_balances.account
+                )
+            ORDER BY RANDOM()
+            LIMIT $3
+        "#,
+        format!("{contract_address:#x}"),
+        i64::try_from(block_number)?,
+        sample_size
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let block_id = BlockId::Number(block_number);
+    let mut checked = 0;
+    let mut drifts = Vec::new();
+
+    for record in records {
+        let token_id = CairoUint256::new(
+            FieldElement::from_dec_str(&record.token_id_low)?,
+            FieldElement::from_dec_str(&record.token_id_high)?,
+        );
+        let account = FieldElement::from_hex_be(&record.account)?;
+        let stored = CairoUint256::new(
+            FieldElement::from_dec_str(&record.balance_low)?,
+            FieldElement::from_dec_str(&record.balance_high)?,
+        );
+
+        let on_chain =
+            match state::balance_of(contract_address, account, token_id, &block_id, rpc).await {
+                Ok(balance) => balance,
+                Err(e) => {
+                    println!(
+                        "[verify] can't get balance of {account:#x} for token #{}: {e}",
+                        token_id.low
+                    );
+                    continue;
+                }
+            };
+
+        checked += 1;
+        if stored != on_chain {
+            drifts.push(Drift::Balance {
+                erc1155_id: record.erc1155_id,
+                token_id,
+                account,
+                stored,
+                on_chain,
+            });
+        }
+    }
+
+    Ok((checked, drifts))
+}
+
+/// Overwrites a mismatched row with chain state at `block_number`, keeping ownership intervals
+/// and balance changes consistent with it
+async fn repair_drift(
+    drift: &Drift,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let block_number = i64::try_from(block_number)?;
+
+    match drift {
+        Drift::Owner { erc721_id, on_chain, .. } => {
+            sqlx::query!(
+                r#"
+                    UPDATE erc721_token
+                    SET latest_owner = $1, last_updated_block = $2
+                    WHERE id = $3
+                "#,
+                format!("{on_chain:#x}"),
+                block_number,
+                erc721_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            sqlx::query!(
+                r#"
+                    UPDATE erc721_owners
+                    SET valid_to_block = $1
+                    WHERE erc721_id = $2 AND valid_to_block IS NULL
+                "#,
+                block_number,
+                erc721_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            sqlx::query!(
+                r#"
+                    INSERT INTO erc721_owners(erc721_id, owner, valid_from_block)
+                    VALUES($1, $2, $3)
+                    ON CONFLICT (erc721_id, owner, valid_from_block) DO UPDATE
+                    SET valid_to_block = NULL
+                "#,
+                erc721_id,
+                format!("{on_chain:#x}"),
+                block_number
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        Drift::Balance { erc1155_id, token_id, account, stored, on_chain } => {
+            // A bootstrap job queued since we read the balance would add the balance from
+            // before it on top of chain state
+            let updated = sqlx::query!(
+                r#"
+                    UPDATE erc1155_balances
+                    SET balance_low = $1, balance_high = $2, last_updated_block = $3
+                    FROM erc1155_token
+                    WHERE
+                        erc1155_balances.erc1155_id = $4 AND
+                        erc1155_balances.account = $5 AND
+                        erc1155_token.id = erc1155_balances.erc1155_id AND
+                        NOT EXISTS (
+                            SELECT 1
+                            FROM bootstrap_jobs
+                            WHERE
+                                bootstrap_jobs.task = 'ERC1155_BALANCE' AND
+                                bootstrap_jobs.contract_address = erc1155_token.contract_address AND
+                                bootstrap_jobs.token_id_low = erc1155_token.token_id_low AND
+                                bootstrap_jobs.token_id_high = erc1155_token.token_id_high AND
+                                bootstrap_jobs.account = erc1155_balances.account
+                        )
+                "#,
+                on_chain.low.to_string(),
+                on_chain.high.to_string(),
+                block_number,
+                erc1155_id,
+                format!("{account:#x}")
+            )
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+
+            if updated == 0 {
+                println!(
+                    "[verify] not repairing balance of {account:#x} for token #{}, it's being \
+                     bootstrapped",
+                    token_id.low
+                );
+                return Ok(());
+            }
+
+            // Log the correction as a regular balance change
+            let is_credit = (on_chain.high, on_chain.low) > (stored.high, stored.low);
+            let amount = if is_credit { *on_chain - *stored } else { *stored - *on_chain };
+            sqlx::query!(
+                r#"
+                    INSERT INTO erc1155_balance_changes(
+                        erc1155_id,
+                        account,
+                        amount_low,
+                        amount_high,
+                        is_credit,
+                        balance_low,
+                        balance_high,
+                        block)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                "#,
+                erc1155_id,
+                format!("{account:#x}"),
+                amount.low.to_string(),
+                amount.high.to_string(),
+                is_credit,
+                on_chain.low.to_string(),
+                on_chain.high.to_string(),
+                block_number
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores a `drift_reports` record with an entry for every mismatch and returns its id
+async fn insert_report(
+    contract_address: FieldElement,
+    block_number: u64,
+    checked: i32,
+    drifts: &[Drift],
+    repaired: bool,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<i32> {
+    let report_id = sqlx::query!(
+        r#"
+            INSERT INTO drift_reports(contract_address, block, checked, mismatched, repaired)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id
+        "#,
+        format!("{contract_address:#x}"),
+        i64::try_from(block_number)?,
+        checked,
+        i32::try_from(drifts.len())?,
+        repaired
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    for drift in drifts {
+        // Owners are stored as hex addresses and balances as decimal uint256 halves, like in
+        // the tables they come from
+        let (token_id, account, stored, on_chain) = match drift {
+            Drift::Owner { token_id, stored, on_chain, .. } => (
+                token_id,
+                None,
+                stored.map(|owner| format!("{owner:#x}")).unwrap_or_default(),
+                format!("{on_chain:#x}"),
+            ),
+            Drift::Balance { token_id, account, stored, on_chain, .. } => (
+                token_id,
+                Some(format!("{account:#x}")),
+                format!("{},{}", stored.low, stored.high),
+                format!("{},{}", on_chain.low, on_chain.high),
+            ),
+        };
+
+        sqlx::query!(
+            r#"
+                INSERT INTO drift_report_entries(
+                    drift_report_id,
+                    token_id_low,
+                    token_id_high,
+                    account,
+                    stored,
+                    on_chain)
+                VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            report_id,
+            token_id.low.to_string(),
+            token_id.high.to_string(),
+            account,
+            stored,
+            on_chain
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    Ok(report_id)
+}
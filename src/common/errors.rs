@@ -1,5 +1,6 @@
 // Custom errors used through the project
-use std::env::VarError;
+use starknet::core::types::FieldElement;
+use std::{env::VarError, num::ParseIntError, string::FromUtf8Error, time::Duration};
 use thiserror::Error;
 use url::ParseError;
 
@@ -9,6 +10,88 @@ pub enum ConfigError {
     MissingField(#[from] VarError),
     #[error("Invalid rpc url")]
     InvalidRpcUrl(#[from] ParseError),
+    #[error("Invalid number in rpc settings")]
+    InvalidRpcNumber(#[from] ParseIntError),
+    #[error("RPC_MODE should be live, record or replay, got {0}")]
+    InvalidRpcMode(String),
+    #[error("Can't open rpc archive")]
+    InvalidRpcArchive(#[from] std::io::Error),
     #[error("Something is wrong with your connection string")]
     InvalidConnectionString(#[from] mongodb::error::Error),
 }
+
+#[derive(Debug, Error)]
+pub enum RpcError {
+    // The node answered with an error, e.g. a reverted call. Asking another node won't help
+    #[error("starknet error: {0}")]
+    Starknet(String),
+    // The endpoint couldn't answer for now, e.g. rate limits, server errors or connection
+    // failures. Asking again or another node might help
+    #[error("rpc endpoint unavailable: {0}")]
+    Unavailable(String),
+}
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("rate limited, retry after {0:?}")]
+    RateLimited(Duration),
+    #[error("rpc request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("unexpected http status {0}")]
+    Status(reqwest::StatusCode),
+    #[error("batch request failed: {0}")]
+    Batch(String),
+    // Transient failure of a batch shared by its requests
+    #[error("batch request failed, endpoint unavailable: {0}")]
+    BatchUnavailable(String),
+    #[error("rpc archive: {0}")]
+    Archive(String),
+}
+
+impl TransportError {
+    /// Whether sending the request again might succeed: timeouts, connection failures, rate
+    /// limits and server errors. Client errors and malformed responses won't go away.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            Self::RateLimited(_) | Self::Timeout(_) | Self::BatchUnavailable(_) => true,
+            Self::Status(status) => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::Json(_) | Self::Batch(_) | Self::Archive(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CairoStringError {
+    #[error("no felts to decode")]
+    Empty,
+    #[error("felt {0:#x} is too large for a short string")]
+    NotShortString(FieldElement),
+    #[error("string isn't valid UTF-8")]
+    InvalidUtf8(#[from] FromUtf8Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_classifies_transport_errors() {
+        assert!(TransportError::RateLimited(Duration::from_secs(1)).is_transient());
+        assert!(TransportError::Timeout(Duration::from_secs(30)).is_transient());
+        assert!(TransportError::Status(StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(TransportError::Status(StatusCode::BAD_GATEWAY).is_transient());
+        assert!(TransportError::BatchUnavailable("connection reset".to_string()).is_transient());
+
+        assert!(!TransportError::Status(StatusCode::UNAUTHORIZED).is_transient());
+        assert!(!TransportError::Json(serde_json::from_str::<u8>("{").unwrap_err()).is_transient());
+        assert!(!TransportError::Batch("no batches".to_string()).is_transient());
+    }
+}
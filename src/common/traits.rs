@@ -1,34 +1,97 @@
 use starknet::core::types::FieldElement;
 
-/// Trait for converting a `FieldElement` to a string
+use super::errors::CairoStringError;
+
+// Bytes held by a full `ByteArray` data word, and at most by a short string
+const BYTES_PER_WORD: usize = 31;
+
+/// Trait for decoding Cairo strings returned by contract calls to a string
 pub trait ToUtf8String {
-    fn to_utf8_string(&self) -> String;
+    /// # Errors
+    /// This function returns `CairoStringError` if the felts aren't a Cairo string or the bytes
+    /// aren't valid UTF-8
+    fn to_utf8_string(&self) -> Result<String, CairoStringError>;
 }
 
-/// Converts a `FieldElement` to a string by converting the bytes to ascii
-/// and trimming the null bytes.
+/// Decodes a Cairo short string, up to 31 bytes packed in a felt
 impl ToUtf8String for FieldElement {
-    fn to_utf8_string(&self) -> String {
-        let chars = self.to_bytes_be().into_iter().filter(|&v| v != 0_u8).collect();
+    fn to_utf8_string(&self) -> Result<String, CairoStringError> {
+        Ok(String::from_utf8(short_string_bytes(*self)?)?)
+    }
+}
 
-        String::from_utf8(chars).unwrap_or_default()
+/// Decodes a string of any Cairo encoding, tried in this order:
+/// - Cairo 1 `ByteArray`: data words count, 31-byte data words, pending word, pending word length
+/// - Cairo 0 length-prefixed array of short strings
+/// - Short strings without a length, including a single one
+///
+/// Bytes of every felt are joined before they're decoded, so characters may span felts.
+impl ToUtf8String for [FieldElement] {
+    fn to_utf8_string(&self) -> Result<String, CairoStringError> {
+        if self.is_empty() {
+            return Err(CairoStringError::Empty);
+        }
+
+        let bytes = match byte_array_bytes(self) {
+            Some(bytes) => bytes,
+            None if is_length_prefixed(self) => short_strings_bytes(&self[1..])?,
+            None => short_strings_bytes(self)?,
+        };
+
+        Ok(String::from_utf8(bytes)?)
     }
 }
 
-/// Converts a `Vec<FieldElement>` aka a Cairo string to UTF8 string
-impl ToUtf8String for Vec<FieldElement> {
-    fn to_utf8_string(&self) -> String {
-        // First element is the string length
-        let chars: Vec<u8> = self
-            .iter()
-            .skip(1)
-            .flat_map(|felt| {
-                let felt_bytes = felt.to_bytes_be();
-                felt_bytes.into_iter().filter(|&v| v != 0_u8).collect::<Vec<u8>>()
-            })
-            .collect();
-        String::from_utf8(chars).unwrap_or_default()
+/// Bytes of a short string, which are right aligned in the felt
+fn short_string_bytes(felt: FieldElement) -> Result<Vec<u8>, CairoStringError> {
+    let bytes = felt.to_bytes_be();
+    if bytes[0] != 0 {
+        return Err(CairoStringError::NotShortString(felt));
+    }
+
+    let start = bytes.iter().position(|&byte| byte != 0).unwrap_or(bytes.len());
+    Ok(bytes[start..].to_vec())
+}
+
+fn short_strings_bytes(felts: &[FieldElement]) -> Result<Vec<u8>, CairoStringError> {
+    let mut bytes = Vec::new();
+    for felt in felts {
+        bytes.append(&mut short_string_bytes(*felt)?);
+    }
+    Ok(bytes)
+}
+
+/// Bytes of a serialized `ByteArray`, `None` if the felts don't have its shape
+fn byte_array_bytes(felts: &[FieldElement]) -> Option<Vec<u8>> {
+    let (&words_count, rest) = felts.split_first()?;
+    let words_count = usize::try_from(u64::try_from(words_count).ok()?).ok()?;
+    if rest.len() != words_count.checked_add(2)? {
+        return None;
+    }
+
+    let (words, pending) = rest.split_at(words_count);
+    let pending_word = pending[0].to_bytes_be();
+    let pending_len = usize::try_from(u64::try_from(pending[1]).ok()?).ok()?;
+    if pending_len >= BYTES_PER_WORD || pending_word[..32 - pending_len].iter().any(|&b| b != 0) {
+        return None;
     }
+
+    let mut bytes = Vec::with_capacity(words_count * BYTES_PER_WORD + pending_len);
+    for word in words {
+        let word = word.to_bytes_be();
+        if word[0] != 0 {
+            return None;
+        }
+        bytes.extend_from_slice(&word[1..]);
+    }
+    bytes.extend_from_slice(&pending_word[32 - pending_len..]);
+
+    Some(bytes)
+}
+
+/// Whether the first felt is the count of the felts following it
+fn is_length_prefixed(felts: &[FieldElement]) -> bool {
+    felts.len() > 1 && felts[0] == FieldElement::from(felts.len() - 1)
 }
 
 #[cfg(test)]
@@ -40,16 +103,50 @@ mod tests {
     #[test]
     fn felt_to_string() {
         let encoded = felt!("0x7a65747375");
-        let decoded = encoded.to_utf8_string();
+        let decoded = encoded.to_utf8_string().unwrap();
 
         assert_eq!(decoded, "zetsu");
     }
 
     #[test]
     fn felt_array_to_string() {
-        let encoded = vec![felt!("0x2"), felt!("0x7a65747375"), felt!("0x626f6969")];
-        let decoded = encoded.to_utf8_string();
+        let encoded = [felt!("0x2"), felt!("0x7a65747375"), felt!("0x626f6969")];
+        let decoded = encoded.to_utf8_string().unwrap();
 
         assert_eq!(decoded, "zetsuboii");
     }
+
+    #[test]
+    fn byte_array_to_string() {
+        // "ipfs://" pending only
+        let encoded = [felt!("0x0"), felt!("0x697066733a2f2f"), felt!("0x7")];
+        assert_eq!(encoded.to_utf8_string().unwrap(), "ipfs://");
+
+        // 31 bytes data word, "é" (0xc3 0xa9) split between it and the pending word
+        let word = format!("0x{}c3", "61".repeat(30));
+        let encoded = [
+            felt!("0x1"),
+            FieldElement::from_hex_be(&word).unwrap(),
+            felt!("0xa921"),
+            felt!("0x2"),
+        ];
+        assert_eq!(encoded.to_utf8_string().unwrap(), format!("{}é!", "a".repeat(30)));
+
+        // Empty
+        let encoded = [felt!("0x0"), felt!("0x0"), felt!("0x0")];
+        assert_eq!(encoded.to_utf8_string().unwrap(), "");
+    }
+
+    #[test]
+    fn invalid_strings_fail() {
+        let empty: Vec<FieldElement> = Vec::new();
+        assert!(matches!(empty.to_utf8_string(), Err(CairoStringError::Empty)));
+
+        // Any felt over 31 bytes isn't a short string
+        let encoded = felt!("0x0100000000000000000000000000000000000000000000000000000000000000");
+        assert!(matches!(encoded.to_utf8_string(), Err(CairoStringError::NotShortString(_))));
+
+        let encoded = felt!("0xc328");
+        assert!(matches!(encoded.to_utf8_string(), Err(CairoStringError::InvalidUtf8(_))));
+    }
 }
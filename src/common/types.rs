@@ -2,7 +2,10 @@
 
 use serde::{Deserialize, Serialize};
 use starknet::core::types::FieldElement;
-use std::ops::{Add, Neg, Sub};
+use std::{
+    ops::{Add, Neg, Sub},
+    str::FromStr,
+};
 
 // felt!(2**128)
 pub const CAIRO_UINT128_SHIFT: FieldElement = FieldElement::from_mont([
@@ -82,6 +85,54 @@ impl CairoUint256 {
         CairoUint256 { low, high }
     }
 
+    /// Splits a `FieldElement` into the low and high 128 bits of a `CairoUint256`.
+    pub fn from_felt(value: FieldElement) -> Self {
+        let bytes = value.to_bytes_be();
+
+        let mut low = [0_u8; 32];
+        low[16..].copy_from_slice(&bytes[16..]);
+        let mut high = [0_u8; 32];
+        high[16..].copy_from_slice(&bytes[..16]);
+
+        // Both halves are below 2**128, so they're always valid felts
+        Self::new(
+            FieldElement::from_bytes_be(&low).unwrap(),
+            FieldElement::from_bytes_be(&high).unwrap(),
+        )
+    }
+
+    /// Returns the 32 big-endian bytes of the value, high half first.
+    pub fn to_bytes_be(self) -> [u8; 32] {
+        let mut bytes = [0_u8; 32];
+        bytes[..16].copy_from_slice(&self.high.to_bytes_be()[16..]);
+        bytes[16..].copy_from_slice(&self.low.to_bytes_be()[16..]);
+        bytes
+    }
+
+    /// Returns the value as 64 lowercase hex characters, without `0x`.
+    pub fn to_hex(self) -> String {
+        self.to_bytes_be().iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Returns the value in decimal.
+    pub fn to_decimal(self) -> String {
+        let mut bytes = self.to_bytes_be();
+        let mut digits = Vec::new();
+
+        // Long division by 10, one byte at a time
+        while bytes.iter().any(|&byte| byte != 0) || digits.is_empty() {
+            let mut remainder = 0_u16;
+            for byte in &mut bytes {
+                let value = (remainder << 8) | u16::from(*byte);
+                *byte = u8::try_from(value / 10).expect("quotient of a byte is a byte");
+                remainder = value % 10;
+            }
+            digits.push(char::from_digit(u32::from(remainder), 10).unwrap());
+        }
+
+        digits.iter().rev().collect()
+    }
+
     /// Returns the bitwise NOT of the `CairoUint256`.
     /// This is equivalent to `felt!(2**256 - 1) - self`.
     pub fn not(self) -> Self {
@@ -89,6 +140,66 @@ impl CairoUint256 {
     }
 }
 
+/// Token standards we index, stored as `t_contract_type` in postgres
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractType {
+    Erc721,
+    Erc1155,
+}
+
+impl ContractType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Erc721 => "ERC721",
+            Self::Erc1155 => "ERC1155",
+        }
+    }
+}
+
+impl FromStr for ContractType {
+    type Err = color_eyre::eyre::ErrReport;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ERC721" => Ok(Self::Erc721),
+            "ERC1155" => Ok(Self::Erc1155),
+            _ => color_eyre::eyre::bail!("unknown contract type {s}"),
+        }
+    }
+}
+
+/// Block on-chain metadata, i.e. token URIs, names and symbols, is read at. Set by
+/// `METADATA_READ_BLOCK`, refreshes always read at the latest block.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataReadBlock {
+    /// Block of the event we read it for
+    #[default]
+    Event,
+    /// Latest block
+    Latest,
+    /// Both, the latest value is stored and both reads are kept in `metadata_reads`
+    Both,
+}
+
+impl MetadataReadBlock {
+    pub fn from_env() -> color_eyre::eyre::Result<Self> {
+        std::env::var("METADATA_READ_BLOCK").map_or(Ok(Self::default()), |value| value.parse())
+    }
+}
+
+impl FromStr for MetadataReadBlock {
+    type Err = color_eyre::eyre::ErrReport;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "event" => Ok(Self::Event),
+            "latest" => Ok(Self::Latest),
+            "both" => Ok(Self::Both),
+            _ => color_eyre::eyre::bail!("unknown metadata read block {s}"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,6 +220,28 @@ mod tests {
         assert_eq!(a + b, CairoUint256::new(FieldElement::ZERO, FieldElement::ZERO));
     }
 
+    #[test]
+    fn test_from_felt() {
+        let value = CAIRO_UINT128_SHIFT * FieldElement::from(3u32) + FieldElement::from(7u32);
+        assert_eq!(
+            CairoUint256::from_felt(value),
+            CairoUint256::new(FieldElement::from(7u32), FieldElement::from(3u32))
+        );
+    }
+
+    #[test]
+    fn test_to_hex_and_decimal() {
+        let token_id = CairoUint256::new(FieldElement::from(255u32), FieldElement::ZERO);
+        assert_eq!(token_id.to_hex(), format!("{}ff", "0".repeat(62)));
+        assert_eq!(token_id.to_decimal(), "255");
+        assert_eq!(CairoUint256::ZERO.to_decimal(), "0");
+
+        // 2**128 + 1
+        let token_id = CairoUint256::new(FieldElement::ONE, FieldElement::ONE);
+        assert_eq!(token_id.to_hex(), format!("{0}1{0}1", "0".repeat(31)));
+        assert_eq!(token_id.to_decimal(), "340282366920938463463374607431768211457");
+    }
+
     #[test]
     fn test_sub() {
         let a = CairoUint256::new(FieldElement::from(100u32), FieldElement::from(20u32));
@@ -0,0 +1,545 @@
+//! Bootstraps contracts, tokens and balances whose history we didn't index from chain state,
+//! so indexing can start at any block
+//!
+//! Bootstrapped state is read at the block right before the event that revealed it, so the
+//! event applies on top of it as usual. The writer doesn't read chain state itself, it queues
+//! the reads in `bootstrap_jobs` for the metadata workers.
+use color_eyre::eyre;
+use sqlx::{Postgres, Transaction};
+use starknet::core::types::{BlockId, FieldElement};
+
+use crate::{
+    common::types::{CairoUint256, ContractType},
+    db::postgres::{
+        bootstrap_jobs::{self, BootstrapTask},
+        contract, metadata_jobs,
+    },
+    rpc::{
+        provider::{is_transient, RpcProvider},
+        state,
+    },
+};
+
+// ERC721Enumerable contracts with a larger supply are bootstrapped token by token instead
+const MAX_ENUMERATED_SUPPLY: u64 = 10_000;
+
+/// Returns the `contract_metadata` id of an ERC721 contract, registering it if it's new
+///
+/// Tokens a new contract already has before `block_number` are bootstrapped along with their
+/// owner by a bootstrap job if it's ERC721Enumerable, see `enumerate_erc721`
+pub async fn erc721_contract(
+    contract_address: FieldElement,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<i32> {
+    let exists = sqlx::query!(
+        "SELECT EXISTS(SELECT 1 FROM contract_metadata WHERE contract_address = $1)",
+        format!("{contract_address:#x}")
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .exists
+    .unwrap_or_default();
+
+    let contract_id = contract::get_or_register(
+        contract_address,
+        ContractType::Erc721,
+        block_number,
+        &mut *transaction,
+    )
+    .await?;
+
+    if !exists {
+        bootstrap_jobs::enqueue(
+            contract_address,
+            BootstrapTask::Erc721Tokens,
+            block_number,
+            &mut *transaction,
+        )
+        .await?;
+    }
+
+    Ok(contract_id)
+}
+
+/// Returns the `erc721_token` id of a token, bootstrapping it if we don't know it
+///
+/// `previous_owner` is the sender of the event, which owned the token right before it
+pub async fn erc721_token(
+    contract_id: i32,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    previous_owner: FieldElement,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<i32> {
+    if let Some(erc721_id) = find_erc721(contract_address, token_id, transaction).await? {
+        return Ok(erc721_id);
+    }
+
+    let state_block = block_number.saturating_sub(1);
+    let inserted = insert_erc721(
+        contract_id,
+        contract_address,
+        token_id,
+        previous_owner,
+        state_block,
+        transaction,
+    )
+    .await?;
+
+    // Bootstrapped by an enumeration job in the meantime
+    match inserted {
+        Some(erc721_id) => Ok(erc721_id),
+        None => find_erc721(contract_address, token_id, transaction)
+            .await?
+            .ok_or_else(|| eyre::eyre!("erc721 token #{} went missing", token_id.low)),
+    }
+}
+
+/// Returns the `erc1155_token` id of a token, bootstrapping it if we don't know it
+///
+/// Balances of bootstrapped tokens are seeded lazily by `erc1155_balance`
+pub async fn erc1155_token(
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<i32> {
+    let erc1155_id = sqlx::query!(
+        r#"
+            SELECT id
+            FROM erc1155_token
+            WHERE
+                contract_address = $1 AND
+                token_id_low = $2 AND
+                token_id_high = $3
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map(|record| record.id);
+
+    if let Some(erc1155_id) = erc1155_id {
+        return Ok(erc1155_id);
+    }
+
+    println!("[bootstrap] bootstrapping erc1155 token #{} of {contract_address:#x}", token_id.low);
+    let state_block = block_number.saturating_sub(1);
+    let contract_id = contract::get_or_register(
+        contract_address,
+        ContractType::Erc1155,
+        state_block,
+        &mut *transaction,
+    )
+    .await?;
+
+    let erc1155_id = sqlx::query!(
+        r#"
+            INSERT INTO erc1155_token(
+                contract_id,
+                contract_address,
+                token_id_low,
+                token_id_high,
+                last_updated_block,
+                bootstrapped_block)
+            VALUES($1, $2, $3, $4, $5, $5)
+            RETURNING id
+        "#,
+        contract_id,
+        format!("{contract_address:#x}"),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+        i64::try_from(state_block)?
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    metadata_jobs::enqueue(
+        contract_address,
+        ContractType::Erc1155,
+        token_id,
+        state_block,
+        &mut *transaction,
+    )
+    .await?;
+
+    Ok(erc1155_id)
+}
+
+/// Queues the balance of `account` before `block_number` to be seeded from chain state if the
+/// token was bootstrapped and we haven't seen the account holding it yet, see
+/// `seed_erc1155_balance`
+///
+/// Changes apply on top of a zero balance until it's seeded. Tokens we indexed from their mint
+/// are left alone, a missing balance is zero for them.
+pub async fn erc1155_balance(
+    erc1155_id: i32,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    account: FieldElement,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let needs_seed = sqlx::query!(
+        r#"
+            SELECT
+                erc1155_token.bootstrapped_block IS NOT NULL AND NOT EXISTS(
+                    SELECT 1
+                    FROM erc1155_balances
+                    WHERE erc1155_id = $1 AND account = $2
+                ) AS "needs_seed!"
+            FROM erc1155_token
+            WHERE id = $1
+        "#,
+        erc1155_id,
+        format!("{account:#x}")
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .needs_seed;
+
+    if !needs_seed {
+        return Ok(());
+    }
+
+    let state_block = block_number.saturating_sub(1);
+    sqlx::query!(
+        r#"
+            INSERT INTO erc1155_balances(
+                erc1155_id,
+                account,
+                balance_low,
+                balance_high,
+                last_updated_block)
+            VALUES ($1, $2, '0', '0', $3)
+            ON CONFLICT (erc1155_id, account) DO NOTHING
+        "#,
+        erc1155_id,
+        format!("{account:#x}"),
+        i64::try_from(state_block)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    bootstrap_jobs::enqueue(
+        contract_address,
+        BootstrapTask::Erc1155Balance { token_id, account },
+        block_number,
+        &mut *transaction,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Reads the balance `account` had before `block_number`, to be added with
+/// `seed_erc1155_balance`
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the call fails
+pub async fn read_erc1155_balance(
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    account: FieldElement,
+    block_number: u64,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<CairoUint256> {
+    let block_id = BlockId::Number(block_number.saturating_sub(1));
+    state::balance_of(contract_address, account, token_id, &block_id, rpc).await
+}
+
+/// Adds `seed`, the balance `account` had before `block_number`, to the balance and to the
+/// balance changes we applied since, and logs it as a credit at the block before
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the token isn't known or a query fails
+pub async fn seed_erc1155_balance(
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    account: FieldElement,
+    block_number: u64,
+    seed: CairoUint256,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let state_block = block_number.saturating_sub(1);
+
+    let erc1155_id = sqlx::query!(
+        r#"
+            SELECT id
+            FROM erc1155_token
+            WHERE
+                contract_address = $1 AND
+                token_id_low = $2 AND
+                token_id_high = $3
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    // Held until we commit, so transfers applied meanwhile wait for the seed
+    let balance = sqlx::query!(
+        r#"
+            SELECT balance_low, balance_high
+            FROM erc1155_balances
+            WHERE erc1155_id = $1 AND account = $2
+            FOR UPDATE
+        "#,
+        erc1155_id,
+        format!("{account:#x}")
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    let balance = parse_uint256(&balance.balance_low, &balance.balance_high)? + seed;
+
+    sqlx::query!(
+        r#"
+            UPDATE erc1155_balances
+            SET balance_low = $1, balance_high = $2
+            WHERE erc1155_id = $3 AND account = $4
+        "#,
+        balance.low.to_string(),
+        balance.high.to_string(),
+        erc1155_id,
+        format!("{account:#x}")
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let changes = sqlx::query!(
+        r#"
+            SELECT id, balance_low, balance_high
+            FROM erc1155_balance_changes
+            WHERE erc1155_id = $1 AND account = $2
+        "#,
+        erc1155_id,
+        format!("{account:#x}")
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    for change in changes {
+        let balance = parse_uint256(&change.balance_low, &change.balance_high)? + seed;
+        sqlx::query!(
+            "UPDATE erc1155_balance_changes SET balance_low = $1, balance_high = $2 WHERE id = $3",
+            balance.low.to_string(),
+            balance.high.to_string(),
+            change.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO erc1155_balance_changes(
+                erc1155_id,
+                account,
+                amount_low,
+                amount_high,
+                is_credit,
+                balance_low,
+                balance_high,
+                block)
+            VALUES ($1, $2, $3, $4, TRUE, $3, $4, $5)
+        "#,
+        erc1155_id,
+        format!("{account:#x}"),
+        seed.low.to_string(),
+        seed.high.to_string(),
+        i64::try_from(state_block)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Reads every token of an ERC721Enumerable contract with its owner before `block_number`, to be
+/// inserted with `insert_erc721_tokens`
+///
+/// Contracts that can't be enumerated, e.g. they aren't ERC721Enumerable or have more than
+/// `MAX_ENUMERATED_SUPPLY` tokens, have no tokens here and are bootstrapped token by token
+/// instead, see `erc721_token`
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if a call fails for now
+pub async fn enumerate_erc721(
+    contract_address: FieldElement,
+    block_number: u64,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<Vec<(CairoUint256, FieldElement)>> {
+    match read_erc721_tokens(contract_address, block_number, rpc).await {
+        Ok(tokens) => Ok(tokens),
+        Err(e) if is_transient(&e) => Err(e),
+        Err(e) => {
+            println!("[bootstrap] {contract_address:#x} can't be enumerated: {e}");
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Bootstraps tokens read by `enumerate_erc721` with their owner before `block_number`, tokens
+/// we learned about since are left alone
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the contract isn't known or a query fails
+pub async fn insert_erc721_tokens(
+    contract_address: FieldElement,
+    block_number: u64,
+    tokens: &[(CairoUint256, FieldElement)],
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let contract_id = sqlx::query!(
+        "SELECT id FROM contract_metadata WHERE contract_address = $1",
+        format!("{contract_address:#x}")
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    let state_block = block_number.saturating_sub(1);
+    for &(token_id, owner) in tokens {
+        insert_erc721(contract_id, contract_address, token_id, owner, state_block, transaction)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Reads every token of an ERC721Enumerable contract with its owner before `block_number`
+async fn read_erc721_tokens(
+    contract_address: FieldElement,
+    block_number: u64,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<Vec<(CairoUint256, FieldElement)>> {
+    let block_id = BlockId::Number(block_number.saturating_sub(1));
+
+    let total_supply = state::total_supply(contract_address, &block_id, rpc).await?;
+    eyre::ensure!(
+        total_supply.high == FieldElement::ZERO,
+        "total supply {} is over {MAX_ENUMERATED_SUPPLY}",
+        total_supply.to_decimal()
+    );
+    let total_supply = u64::try_from(total_supply.low)?;
+
+    eyre::ensure!(
+        total_supply <= MAX_ENUMERATED_SUPPLY,
+        "total supply {total_supply} is over {MAX_ENUMERATED_SUPPLY}"
+    );
+
+    println!("[bootstrap] enumerating {total_supply} tokens of {contract_address:#x}");
+    let mut tokens = Vec::new();
+    for index in 0..total_supply {
+        let index = CairoUint256::new(FieldElement::from(index), FieldElement::ZERO);
+        let token_id = state::token_by_index(contract_address, index, &block_id, rpc).await?;
+        let owner = state::owner_of(contract_address, token_id, &block_id, rpc).await?;
+        tokens.push((token_id, owner));
+    }
+
+    Ok(tokens)
+}
+
+/// Returns the `erc721_token` id of a token, `None` if we don't know it
+async fn find_erc721(
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<Option<i32>> {
+    let erc721_id = sqlx::query!(
+        r#"
+            SELECT id
+            FROM erc721_token
+            WHERE
+                contract_address = $1 AND
+                token_id_low = $2 AND
+                token_id_high = $3
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map(|record| record.id);
+
+    Ok(erc721_id)
+}
+
+/// Inserts a bootstrapped ERC721 token and its ownership starting at `state_block`, and queues
+/// its metadata
+///
+/// Returns the id of the new `erc721_token` record, `None` if the token is known already
+async fn insert_erc721(
+    contract_id: i32,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    owner: FieldElement,
+    state_block: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<Option<i32>> {
+    let inserted = sqlx::query!(
+        r#"
+            INSERT INTO erc721_token(
+                contract_address,
+                contract_id,
+                token_id_low,
+                token_id_high,
+                latest_owner,
+                last_updated_block,
+                bootstrapped_block)
+            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            ON CONFLICT (network, contract_address, token_id_low, token_id_high) DO NOTHING
+            RETURNING id
+        "#,
+        format!("{contract_address:#x}"),
+        contract_id,
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+        format!("{owner:#x}"),
+        i64::try_from(state_block)?
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+
+    let Some(erc721_id) = inserted.map(|record| record.id) else {
+        return Ok(None);
+    };
+    println!("[bootstrap] bootstrapped erc721 token #{} of {contract_address:#x}", token_id.low);
+
+    sqlx::query!(
+        r#"
+            INSERT INTO erc721_owners(erc721_id, owner, valid_from_block)
+            VALUES($1, $2, $3)
+            ON CONFLICT (erc721_id, owner, valid_from_block) DO NOTHING
+        "#,
+        erc721_id,
+        format!("{owner:#x}"),
+        i64::try_from(state_block)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    metadata_jobs::enqueue(
+        contract_address,
+        ContractType::Erc721,
+        token_id,
+        state_block,
+        &mut *transaction,
+    )
+    .await?;
+
+    Ok(Some(erc721_id))
+}
+
+/// Reads a `CairoUint256` stored as the decimal strings of its halves
+fn parse_uint256(low: &str, high: &str) -> eyre::Result<CairoUint256> {
+    Ok(CairoUint256::new(FieldElement::from_dec_str(low)?, FieldElement::from_dec_str(high)?))
+}
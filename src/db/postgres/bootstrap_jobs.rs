@@ -0,0 +1,189 @@
+//! Persistent queue of chain state to read for contracts, tokens and balances the indexer
+//! didn't see from the start, see `bootstrap`
+//!
+//! The writer only enqueues a job, so it doesn't wait for RPC reads and metadata hosts. Jobs are
+//! claimed and retried like `metadata_jobs`.
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres, Transaction};
+use starknet::core::types::FieldElement;
+use std::time::Duration;
+
+use crate::common::types::CairoUint256;
+
+/// What a bootstrap job reads
+#[derive(Debug, Clone, Copy)]
+pub enum BootstrapTask {
+    /// Name, symbol and collection metadata of a newly registered contract
+    Collection,
+    /// Every token of an ERC721Enumerable contract along with its owner
+    Erc721Tokens,
+    /// Balance of an account for an ERC1155 token we didn't index from its mint
+    Erc1155Balance { token_id: CairoUint256, account: FieldElement },
+}
+
+impl BootstrapTask {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Collection => "COLLECTION",
+            Self::Erc721Tokens => "ERC721_TOKENS",
+            Self::Erc1155Balance { .. } => "ERC1155_BALANCE",
+        }
+    }
+}
+
+/// A contract whose chain state should be read for an event in `block_number`
+#[derive(Debug, Clone, Copy)]
+pub struct BootstrapJob {
+    pub id: i32,
+    pub contract_address: FieldElement,
+    pub task: BootstrapTask,
+    pub block_number: u64,
+    /// Attempts so far, including the current one
+    pub attempts: i32,
+}
+
+/// Queues a bootstrap task for an event in `block_number`, unless it's queued already
+pub async fn enqueue(
+    contract_address: FieldElement,
+    task: BootstrapTask,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let (token_id, account) = match task {
+        BootstrapTask::Erc1155Balance { token_id, account } => {
+            (Some(token_id), Some(format!("{account:#x}")))
+        }
+        BootstrapTask::Collection | BootstrapTask::Erc721Tokens => (None, None),
+    };
+
+    sqlx::query!(
+        r#"
+            INSERT INTO bootstrap_jobs(
+                task,
+                contract_address,
+                token_id_low,
+                token_id_high,
+                account,
+                block)
+            VALUES ($1::TEXT::t_bootstrap_task, $2, $3, $4, $5, $6)
+            ON CONFLICT (
+                network,
+                task,
+                contract_address,
+                token_id_low,
+                token_id_high,
+                account) DO NOTHING
+        "#,
+        task.as_str(),
+        format!("{contract_address:#x}"),
+        token_id.map_or_else(String::new, |token_id| token_id.low.to_string()),
+        token_id.map_or_else(String::new, |token_id| token_id.high.to_string()),
+        account.unwrap_or_default(),
+        i64::try_from(block_number)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Takes the job that's been due the longest and holds it for `lease`, `None` if no job is due
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the query fails or the job is malformed
+pub async fn claim(lease: Duration, pool: &Pool<Postgres>) -> eyre::Result<Option<BootstrapJob>> {
+    let record = sqlx::query!(
+        r#"
+            UPDATE bootstrap_jobs
+            SET
+                attempts = attempts + 1,
+                next_attempt_at = NOW() + make_interval(secs => $1)
+            WHERE id IN (
+                SELECT id
+                FROM bootstrap_jobs
+                WHERE next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING
+                id,
+                task::TEXT AS "task!",
+                contract_address,
+                token_id_low,
+                token_id_high,
+                account,
+                block,
+                attempts
+        "#,
+        lease.as_secs_f64()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(record) = record else {
+        return Ok(None);
+    };
+
+    let task = match record.task.as_str() {
+        "COLLECTION" => BootstrapTask::Collection,
+        "ERC721_TOKENS" => BootstrapTask::Erc721Tokens,
+        "ERC1155_BALANCE" => BootstrapTask::Erc1155Balance {
+            token_id: CairoUint256::new(
+                FieldElement::from_dec_str(&record.token_id_low)?,
+                FieldElement::from_dec_str(&record.token_id_high)?,
+            ),
+            account: FieldElement::from_hex_be(&record.account)?,
+        },
+        task => eyre::bail!("unknown bootstrap task {task}"),
+    };
+
+    Ok(Some(BootstrapJob {
+        id: record.id,
+        contract_address: FieldElement::from_hex_be(&record.contract_address)?,
+        task,
+        block_number: u64::try_from(record.block)?,
+        attempts: record.attempts,
+    }))
+}
+
+/// Removes a finished job
+pub async fn complete(
+    job: &BootstrapJob,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!("DELETE FROM bootstrap_jobs WHERE id = $1", job.id)
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Records why a job failed and when to try again, `None` gives up on it. Given up jobs are
+/// kept for inspection.
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the query fails
+pub async fn fail(
+    job: &BootstrapJob,
+    error: &str,
+    retry_in: Option<Duration>,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE bootstrap_jobs
+            SET
+                last_error = $1,
+                next_attempt_at = NOW() + make_interval(secs => $2)
+            WHERE id = $3
+        "#,
+        error,
+        retry_in.map(|retry_in| retry_in.as_secs_f64()),
+        job.id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! Caches what we learn about contracts, so it's read from the chain once per class instead of
+//! once per event
+//!
+//! Classification and ABI are shared by every contract of a class and keyed by class hash, name
+//! and symbol are kept per contract and class. Both are stored in the database so they survive
+//! restarts. The class hash of a contract is remembered along with the blocks it was read at and
+//! only trusted between them, so an upgrade is picked up at the block it happened.
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres};
+use starknet::core::types::{BlockId, FieldElement};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use crate::rpc::{
+    metadata::contract,
+    provider::{is_transient, RpcProvider},
+};
+
+/// In-memory contract cache shared by reader tasks, backed by `contract_classes`
+pub struct ContractCache {
+    pool: Pool<Postgres>,
+    // Contracts blacklisted by hand, read once on load
+    blacklist: HashSet<FieldElement>,
+    // Contract address -> class hash and the blocks we read it at
+    class_hashes: RwLock<HashMap<FieldElement, ClassHashRange>>,
+    // Class hash -> whether contracts of the class are ERC721
+    classes: RwLock<HashMap<FieldElement, bool>>,
+}
+
+impl ContractCache {
+    /// Creates an empty cache on top of the stored classes
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the blacklist can't be read
+    pub async fn load(pool: Pool<Postgres>) -> eyre::Result<Self> {
+        let blacklist = sqlx::query!("SELECT address FROM blacklisted_contracts")
+            .fetch_all(&pool)
+            .await?
+            .iter()
+            .filter_map(|record| FieldElement::from_dec_str(&record.address).ok())
+            .collect();
+
+        Ok(Self {
+            pool,
+            blacklist,
+            class_hashes: RwLock::new(HashMap::new()),
+            classes: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_blacklisted(&self, contract_address: FieldElement) -> bool {
+        self.blacklist.contains(&contract_address)
+    }
+
+    /// Returns if the contract is ERC721, reading and classifying its ABI only if we haven't seen
+    /// its class before. A class that can't be classified is remembered as not ERC721, unless
+    /// the node was unavailable.
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if an RPC call fails transiently or a query fails
+    pub async fn is_erc721(
+        &self,
+        contract_address: FieldElement,
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<bool> {
+        let class_hash = self.class_hash(contract_address, block_id, rpc).await?;
+        let cached = self.classes.read().unwrap().get(&class_hash).copied();
+        if let Some(is_erc721) = cached {
+            return Ok(is_erc721);
+        }
+
+        let stored = sqlx::query!(
+            "SELECT is_erc721 FROM contract_classes WHERE class_hash = $1",
+            format!("{class_hash:#x}")
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|record| record.is_erc721);
+
+        let is_erc721 = match stored {
+            Some(is_erc721) => is_erc721,
+            None => {
+                println!("[cache] classifying new class {class_hash:#x}");
+                let (is_erc721, abi) = match classify(contract_address, block_id, rpc).await {
+                    Ok(classified) => classified,
+                    Err(e) if is_transient(&e) => return Err(e),
+                    Err(e) => {
+                        eprintln!("[cache] can't classify class {class_hash:#x}, not ERC721: {e}");
+                        (false, None)
+                    }
+                };
+
+                sqlx::query!(
+                    r#"
+                        INSERT INTO contract_classes(class_hash, is_erc721, abi)
+                        VALUES ($1, $2, $3)
+                        ON CONFLICT DO NOTHING
+                    "#,
+                    format!("{class_hash:#x}"),
+                    is_erc721,
+                    abi
+                )
+                .execute(&self.pool)
+                .await?;
+
+                is_erc721
+            }
+        };
+
+        self.classes.write().unwrap().insert(class_hash, is_erc721);
+        Ok(is_erc721)
+    }
+
+    /// Returns name and symbol of the contract, reading them from the chain only if we haven't
+    /// stored them for its class at given block
+    ///
+    /// Contracts are only registered once, so these don't need to be kept in memory
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if an RPC call or a query fails
+    pub async fn name_and_symbol(
+        &self,
+        contract_address: FieldElement,
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<(String, String)> {
+        let class_hash = self.class_hash(contract_address, block_id, rpc).await?;
+
+        let stored = sqlx::query!(
+            r#"
+                SELECT name, symbol
+                FROM contract_names
+                WHERE contract_address = $1 AND class_hash = $2
+            "#,
+            format!("{contract_address:#x}"),
+            format!("{class_hash:#x}")
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(record) = stored {
+            return Ok((record.name, record.symbol));
+        }
+
+        let (name, symbol) = tokio::try_join!(
+            contract::get_name(contract_address, block_id, rpc),
+            contract::get_symbol(contract_address, block_id, rpc)
+        )?;
+
+        sqlx::query!(
+            r#"
+                INSERT INTO contract_names(contract_address, class_hash, name, symbol)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT DO NOTHING
+            "#,
+            format!("{contract_address:#x}"),
+            format!("{class_hash:#x}"),
+            name,
+            symbol
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok((name, symbol))
+    }
+
+    /// Returns the class hash of the contract at given block, read again unless it's between
+    /// blocks we already read the same class hash at
+    async fn class_hash(
+        &self,
+        contract_address: FieldElement,
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<FieldElement> {
+        // Only block numbers can be placed in a range, other ids are always read
+        let block_number = match block_id {
+            BlockId::Number(block_number) => Some(*block_number),
+            _ => None,
+        };
+
+        if let Some(block_number) = block_number {
+            let cached = self.class_hashes.read().unwrap().get(&contract_address).copied();
+            if let Some(range) = cached.filter(|range| range.contains(block_number)) {
+                return Ok(range.class_hash);
+            }
+        }
+
+        let class_hash = rpc.get_class_hash_at(block_id, contract_address).await?;
+
+        if let Some(block_number) = block_number {
+            let read =
+                ClassHashRange { class_hash, from_block: block_number, to_block: block_number };
+            let mut class_hashes = self.class_hashes.write().unwrap();
+            match class_hashes.get_mut(&contract_address) {
+                Some(range) if range.class_hash == class_hash => range.extend(block_number),
+                // Upgraded in between, the range of the other class hash can't be trusted anymore
+                Some(range) => *range = read,
+                None => {
+                    class_hashes.insert(contract_address, read);
+                }
+            }
+        }
+
+        Ok(class_hash)
+    }
+}
+
+/// Class hash of a contract and the first and last block we read it at. A contract with the
+/// same class hash at both is assumed not to have been upgraded in between.
+#[derive(Debug, Clone, Copy)]
+struct ClassHashRange {
+    class_hash: FieldElement,
+    from_block: u64,
+    to_block: u64,
+}
+
+impl ClassHashRange {
+    fn contains(&self, block_number: u64) -> bool {
+        (self.from_block..=self.to_block).contains(&block_number)
+    }
+
+    fn extend(&mut self, block_number: u64) {
+        self.from_block = self.from_block.min(block_number);
+        self.to_block = self.to_block.max(block_number);
+    }
+}
+
+/// Returns if the class deployed at given address is ERC721 along with its ABI
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the RPC call fails or the ABI can't be parsed
+async fn classify(
+    contract_address: FieldElement,
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<(bool, Option<String>)> {
+    let Some(abi) = contract::get_abi(contract_address, block_id, rpc).await? else {
+        return Ok((false, None));
+    };
+
+    Ok((contract::is_erc721(&abi)?, Some(abi.to_json()?)))
+}
@@ -0,0 +1,268 @@
+use color_eyre::eyre;
+use sqlx::{Postgres, Transaction};
+use starknet::core::types::{BlockId, FieldElement};
+
+use super::{
+    bootstrap_jobs::{self, BootstrapTask},
+    cache::ContractCache,
+    metadata_reads,
+};
+use crate::{
+    common::types::ContractType,
+    rpc::{
+        http,
+        metadata::{
+            contract::{self, CollectionMetadata},
+            token,
+        },
+        provider::RpcProvider,
+    },
+};
+
+/// Returns the `contract_metadata` id of given contract, registering it first if it isn't known
+/// yet
+///
+/// Name, symbol and collection level metadata of a new contract are read by a bootstrap job, see
+/// `read_collection`
+pub async fn get_or_register(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<i32> {
+    // Contracts are unique by address, one registered with the other type is returned as is
+    let registered = sqlx::query!(
+        r#"
+            SELECT id, contract_type::TEXT AS "contract_type!"
+            FROM contract_metadata
+            WHERE contract_address = $1
+        "#,
+        format!("{contract_address:#x}")
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+
+    if let Some(record) = registered {
+        if record.contract_type != contract_type.as_str() {
+            eprintln!(
+                "[contract] {contract_address:#x} is registered as {}, not {}",
+                record.contract_type,
+                contract_type.as_str()
+            );
+        }
+        return Ok(record.id);
+    }
+
+    println!("[contract] no metadata found, inserting a new one");
+    let id = sqlx::query!(
+        r#"
+            INSERT INTO contract_metadata(contract_address, contract_type, last_updated_block)
+            VALUES ($1, $2::TEXT::t_contract_type, $3)
+            ON CONFLICT (network, contract_address) DO UPDATE
+            SET last_updated_block = EXCLUDED.last_updated_block
+            RETURNING id
+        "#,
+        format!("{contract_address:#x}"),
+        contract_type.as_str(),
+        i64::try_from(block_number)?
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    bootstrap_jobs::enqueue(
+        contract_address,
+        BootstrapTask::Collection,
+        block_number,
+        &mut *transaction,
+    )
+    .await?;
+
+    Ok(id)
+}
+
+/// Name, symbol and collection level metadata of a new contract read by `read_collection`, to be
+/// stored with `store_collection`
+#[derive(Debug)]
+pub struct BootstrappedCollection {
+    // Name and symbol read at every block, kept when there's more than one, see `metadata_reads`
+    names: Vec<(u64, String, String)>,
+    read: CollectionRead,
+}
+
+/// Reads name and symbol of a contract at the blocks given by `METADATA_READ_BLOCK` for an event
+/// in `block_number`, unless they're cached, and its collection level metadata with
+/// `fetch_collection_metadata`, without touching the database
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if a call fails or the collection JSON is unavailable
+/// for now
+pub async fn read_collection(
+    contract_address: FieldElement,
+    block_number: u64,
+    cache: &ContractCache,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<BootstrappedCollection> {
+    let read_blocks = metadata_reads::read_blocks(block_number, rpc).await?;
+
+    let mut names = Vec::with_capacity(read_blocks.len());
+    for read_block in read_blocks {
+        let read_block_id = BlockId::Number(read_block);
+        let (name, symbol) = cache.name_and_symbol(contract_address, &read_block_id, rpc).await?;
+        names.push((read_block, name, symbol));
+    }
+
+    let block_id = BlockId::Number(block_number);
+    let read = fetch_collection_metadata(contract_address, &block_id, rpc).await?;
+
+    Ok(BootstrappedCollection { names, read })
+}
+
+/// Stores what `read_collection` read in the `contract_metadata` record of a registered contract
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the contract isn't registered or a query fails
+pub async fn store_collection(
+    contract_address: FieldElement,
+    collection: &BootstrappedCollection,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    if collection.names.len() > 1 {
+        for (read_block, name, symbol) in &collection.names {
+            for (field, value) in [("name", name), ("symbol", symbol)] {
+                metadata_reads::record(
+                    contract_address,
+                    None,
+                    field,
+                    value,
+                    *read_block,
+                    &mut *transaction,
+                )
+                .await?;
+            }
+        }
+    }
+    let (name_block, name, symbol) =
+        collection.names.last().expect("metadata is read at one block at least");
+    println!("[contract] name: {name}, symbol: {symbol} at block {name_block}");
+
+    let id = sqlx::query!(
+        r#"
+            UPDATE contract_metadata
+            SET name = $1, symbol = $2, name_block = $3
+            WHERE contract_address = $4
+            RETURNING id
+        "#,
+        name,
+        symbol,
+        i64::try_from(*name_block)?,
+        format!("{contract_address:#x}")
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    update_collection_metadata(id, &collection.read, transaction).await
+}
+
+/// Owner, URIs and collection JSON of a contract, see `fetch_collection_metadata`
+#[derive(Debug)]
+pub struct CollectionRead {
+    pub owner: Option<FieldElement>,
+    pub base_uri: String,
+    pub contract_uri: String,
+    /// `None` if the collection JSON couldn't be read for good, the stored one is kept then
+    pub metadata: Option<CollectionMetadata>,
+}
+
+/// Reads owner, base URI and contract URI at given block and resolves the collection JSON
+/// behind the contract URI, to be stored with `update_collection_metadata`
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if a call fails or the collection JSON is unavailable
+/// for now, e.g. its host timed out, so it's read again later
+pub async fn fetch_collection_metadata(
+    contract_address: FieldElement,
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<CollectionRead> {
+    // Sent together, so they can share a batch
+    let (owner, base_uri, contract_uri) = tokio::try_join!(
+        contract::get_owner(contract_address, block_id, rpc),
+        contract::get_base_uri(contract_address, block_id, rpc),
+        contract::get_contract_uri(contract_address, block_id, rpc)
+    )?;
+
+    let metadata = if contract_uri.is_empty() {
+        Some(CollectionMetadata::default())
+    } else {
+        match token::get_json_metadata::<CollectionMetadata>(&contract_uri).await {
+            Ok(metadata) => Some(metadata),
+            Err(e) if http::is_transient(&e) => return Err(e),
+            Err(e) => {
+                eprintln!("[contract] keeping collection metadata, {contract_uri} is invalid: {e}");
+                None
+            }
+        }
+    };
+
+    Ok(CollectionRead { owner, base_uri, contract_uri, metadata })
+}
+
+/// Stores what `fetch_collection_metadata` read in the `contract_metadata` record
+pub async fn update_collection_metadata(
+    id: i32,
+    read: &CollectionRead,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE contract_metadata
+            SET
+                owner = $1,
+                base_uri = $2,
+                contract_uri = $3,
+                metadata_refreshed_at = NOW(),
+                metadata_refresh_failures = 0,
+                metadata_refresh_error = NULL,
+                metadata_retry_at = NULL
+            WHERE id = $4
+        "#,
+        read.owner.map(|owner| format!("{owner:#x}")),
+        (!read.base_uri.is_empty()).then_some(&read.base_uri),
+        (!read.contract_uri.is_empty()).then_some(&read.contract_uri),
+        id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let Some(metadata) = &read.metadata else {
+        return Ok(());
+    };
+
+    sqlx::query!(
+        r#"
+            UPDATE contract_metadata
+            SET
+                -- Only fall back to the JSON name if the contract doesn't have one
+                name = COALESCE(NULLIF(name, ''), $1),
+                description = $2,
+                image = $3,
+                external_link = $4,
+                seller_fee_basis_points = $5,
+                fee_recipient = $6
+            WHERE id = $7
+        "#,
+        metadata.name,
+        metadata.description,
+        metadata.image,
+        metadata.external_link,
+        metadata.seller_fee_basis_points,
+        metadata.fee_recipient,
+        id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,258 @@
+//! Point-in-time queries over `erc721_owners` intervals and `erc1155_balance_changes`
+//!
+//! Every function here answers with the state at the end of `block_number`, so a transfer
+//! emitted in `block_number` is already applied. See `shovel owner-at` and `shovel balance-at`.
+//!
+//! Tokens bootstrapped from chain state, see `bootstrap`, are only known from their
+//! `bootstrapped_block` on, their state before is `AtBlock::Unknown` rather than "not minted".
+
+use color_eyre::eyre::Result;
+use sqlx::{Pool, Postgres};
+use starknet::core::types::FieldElement;
+
+use crate::common::types::CairoUint256;
+
+/// State at a block, unless the block is before the token was bootstrapped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AtBlock<T> {
+    Known(T),
+    /// The token was bootstrapped from chain state at `bootstrapped_block`, we don't know its
+    /// state before
+    Unknown {
+        bootstrapped_block: u64,
+    },
+}
+
+impl<T> AtBlock<T> {
+    /// `Unknown` if a token bootstrapped at `bootstrapped_block` isn't known at `block_number`
+    fn check(bootstrapped_block: Option<i64>, block_number: u64) -> Result<Option<Self>> {
+        let Some(bootstrapped_block) = bootstrapped_block else {
+            return Ok(None);
+        };
+        let bootstrapped_block = u64::try_from(bootstrapped_block)?;
+
+        Ok((block_number < bootstrapped_block).then_some(Self::Unknown { bootstrapped_block }))
+    }
+}
+
+/// Returns the owner of an ERC721 token at given block, `None` if it wasn't minted yet
+pub async fn erc721_owner_at(
+    pool: &Pool<Postgres>,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    block_number: u64,
+) -> Result<AtBlock<Option<FieldElement>>> {
+    let token = sqlx::query!(
+        r#"
+            SELECT id, bootstrapped_block
+            FROM erc721_token
+            WHERE
+                contract_address = $1 AND
+                token_id_low = $2 AND
+                token_id_high = $3
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.low.to_string(),
+        token_id.high.to_string()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(token) = token else {
+        return Ok(AtBlock::Known(None));
+    };
+    if let Some(unknown) = AtBlock::check(token.bootstrapped_block, block_number)? {
+        return Ok(unknown);
+    }
+
+    let record = sqlx::query!(
+        r#"
+            SELECT owner
+            FROM erc721_owners
+            WHERE
+                erc721_id = $1 AND
+                valid_from_block <= $2 AND
+                (valid_to_block IS NULL OR valid_to_block > $2)
+            ORDER BY valid_from_block DESC, id DESC
+            LIMIT 1
+        "#,
+        token.id,
+        i64::try_from(block_number)?
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let owner = record.map(|record| FieldElement::from_hex_be(&record.owner)).transpose()?;
+    Ok(AtBlock::Known(owner))
+}
+
+/// Returns every token of an ERC721 contract with its owner at given block, tokens bootstrapped
+/// after it are listed as `Unknown`
+pub async fn erc721_owners_at(
+    pool: &Pool<Postgres>,
+    contract_address: FieldElement,
+    block_number: u64,
+) -> Result<Vec<(CairoUint256, AtBlock<FieldElement>)>> {
+    let records = sqlx::query!(
+        r#"
+            SELECT DISTINCT ON (erc721_token.id)
+                erc721_token.token_id_low,
+                erc721_token.token_id_high,
+                erc721_owners.owner
+            FROM erc721_owners
+            INNER JOIN erc721_token ON erc721_token.id = erc721_owners.erc721_id
+            WHERE
+                erc721_token.contract_address = $1 AND
+                erc721_owners.valid_from_block <= $2 AND
+                (erc721_owners.valid_to_block IS NULL OR erc721_owners.valid_to_block > $2)
+            ORDER BY erc721_token.id, erc721_owners.valid_from_block DESC, erc721_owners.id DESC
+        "#,
+        format!("{contract_address:#x}"),
+        i64::try_from(block_number)?
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut owners = Vec::with_capacity(records.len());
+    for record in records {
+        let token_id = CairoUint256::new(
+            FieldElement::from_dec_str(&record.token_id_low)?,
+            FieldElement::from_dec_str(&record.token_id_high)?,
+        );
+        owners.push((token_id, AtBlock::Known(FieldElement::from_hex_be(&record.owner)?)));
+    }
+
+    let unknown = sqlx::query!(
+        r#"
+            SELECT token_id_low, token_id_high, bootstrapped_block AS "bootstrapped_block!"
+            FROM erc721_token
+            WHERE contract_address = $1 AND bootstrapped_block > $2
+        "#,
+        format!("{contract_address:#x}"),
+        i64::try_from(block_number)?
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for record in unknown {
+        let token_id = CairoUint256::new(
+            FieldElement::from_dec_str(&record.token_id_low)?,
+            FieldElement::from_dec_str(&record.token_id_high)?,
+        );
+        let bootstrapped_block = u64::try_from(record.bootstrapped_block)?;
+        owners.push((token_id, AtBlock::Unknown { bootstrapped_block }));
+    }
+
+    Ok(owners)
+}
+
+/// Returns the balance of `account` for an ERC1155 token id at given block
+pub async fn erc1155_balance_at(
+    pool: &Pool<Postgres>,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    account: FieldElement,
+    block_number: u64,
+) -> Result<AtBlock<CairoUint256>> {
+    let Some((erc1155_id, bootstrapped_block)) =
+        find_erc1155(pool, contract_address, token_id).await?
+    else {
+        return Ok(AtBlock::Known(CairoUint256::ZERO));
+    };
+    if let Some(unknown) = AtBlock::check(bootstrapped_block, block_number)? {
+        return Ok(unknown);
+    }
+
+    let record = sqlx::query!(
+        r#"
+            SELECT balance_low, balance_high
+            FROM erc1155_balance_changes
+            WHERE erc1155_id = $1 AND account = $2 AND block <= $3
+            ORDER BY block DESC, id DESC
+            LIMIT 1
+        "#,
+        erc1155_id,
+        format!("{account:#x}"),
+        i64::try_from(block_number)?
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    match record {
+        Some(record) => Ok(AtBlock::Known(CairoUint256::new(
+            FieldElement::from_dec_str(&record.balance_low)?,
+            FieldElement::from_dec_str(&record.balance_high)?,
+        ))),
+        None => Ok(AtBlock::Known(CairoUint256::ZERO)),
+    }
+}
+
+/// Returns every account holding a non-zero balance of an ERC1155 token id at given block
+pub async fn erc1155_balances_at(
+    pool: &Pool<Postgres>,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+    block_number: u64,
+) -> Result<AtBlock<Vec<(FieldElement, CairoUint256)>>> {
+    let Some((erc1155_id, bootstrapped_block)) =
+        find_erc1155(pool, contract_address, token_id).await?
+    else {
+        return Ok(AtBlock::Known(Vec::new()));
+    };
+    if let Some(unknown) = AtBlock::check(bootstrapped_block, block_number)? {
+        return Ok(unknown);
+    }
+
+    let records = sqlx::query!(
+        r#"
+            SELECT DISTINCT ON (account) account, balance_low, balance_high
+            FROM erc1155_balance_changes
+            WHERE erc1155_id = $1 AND block <= $2
+            ORDER BY account, block DESC, id DESC
+        "#,
+        erc1155_id,
+        i64::try_from(block_number)?
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut balances = Vec::with_capacity(records.len());
+
+    for record in records {
+        let balance = CairoUint256::new(
+            FieldElement::from_dec_str(&record.balance_low)?,
+            FieldElement::from_dec_str(&record.balance_high)?,
+        );
+
+        if balance != CairoUint256::ZERO {
+            balances.push((FieldElement::from_hex_be(&record.account)?, balance));
+        }
+    }
+
+    Ok(AtBlock::Known(balances))
+}
+
+/// Returns the id and bootstrapped block of an ERC1155 token, `None` if we don't know it
+async fn find_erc1155(
+    pool: &Pool<Postgres>,
+    contract_address: FieldElement,
+    token_id: CairoUint256,
+) -> Result<Option<(i32, Option<i64>)>> {
+    let record = sqlx::query!(
+        r#"
+            SELECT id, bootstrapped_block
+            FROM erc1155_token
+            WHERE
+                contract_address = $1 AND
+                token_id_low = $2 AND
+                token_id_high = $3
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.low.to_string(),
+        token_id.high.to_string()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|record| (record.id, record.bootstrapped_block)))
+}
@@ -0,0 +1,155 @@
+//! Persistent queue of tokens waiting for their token URI and metadata
+//!
+//! Mints only enqueue a job, so the writer doesn't wait for RPC reads and metadata hosts.
+//! Workers claim jobs with `FOR UPDATE SKIP LOCKED`, a claimed job is held for a lease and
+//! becomes available again if its worker doesn't finish it in time.
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres, Transaction};
+use starknet::core::types::FieldElement;
+use std::{str::FromStr, time::Duration};
+
+use crate::common::types::{CairoUint256, ContractType};
+
+/// A token whose metadata should be read for `block_number`
+#[derive(Debug, Clone, Copy)]
+pub struct MetadataJob {
+    pub id: i32,
+    pub contract_address: FieldElement,
+    pub contract_type: ContractType,
+    pub token_id: CairoUint256,
+    pub block_number: u64,
+    /// Attempts so far, including the current one
+    pub attempts: i32,
+}
+
+/// Queues metadata of a token to be read for `block_number`. A token that's already queued is
+/// read for the new block instead, with its attempts reset.
+pub async fn enqueue(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            INSERT INTO metadata_jobs(
+                contract_address,
+                contract_type,
+                token_id_low,
+                token_id_high,
+                block)
+            VALUES ($1, $2::TEXT::t_contract_type, $3, $4, $5)
+            ON CONFLICT (network, contract_address, token_id_low, token_id_high) DO UPDATE
+            SET
+                block = EXCLUDED.block,
+                attempts = 0,
+                next_attempt_at = NOW(),
+                last_error = NULL
+        "#,
+        format!("{contract_address:#x}"),
+        contract_type.as_str(),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+        i64::try_from(block_number)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Takes the job that's been due the longest and holds it for `lease`, `None` if no job is due
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the query fails or the job is malformed
+pub async fn claim(lease: Duration, pool: &Pool<Postgres>) -> eyre::Result<Option<MetadataJob>> {
+    let record = sqlx::query!(
+        r#"
+            UPDATE metadata_jobs
+            SET
+                attempts = attempts + 1,
+                next_attempt_at = NOW() + make_interval(secs => $1)
+            WHERE id IN (
+                SELECT id
+                FROM metadata_jobs
+                WHERE next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING
+                id,
+                contract_address,
+                contract_type::TEXT AS "contract_type!",
+                token_id_low,
+                token_id_high,
+                block,
+                attempts
+        "#,
+        lease.as_secs_f64()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(record) = record else {
+        return Ok(None);
+    };
+
+    Ok(Some(MetadataJob {
+        id: record.id,
+        contract_address: FieldElement::from_hex_be(&record.contract_address)?,
+        contract_type: ContractType::from_str(&record.contract_type)?,
+        token_id: CairoUint256::new(
+            FieldElement::from_dec_str(&record.token_id_low)?,
+            FieldElement::from_dec_str(&record.token_id_high)?,
+        ),
+        block_number: u64::try_from(record.block)?,
+        attempts: record.attempts,
+    }))
+}
+
+/// Removes a finished job, unless it was queued again for another block in the meantime
+pub async fn complete(
+    job: &MetadataJob,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        "DELETE FROM metadata_jobs WHERE id = $1 AND block = $2",
+        job.id,
+        i64::try_from(job.block_number)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Records why a job failed and when to try again, `None` gives up on it. Given up jobs are
+/// kept for inspection.
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the query fails
+pub async fn fail(
+    job: &MetadataJob,
+    error: &str,
+    retry_in: Option<Duration>,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE metadata_jobs
+            SET
+                last_error = $1,
+                next_attempt_at = NOW() + make_interval(secs => $2)
+            WHERE id = $3
+        "#,
+        error,
+        retry_in.map(|retry_in| retry_in.as_secs_f64()),
+        job.id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Picks the blocks on-chain metadata is read at, see `MetadataReadBlock`, and keeps every read
+//! in `metadata_reads` when there's more than one
+use color_eyre::eyre;
+use sqlx::{Postgres, Transaction};
+use starknet::core::types::FieldElement;
+
+use crate::{
+    common::types::{CairoUint256, MetadataReadBlock},
+    rpc::provider::RpcProvider,
+};
+
+/// Returns the blocks metadata of an event in `event_block` should be read at, the value read at
+/// the last one is the one to store
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if `METADATA_READ_BLOCK` is invalid or the latest
+/// block can't be read
+pub async fn read_blocks(event_block: u64, rpc: &impl RpcProvider) -> eyre::Result<Vec<u64>> {
+    Ok(match MetadataReadBlock::from_env()? {
+        MetadataReadBlock::Event => vec![event_block],
+        MetadataReadBlock::Latest => vec![rpc.block_number().await?],
+        MetadataReadBlock::Both => {
+            let latest = rpc.block_number().await?;
+            if latest > event_block {
+                vec![event_block, latest]
+            } else {
+                vec![event_block]
+            }
+        }
+    })
+}
+
+/// Keeps a read of `field`, token id is `None` for contract level fields
+pub async fn record(
+    contract_address: FieldElement,
+    token_id: Option<CairoUint256>,
+    field: &str,
+    value: &str,
+    block_number: u64,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            INSERT INTO metadata_reads(
+                contract_address,
+                token_id_low,
+                token_id_high,
+                field,
+                value,
+                block)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.map(|token_id| token_id.low.to_string()),
+        token_id.map(|token_id| token_id.high.to_string()),
+        field,
+        value,
+        i64::try_from(block_number)?
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
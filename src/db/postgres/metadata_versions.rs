@@ -0,0 +1,97 @@
+//! Keeps every distinct metadata document of a token in `token_metadata_versions`, so reveals
+//! and later changes can be traced back
+//!
+//! Documents are compared by `content_hash`, a version is only added when it differs from the
+//! one `token_metadata.current_version_id` points at.
+use color_eyre::eyre;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::{Postgres, Transaction};
+
+use super::token::MetadataSource;
+use crate::rpc::metadata::token::MetadataDocument;
+
+/// Hex encoded SHA-256 of a document serialized without whitespace and with sorted keys, so
+/// formatting and key order don't make a new version
+pub fn content_hash(raw: &Value) -> String {
+    // `Value` keeps object keys sorted and displays without whitespace
+    format!("{:x}", Sha256::digest(raw.to_string()))
+}
+
+/// Adds a version for the document of a `token_metadata` record and makes it the current one if
+/// its content changed. Tokens without a document keep their current version.
+///
+/// Returns whether a version was added
+pub async fn record_version(
+    token_metadata_id: i32,
+    source: &MetadataSource,
+    document: &MetadataDocument,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<bool> {
+    let Some(raw) = &document.raw else {
+        return Ok(false);
+    };
+    let content_hash = content_hash(raw);
+
+    let current_hash = sqlx::query!(
+        r#"
+            SELECT version.content_hash
+            FROM token_metadata
+            JOIN token_metadata_versions version ON version.id = token_metadata.current_version_id
+            WHERE token_metadata.id = $1
+        "#,
+        token_metadata_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map(|record| record.content_hash);
+
+    if current_hash.as_deref() == Some(content_hash.as_str()) {
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        r#"
+            WITH version AS (
+                INSERT INTO token_metadata_versions(
+                    token_metadata_id,
+                    content_hash,
+                    raw_metadata,
+                    token_uri,
+                    token_uri_block,
+                    metadata_gateway)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING id
+            )
+            UPDATE token_metadata
+            SET current_version_id = (SELECT id FROM version)
+            WHERE id = $1
+        "#,
+        token_metadata_id,
+        content_hash,
+        raw,
+        source.token_uri,
+        i64::try_from(source.token_uri_block)?,
+        source.gateway
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash_ignores_formatting() {
+        let a: Value = serde_json::from_str(r#"{"name": "Duck", "edition": 1}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"edition":1,"name":"Duck"}"#).unwrap();
+        let c: Value = serde_json::from_str(r#"{"edition":2,"name":"Duck"}"#).unwrap();
+
+        assert_eq!(content_hash(&a), content_hash(&b));
+        assert_ne!(content_hash(&a), content_hash(&c));
+        assert_eq!(content_hash(&a).len(), 64);
+    }
+}
@@ -0,0 +1,157 @@
+-- Records are identified by (network, contract, token id[, account]). Only mainnet is indexed
+-- for now, so every existing record belongs to it.
+ALTER TABLE contract_metadata ADD COLUMN "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet';
+ALTER TABLE token_metadata ADD COLUMN "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet';
+ALTER TABLE erc721_token ADD COLUMN "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet';
+ALTER TABLE erc1155_token ADD COLUMN "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet';
+
+-- contract_metadata: keep the first record of every contract and point tokens to it
+CREATE TEMPORARY TABLE contract_duplicates AS
+  SELECT * FROM (
+    SELECT
+      "id",
+      FIRST_VALUE("id") OVER (PARTITION BY "network", "contract_address" ORDER BY "id") AS "keep_id"
+    FROM contract_metadata
+  ) AS ranked
+  WHERE "id" <> "keep_id";
+
+UPDATE erc721_token SET "contract_id" = contract_duplicates."keep_id"
+FROM contract_duplicates WHERE erc721_token."contract_id" = contract_duplicates."id";
+
+UPDATE erc1155_token SET "contract_id" = contract_duplicates."keep_id"
+FROM contract_duplicates WHERE erc1155_token."contract_id" = contract_duplicates."id";
+
+DELETE FROM contract_metadata USING contract_duplicates
+WHERE contract_metadata."id" = contract_duplicates."id";
+
+-- token_metadata: keep the latest record of every token, attributes cascade
+DELETE FROM token_metadata USING (
+  SELECT * FROM (
+    SELECT
+      "id",
+      FIRST_VALUE("id") OVER (
+        PARTITION BY "network", "contract_address", "token_id_low", "token_id_high"
+        ORDER BY "id" DESC
+      ) AS "keep_id"
+    FROM token_metadata
+  ) AS ranked
+  WHERE "id" <> "keep_id"
+) AS duplicates
+WHERE token_metadata."id" = duplicates."id";
+
+-- erc721_token: keep the first record of every token and move ownership history to it
+CREATE TEMPORARY TABLE erc721_duplicates AS
+  SELECT * FROM (
+    SELECT
+      "id",
+      FIRST_VALUE("id") OVER (
+        PARTITION BY "network", "contract_address", "token_id_low", "token_id_high"
+        ORDER BY "id"
+      ) AS "keep_id"
+    FROM erc721_token
+  ) AS ranked
+  WHERE "id" <> "keep_id";
+
+UPDATE erc721_owners SET "erc721_id" = erc721_duplicates."keep_id"
+FROM erc721_duplicates WHERE erc721_owners."erc721_id" = erc721_duplicates."id";
+
+DELETE FROM erc721_token USING erc721_duplicates
+WHERE erc721_token."id" = erc721_duplicates."id";
+
+-- erc1155_token: keep the first record of every token and move balances to it
+CREATE TEMPORARY TABLE erc1155_duplicates AS
+  SELECT * FROM (
+    SELECT
+      "id",
+      FIRST_VALUE("id") OVER (
+        PARTITION BY "network", "contract_address", "token_id_low", "token_id_high"
+        ORDER BY "id"
+      ) AS "keep_id"
+    FROM erc1155_token
+  ) AS ranked
+  WHERE "id" <> "keep_id";
+
+UPDATE erc1155_balances SET "erc1155_id" = erc1155_duplicates."keep_id"
+FROM erc1155_duplicates WHERE erc1155_balances."erc1155_id" = erc1155_duplicates."id";
+
+UPDATE erc1155_balance_changes SET "erc1155_id" = erc1155_duplicates."keep_id"
+FROM erc1155_duplicates WHERE erc1155_balance_changes."erc1155_id" = erc1155_duplicates."id";
+
+DELETE FROM erc1155_token USING erc1155_duplicates
+WHERE erc1155_token."id" = erc1155_duplicates."id";
+
+-- erc1155_balances: keep the most recently updated balance of every account
+DELETE FROM erc1155_balances USING (
+  SELECT * FROM (
+    SELECT
+      "id",
+      FIRST_VALUE("id") OVER (
+        PARTITION BY "erc1155_id", "account"
+        ORDER BY "last_updated_block" DESC, "id" DESC
+      ) AS "keep_id"
+    FROM erc1155_balances
+  ) AS ranked
+  WHERE "id" <> "keep_id"
+) AS duplicates
+WHERE erc1155_balances."id" = duplicates."id";
+
+-- erc721_owners: the same owner can't start holding a token twice in the same block
+DELETE FROM erc721_owners USING (
+  SELECT * FROM (
+    SELECT
+      "id",
+      FIRST_VALUE("id") OVER (
+        PARTITION BY "erc721_id", "owner", "valid_from_block"
+        ORDER BY "id"
+      ) AS "keep_id"
+    FROM erc721_owners
+  ) AS ranked
+  WHERE "id" <> "keep_id"
+) AS duplicates
+WHERE erc721_owners."id" = duplicates."id";
+
+-- Close the ownership intervals of tokens whose histories got merged again
+UPDATE erc721_owners AS current
+SET "valid_to_block" = (
+  SELECT MIN(next."valid_from_block")
+  FROM erc721_owners AS next
+  WHERE
+    next."erc721_id" = current."erc721_id" AND
+    (next."valid_from_block", next."id") > (current."valid_from_block", current."id")
+)
+WHERE current."erc721_id" IN (SELECT "keep_id" FROM erc721_duplicates);
+
+ALTER TABLE contract_metadata
+  ADD CONSTRAINT "uq_contract_metadata" UNIQUE ("network", "contract_address");
+ALTER TABLE token_metadata
+  ADD CONSTRAINT "uq_token_metadata"
+  UNIQUE ("network", "contract_address", "token_id_low", "token_id_high");
+ALTER TABLE erc721_token
+  ADD CONSTRAINT "uq_erc721_token"
+  UNIQUE ("network", "contract_address", "token_id_low", "token_id_high");
+ALTER TABLE erc1155_token
+  ADD CONSTRAINT "uq_erc1155_token"
+  UNIQUE ("network", "contract_address", "token_id_low", "token_id_high");
+ALTER TABLE erc1155_balances
+  ADD CONSTRAINT "uq_erc1155_balances" UNIQUE ("erc1155_id", "account");
+ALTER TABLE erc721_owners
+  ADD CONSTRAINT "uq_erc721_owners" UNIQUE ("erc721_id", "owner", "valid_from_block");
+
+-- Transfer every balance change comes from, so a batch replayed after a restart doesn't apply it
+-- twice. NULL for changes that don't come from a transfer.
+ALTER TABLE erc1155_balance_changes
+  ADD COLUMN "transaction_hash" VARCHAR(80),
+  -- Index of the event among the transfer events of its contract in the transaction
+  ADD COLUMN "event_index" INT,
+  -- Index of the transfer in its TransferBatch event, 0 for TransferSingle
+  ADD COLUMN "transfer_index" INT;
+
+ALTER TABLE erc1155_balance_changes
+  ADD CONSTRAINT "uq_erc1155_balance_changes" UNIQUE (
+    "erc1155_id",
+    "account",
+    "is_credit",
+    "transaction_hash",
+    "event_index",
+    "transfer_index"
+  );
@@ -0,0 +1,3 @@
+-- Block the token was bootstrapped from chain state at, NULL if we indexed it from its mint
+ALTER TABLE erc721_token ADD COLUMN "bootstrapped_block" BIGINT;
+ALTER TABLE erc1155_token ADD COLUMN "bootstrapped_block" BIGINT;
@@ -0,0 +1,25 @@
+-- Results of `shovel verify` runs, comparing stored ownership with chain state
+CREATE TABLE drift_reports(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "contract_address" VARCHAR(80) NOT NULL,
+  "block" BIGINT NOT NULL,
+  "checked" INT NOT NULL,
+  "mismatched" INT NOT NULL,
+  "repaired" BOOLEAN NOT NULL,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- A mismatched ERC721 owner, or ERC1155 balance of `account`
+CREATE TABLE drift_report_entries(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "drift_report_id" INT NOT NULL,
+  "token_id_low" VARCHAR(80) NOT NULL,
+  "token_id_high" VARCHAR(80) NOT NULL,
+  "account" VARCHAR(80),
+  "stored" VARCHAR(80) NOT NULL,
+  "on_chain" VARCHAR(80) NOT NULL,
+  CONSTRAINT fk_drift_report
+    FOREIGN KEY("drift_report_id")
+      REFERENCES drift_reports("id")
+        ON DELETE CASCADE
+);
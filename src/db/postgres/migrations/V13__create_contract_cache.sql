@@ -0,0 +1,18 @@
+-- Classification and ABI of every contract class we've seen. Keyed by class hash, so an
+-- upgraded contract is classified again.
+CREATE TABLE contract_classes(
+  "class_hash" VARCHAR(80) PRIMARY KEY,
+  "is_erc721" BOOLEAN NOT NULL,
+  "abi" TEXT,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Name and symbol of a contract while it runs given class
+CREATE TABLE contract_names(
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  "class_hash" VARCHAR(80) NOT NULL,
+  "name" TEXT NOT NULL,
+  "symbol" TEXT NOT NULL,
+  PRIMARY KEY ("network", "contract_address", "class_hash")
+);
@@ -0,0 +1,22 @@
+-- Block on-chain metadata was read at, see METADATA_READ_BLOCK
+ALTER TABLE contract_metadata ADD COLUMN "name_block" BIGINT;
+ALTER TABLE token_metadata ADD COLUMN "token_uri_block" BIGINT;
+ALTER TABLE erc721_token ADD COLUMN "token_uri_block" BIGINT;
+ALTER TABLE erc1155_token ADD COLUMN "token_uri_block" BIGINT;
+
+-- Every read of a token URI, name or symbol when metadata is read at both the event block and
+-- the latest block. Token id is NULL for contract level fields.
+CREATE TABLE metadata_reads(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  "token_id_low" VARCHAR(80),
+  "token_id_high" VARCHAR(80),
+  "field" VARCHAR(16) NOT NULL,
+  "value" TEXT NOT NULL,
+  "block" BIGINT NOT NULL,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX "idx_metadata_reads_token"
+  ON metadata_reads("contract_address", "token_id_low", "token_id_high", "block");
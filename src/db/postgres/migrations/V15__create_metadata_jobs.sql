@@ -0,0 +1,43 @@
+-- Tokens waiting for their token URI and metadata, filled in by metadata workers behind the
+-- indexer. `block` is the block metadata is read for, see METADATA_READ_BLOCK.
+CREATE TABLE metadata_jobs(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  "contract_type" t_contract_type NOT NULL,
+  "token_id_low" VARCHAR(80) NOT NULL,
+  "token_id_high" VARCHAR(80) NOT NULL,
+  "block" BIGINT NOT NULL,
+  "attempts" INT NOT NULL DEFAULT 0,
+  -- Pushed back while a worker holds the job and after every failure, NULL once we gave up
+  "next_attempt_at" TIMESTAMPTZ DEFAULT NOW(),
+  "last_error" TEXT,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE ("network", "contract_address", "token_id_low", "token_id_high")
+);
+
+CREATE INDEX "idx_metadata_jobs_next_attempt_at" ON metadata_jobs("next_attempt_at");
+
+CREATE TYPE t_bootstrap_task AS ENUM ('COLLECTION', 'ERC721_TOKENS', 'ERC1155_BALANCE');
+
+-- Chain state read behind the indexer for contracts, tokens and balances it didn't see from the
+-- start, by the metadata workers. `block` is the block of the event that revealed them.
+CREATE TABLE bootstrap_jobs(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "task" t_bootstrap_task NOT NULL,
+  "contract_address" VARCHAR(80) NOT NULL,
+  -- Token and account of an ERC1155_BALANCE job, empty for jobs about the whole contract
+  "token_id_low" VARCHAR(80) NOT NULL DEFAULT '',
+  "token_id_high" VARCHAR(80) NOT NULL DEFAULT '',
+  "account" VARCHAR(80) NOT NULL DEFAULT '',
+  "block" BIGINT NOT NULL,
+  "attempts" INT NOT NULL DEFAULT 0,
+  -- Pushed back while a worker holds the job and after every failure, NULL once we gave up
+  "next_attempt_at" TIMESTAMPTZ DEFAULT NOW(),
+  "last_error" TEXT,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE ("network", "task", "contract_address", "token_id_low", "token_id_high", "account")
+);
+
+CREATE INDEX "idx_bootstrap_jobs_next_attempt_at" ON bootstrap_jobs("next_attempt_at");
@@ -0,0 +1,2 @@
+-- IPFS gateway that served the metadata of the token, NULL if it isn't on IPFS
+ALTER TABLE token_metadata ADD COLUMN "metadata_gateway" TEXT;
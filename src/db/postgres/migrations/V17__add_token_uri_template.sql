@@ -0,0 +1,5 @@
+-- Token URI as the contract returned it when it's a template, e.g. ERC1155 `{id}` URIs, in
+-- which case `token_uri` holds its expansion
+ALTER TABLE token_metadata ADD COLUMN "token_uri_template" TEXT;
+ALTER TABLE erc721_token ADD COLUMN "token_uri_template" TEXT;
+ALTER TABLE erc1155_token ADD COLUMN "token_uri_template" TEXT;
@@ -0,0 +1,8 @@
+-- Token metadata document as published, including fields we don't parse, NULL if there's none
+ALTER TABLE token_metadata ADD COLUMN "raw_metadata" JSONB;
+-- Whether the known fields parsed out of `raw_metadata`, they're left empty if not
+ALTER TABLE token_metadata ADD COLUMN "metadata_parsed" BOOLEAN;
+
+-- Serves JSON path queries over documents, e.g. `raw_metadata @? '$.properties.rarity'`
+CREATE INDEX "idx_token_metadata_raw_metadata" ON token_metadata
+  USING GIN ("raw_metadata" jsonb_path_ops);
@@ -0,0 +1,42 @@
+-- Attribute values as published, plus typed copies of scalar ones so they can be queried
+ALTER TABLE token_metadata_attributes
+  ADD COLUMN "value_json" JSONB,
+  ADD COLUMN "value_text" TEXT,
+  ADD COLUMN "value_numeric" NUMERIC,
+  ADD COLUMN "value_boolean" BOOLEAN,
+  ADD COLUMN "value_date" TIMESTAMPTZ;
+
+-- Values, display types and trait types used to be stored JSON encoded, e.g. `"Gold"`
+UPDATE token_metadata_attributes
+SET
+  value_json = value::JSONB,
+  trait_type = trait_type::JSONB #>> '{}',
+  display_type = CASE display_type::JSONB #>> '{}'
+    WHEN 'Number' THEN 'number'
+    WHEN 'BoostPercentage' THEN 'boost_percentage'
+    WHEN 'BoostNumber' THEN 'boost_number'
+    WHEN 'Date' THEN 'date'
+  END;
+
+UPDATE token_metadata_attributes
+SET
+  value_text = CASE
+    WHEN jsonb_typeof(value_json) = 'string' THEN value_json #>> '{}'
+  END,
+  value_numeric = CASE
+    WHEN jsonb_typeof(value_json) = 'number' THEN (value_json #>> '{}')::NUMERIC
+  END,
+  value_boolean = CASE
+    WHEN jsonb_typeof(value_json) = 'boolean' THEN (value_json #>> '{}')::BOOLEAN
+  END,
+  value_date = CASE
+    WHEN display_type = 'date' AND jsonb_typeof(value_json) = 'number'
+    THEN to_timestamp((value_json #>> '{}')::DOUBLE PRECISION)
+  END;
+
+ALTER TABLE token_metadata_attributes DROP COLUMN "value";
+
+CREATE INDEX "idx_token_metadata_attributes_trait"
+  ON token_metadata_attributes("trait_type", "value_text");
+CREATE INDEX "idx_token_metadata_attributes_token_metadata_id"
+  ON token_metadata_attributes("token_metadata_id");
@@ -0,0 +1,28 @@
+-- Every distinct metadata document a token had, a version is added whenever the content changes,
+-- e.g. on reveal or when a dynamic token updates
+CREATE TABLE token_metadata_versions(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "token_metadata_id" INT NOT NULL,
+  -- SHA-256 of the document with sorted keys and no whitespace, hex encoded
+  "content_hash" CHAR(64) NOT NULL,
+  "raw_metadata" JSONB NOT NULL,
+  "token_uri" TEXT,
+  "token_uri_block" BIGINT,
+  "metadata_gateway" TEXT,
+  "fetched_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+  -- token_metadata_versions[token_metadata_id] -> token_metadata[id]
+  CONSTRAINT "fk_token_metadata"
+    FOREIGN KEY("token_metadata_id")
+    REFERENCES token_metadata("id")
+    ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_token_metadata_versions_token"
+  ON token_metadata_versions("token_metadata_id", "fetched_at");
+CREATE INDEX "idx_token_metadata_versions_content_hash"
+  ON token_metadata_versions("content_hash");
+
+-- Version `token_metadata` currently holds, NULL until a document was fetched
+ALTER TABLE token_metadata ADD COLUMN "current_version_id" INT
+  REFERENCES token_metadata_versions("id") ON DELETE SET NULL;
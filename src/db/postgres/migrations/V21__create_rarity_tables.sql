@@ -0,0 +1,83 @@
+-- Number of tokens of a collection with every (trait type, value), value is NULL for tokens that
+-- don't have the trait. "Trait Count" is counted as a trait of its own.
+CREATE TABLE trait_frequencies(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  "trait_type" TEXT NOT NULL,
+  "value" TEXT,
+  "token_count" INT NOT NULL,
+  -- token_count over the number of scored tokens of the collection
+  "frequency" DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX "idx_trait_frequencies_contract"
+  ON trait_frequencies("network", "contract_address", "trait_type");
+
+-- Rarity of every scored token, rank 1 is the rarest
+CREATE TABLE token_rarity(
+  "token_metadata_id" INT PRIMARY KEY,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  -- Product of the frequencies of the token's traits, lower is rarer
+  "statistical_score" DOUBLE PRECISION NOT NULL,
+  "statistical_rank" INT NOT NULL,
+  -- Sum of the inverse frequencies of the token's traits, higher is rarer
+  "rarity_score" DOUBLE PRECISION NOT NULL,
+  "rarity_rank" INT NOT NULL,
+  "scored_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+  -- token_rarity[token_metadata_id] -> token_metadata[id]
+  CONSTRAINT "fk_token_metadata"
+    FOREIGN KEY("token_metadata_id")
+    REFERENCES token_metadata("id")
+    ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_token_rarity_contract"
+  ON token_rarity("network", "contract_address", "rarity_rank");
+
+-- Tokens whose attributes changed since their collection was last scored. Marks are only ever
+-- inserted and deleted, so storing attributes doesn't lock rows the rarity scorer reads.
+CREATE TABLE rarity_changes(
+  "id" BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  "token_metadata_id" INT NOT NULL,
+  "marked_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+  -- rarity_changes[token_metadata_id] -> token_metadata[id]
+  CONSTRAINT "fk_token_metadata"
+    FOREIGN KEY("token_metadata_id")
+    REFERENCES token_metadata("id")
+    ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_rarity_changes_marked_at" ON rarity_changes("marked_at");
+CREATE INDEX "idx_rarity_changes_contract"
+  ON rarity_changes("network", "contract_address");
+
+-- Traits every token was last scored with, so a collection is rescored without reading the
+-- attributes of tokens that didn't change. A token without traits has a single row with NULL
+-- trait type and value.
+CREATE TABLE rarity_token_traits(
+  "token_metadata_id" INT NOT NULL,
+  "network" VARCHAR(16) NOT NULL DEFAULT 'mainnet',
+  "contract_address" VARCHAR(80) NOT NULL,
+  "trait_type" TEXT,
+  "value" TEXT,
+
+  -- rarity_token_traits[token_metadata_id] -> token_metadata[id]
+  CONSTRAINT "fk_token_metadata"
+    FOREIGN KEY("token_metadata_id")
+    REFERENCES token_metadata("id")
+    ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_rarity_token_traits_token" ON rarity_token_traits("token_metadata_id");
+CREATE INDEX "idx_rarity_token_traits_contract"
+  ON rarity_token_traits("network", "contract_address");
+
+-- Every token we already have is scored once
+INSERT INTO rarity_changes("network", "contract_address", "token_metadata_id")
+SELECT "network", "contract_address", "id" FROM token_metadata;
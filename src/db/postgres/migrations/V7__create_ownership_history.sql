@@ -0,0 +1,41 @@
+-- ERC721 ownership is stored as intervals: an owner holds the token from
+-- "valid_from_block" (inclusive) until "valid_to_block" (exclusive). The current
+-- owner has a NULL "valid_to_block".
+ALTER TABLE erc721_owners RENAME COLUMN "block" TO "valid_from_block";
+ALTER TABLE erc721_owners ADD COLUMN "valid_to_block" BIGINT;
+
+-- Close the intervals of the rows that were written before this migration
+UPDATE erc721_owners AS current
+SET "valid_to_block" = (
+  SELECT MIN(next."valid_from_block")
+  FROM erc721_owners AS next
+  WHERE
+    next."erc721_id" = current."erc721_id" AND
+    (next."valid_from_block", next."id") > (current."valid_from_block", current."id")
+);
+
+CREATE INDEX "idx_erc721_owners_interval"
+  ON erc721_owners("erc721_id", "valid_from_block", "valid_to_block");
+
+-- Every change applied to erc1155_balances, with the resulting balance
+CREATE TABLE erc1155_balance_changes(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "erc1155_id" INT NOT NULL,
+  "account" VARCHAR(80) NOT NULL,
+  "amount_low" VARCHAR(80) NOT NULL,
+  "amount_high" VARCHAR(80) NOT NULL,
+  -- TRUE if "amount" was added to the balance, FALSE if it was subtracted
+  "is_credit" BOOLEAN NOT NULL,
+  "balance_low" VARCHAR(80) NOT NULL,
+  "balance_high" VARCHAR(80) NOT NULL,
+  "block" BIGINT NOT NULL,
+
+  -- erc1155_balance_changes[erc1155_id] -> erc1155_token[id]
+  CONSTRAINT "fk_erc1155"
+    FOREIGN KEY("erc1155_id")
+    REFERENCES erc1155_token("id")
+    ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_erc1155_balance_changes_account"
+  ON erc1155_balance_changes("erc1155_id", "account", "block");
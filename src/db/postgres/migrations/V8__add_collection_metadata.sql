@@ -0,0 +1,15 @@
+-- Collection level metadata, read from contractURI() and the JSON document behind it
+ALTER TABLE contract_metadata
+  ADD COLUMN "contract_uri" TEXT,
+  ADD COLUMN "description" TEXT,
+  ADD COLUMN "image" TEXT,
+  ADD COLUMN "external_link" TEXT,
+  -- Royalty info
+  ADD COLUMN "seller_fee_basis_points" INT,
+  ADD COLUMN "fee_recipient" VARCHAR(80),
+  -- Last time owner, URIs and the collection JSON were refreshed
+  ADD COLUMN "metadata_refreshed_at" TIMESTAMPTZ,
+  -- Failed refreshes since the last successful one, retried with backoff from metadata_retry_at
+  ADD COLUMN "metadata_refresh_failures" INT NOT NULL DEFAULT 0,
+  ADD COLUMN "metadata_refresh_error" TEXT,
+  ADD COLUMN "metadata_retry_at" TIMESTAMPTZ;
@@ -0,0 +1,27 @@
+-- Refresh state of every token metadata record
+ALTER TABLE token_metadata
+  ADD COLUMN "token_uri" TEXT,
+  ADD COLUMN "last_refreshed_at" TIMESTAMPTZ,
+  ADD COLUMN "last_refresh_succeeded" BOOLEAN,
+  ADD COLUMN "last_refresh_error" TEXT;
+
+-- Scheduled token metadata refresh policy of the collection, NULL means never
+ALTER TABLE contract_metadata ADD COLUMN "token_refresh_interval_secs" BIGINT;
+
+-- On-demand refresh requests, a NULL token id refreshes every token of the contract. Requests are
+-- held by the refresher while it serves them and kept until every token they cover refreshed.
+CREATE TABLE metadata_refresh_requests(
+  "id" INT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  "contract_address" VARCHAR(80) NOT NULL,
+  "token_id_low" VARCHAR(80),
+  "token_id_high" VARCHAR(80),
+  "requested_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "attempts" INT NOT NULL DEFAULT 0,
+  -- Pushed back while the refresher holds the request and after every failure, NULL once we gave
+  -- up
+  "next_attempt_at" TIMESTAMPTZ DEFAULT NOW(),
+  "last_error" TEXT
+);
+
+CREATE INDEX "idx_metadata_refresh_requests_next_attempt_at"
+  ON metadata_refresh_requests("next_attempt_at");
@@ -1,4 +1,13 @@
+pub mod bootstrap;
+pub mod bootstrap_jobs;
+pub mod cache;
+pub mod contract;
+pub mod history;
+pub mod metadata_jobs;
+pub mod metadata_reads;
+pub mod metadata_versions;
 pub mod process;
+pub mod token;
 
 // TODO: Pack following functions into a trait that all databases can implement
 
@@ -21,6 +30,11 @@ pub async fn drop_everything(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<()> {
     sqlx::query!("DELETE FROM erc721_owners").execute(&mut transaction).await?;
     sqlx::query!("DELETE FROM erc1155_token").execute(&mut transaction).await?;
     sqlx::query!("DELETE FROM erc1155_balances").execute(&mut transaction).await?;
+    sqlx::query!("DELETE FROM erc1155_balance_changes").execute(&mut transaction).await?;
+    sqlx::query!("DELETE FROM metadata_jobs").execute(&mut transaction).await?;
+    sqlx::query!("DELETE FROM bootstrap_jobs").execute(&mut transaction).await?;
+    // Rarity of tokens and their scored traits go along with their token metadata
+    sqlx::query!("DELETE FROM trait_frequencies").execute(&mut transaction).await?;
 
     transaction.commit().await?;
 
@@ -0,0 +1,579 @@
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres, Transaction};
+use starknet::core::types::{BlockId, FieldElement};
+
+use super::{metadata_reads, metadata_versions};
+use crate::{
+    common::types::{CairoUint256, ContractType},
+    rpc::{
+        http,
+        metadata::token::{self, Attribute, DisplayType, MetadataDocument, TokenMetadata},
+        provider::RpcProvider,
+    },
+};
+
+/// Where the metadata stored for a token came from
+#[derive(Debug, Clone)]
+pub struct MetadataSource {
+    /// Token URI metadata is fetched from, expanded if the contract returned a template
+    pub token_uri: String,
+    /// Token URI as the contract returned it if it's a template, see
+    /// `token::expand_uri_template`
+    pub token_uri_template: Option<String>,
+    /// Block the token URI was read at
+    pub token_uri_block: u64,
+    /// IPFS gateway that served the metadata, `None` if it isn't on IPFS or wasn't served
+    pub gateway: Option<String>,
+}
+
+/// Token URI and metadata of a token read by `fetch_metadata`, to be stored with
+/// `store_metadata`
+#[derive(Debug)]
+pub struct FetchedMetadata {
+    pub source: MetadataSource,
+    // Token URI read at every block, kept when there's more than one, see `metadata_reads`
+    reads: Vec<(u64, String)>,
+    // Why the metadata couldn't be read for good, e.g. a malformed document
+    document: Result<MetadataDocument, String>,
+}
+
+/// Reads the token URI of a token and fetches the metadata behind it, without touching the
+/// database
+///
+/// The token URI is read at the blocks given by `METADATA_READ_BLOCK` for an event in
+/// `block_number`, see `metadata_reads::read_blocks`.
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if a call fails or the metadata is unavailable for
+/// now, e.g. its host timed out, so it's fetched again later
+pub async fn fetch_metadata(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    block_number: u64,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<FetchedMetadata> {
+    let read_blocks = metadata_reads::read_blocks(block_number, rpc).await?;
+
+    let mut reads = Vec::with_capacity(read_blocks.len());
+    for read_block in read_blocks {
+        let block_id = BlockId::Number(read_block);
+        let token_uri = match contract_type {
+            ContractType::Erc721 => {
+                token::get_erc721_uri(contract_address, token_id, &block_id, rpc).await?
+            }
+            ContractType::Erc1155 => {
+                token::get_erc1155_uri(contract_address, token_id, &block_id, rpc).await?
+            }
+        };
+        reads.push((read_block, token_uri));
+    }
+    let (token_uri_block, token_uri) =
+        reads.last().cloned().expect("metadata is read at one block at least");
+
+    let (expanded_uri, fetched) = token::get_expanded_token_metadata(&token_uri, token_id).await;
+    let mut source = MetadataSource {
+        token_uri_template: (expanded_uri != token_uri).then_some(token_uri),
+        token_uri: expanded_uri,
+        token_uri_block,
+        gateway: None,
+    };
+
+    let document = match fetched {
+        Ok((document, gateway)) => {
+            source.gateway = gateway;
+            Ok(document)
+        }
+        Err(e) if http::is_transient(&e) => return Err(e),
+        Err(e) => {
+            eprintln!("[metadata] keeping metadata, {} is invalid: {e}", source.token_uri);
+            Err(e.to_string())
+        }
+    };
+
+    Ok(FetchedMetadata { source, reads, document })
+}
+
+/// Upserts the `token_metadata` record of a token with what `fetch_metadata` read. Metadata that
+/// couldn't be read for good is recorded as a failed refresh and leaves stored metadata in place.
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if a query fails
+pub async fn store_metadata(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    fetched: &FetchedMetadata,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    if fetched.reads.len() > 1 {
+        for (read_block, token_uri) in &fetched.reads {
+            metadata_reads::record(
+                contract_address,
+                Some(token_id),
+                "token_uri",
+                token_uri,
+                *read_block,
+                transaction,
+            )
+            .await?;
+        }
+    }
+
+    match &fetched.document {
+        Ok(document) => {
+            upsert_token_metadata(
+                contract_address,
+                contract_type,
+                token_id,
+                &fetched.source,
+                document,
+                transaction,
+            )
+            .await?;
+        }
+        Err(error) => {
+            record_fetch_error(
+                contract_address,
+                contract_type,
+                token_id,
+                &fetched.source,
+                error,
+                transaction,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Inserts or overwrites the `token_metadata` record of a token along with its attributes and
+/// returns its id. A new version is kept if the document changed, see `metadata_versions`.
+pub async fn upsert_token_metadata(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    source: &MetadataSource,
+    document: &MetadataDocument,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<i32> {
+    let metadata = &document.metadata;
+    let token_metadata_id = sqlx::query!(
+        r#"
+            INSERT INTO token_metadata(
+                contract_address,
+                contract_type,
+                token_id_low,
+                token_id_high,
+                token_uri,
+                token_uri_template,
+                token_uri_block,
+                metadata_gateway,
+                last_refreshed_at,
+                last_refresh_succeeded,
+                last_refresh_error,
+                raw_metadata,
+                metadata_parsed,
+                -- Metadata
+                image,
+                image_data,
+                external_url,
+                description,
+                name,
+                background_color,
+                animation_url,
+                youtube_url)
+            VALUES($1, $2::TEXT::t_contract_type, $3, $4, $5, $6, $7, $8, NOW(), TRUE, NULL, $9,
+                $10, $11, $12, $13, $14, $15, $16, $17, $18)
+            ON CONFLICT (network, contract_address, token_id_low, token_id_high) DO UPDATE
+            SET
+                token_uri = EXCLUDED.token_uri,
+                token_uri_template = EXCLUDED.token_uri_template,
+                token_uri_block = EXCLUDED.token_uri_block,
+                metadata_gateway = EXCLUDED.metadata_gateway,
+                last_refreshed_at = EXCLUDED.last_refreshed_at,
+                last_refresh_succeeded = EXCLUDED.last_refresh_succeeded,
+                last_refresh_error = EXCLUDED.last_refresh_error,
+                raw_metadata = EXCLUDED.raw_metadata,
+                metadata_parsed = EXCLUDED.metadata_parsed,
+                image = EXCLUDED.image,
+                image_data = EXCLUDED.image_data,
+                external_url = EXCLUDED.external_url,
+                description = EXCLUDED.description,
+                name = EXCLUDED.name,
+                background_color = EXCLUDED.background_color,
+                animation_url = EXCLUDED.animation_url,
+                youtube_url = EXCLUDED.youtube_url
+            RETURNING id
+        "#,
+        format!("{contract_address:#x}"),
+        contract_type.as_str(),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+        source.token_uri,
+        source.token_uri_template,
+        i64::try_from(source.token_uri_block)?,
+        source.gateway,
+        document.raw,
+        document.parsed,
+        metadata.image,
+        metadata.image_data,
+        metadata.external_url,
+        metadata.description,
+        metadata.name,
+        metadata.background_color,
+        metadata.animation_url,
+        metadata.youtube_url
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    replace_attributes(token_metadata_id, metadata.attributes.as_deref(), transaction).await?;
+    metadata_versions::record_version(token_metadata_id, source, document, transaction).await?;
+
+    Ok(token_metadata_id)
+}
+
+/// Overwrites metadata fields of a `token_metadata` record and replaces its attributes. A new
+/// version is kept if the document changed, see `metadata_versions`.
+pub async fn update_token_metadata(
+    token_metadata_id: i32,
+    source: &MetadataSource,
+    document: &MetadataDocument,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let metadata = &document.metadata;
+    sqlx::query!(
+        r#"
+            UPDATE token_metadata
+            SET
+                token_uri = $1,
+                token_uri_template = $2,
+                token_uri_block = $3,
+                metadata_gateway = $4,
+                raw_metadata = $5,
+                metadata_parsed = $6,
+                image = $7,
+                image_data = $8,
+                external_url = $9,
+                description = $10,
+                name = $11,
+                background_color = $12,
+                animation_url = $13,
+                youtube_url = $14
+            WHERE id = $15
+        "#,
+        source.token_uri,
+        source.token_uri_template,
+        i64::try_from(source.token_uri_block)?,
+        source.gateway,
+        document.raw,
+        document.parsed,
+        metadata.image,
+        metadata.image_data,
+        metadata.external_url,
+        metadata.description,
+        metadata.name,
+        metadata.background_color,
+        metadata.animation_url,
+        metadata.youtube_url,
+        token_metadata_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    replace_attributes(token_metadata_id, metadata.attributes.as_deref(), transaction).await?;
+    metadata_versions::record_version(token_metadata_id, source, document, transaction).await?;
+
+    Ok(())
+}
+
+/// Reads back the token URI and metadata document stored in a `token_metadata` record
+pub async fn get_token_metadata(
+    token_metadata_id: i32,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<(Option<String>, MetadataDocument)> {
+    let record = sqlx::query!(
+        r#"
+            SELECT
+                token_uri,
+                raw_metadata,
+                metadata_parsed,
+                image,
+                image_data,
+                external_url,
+                description,
+                name,
+                background_color,
+                animation_url,
+                youtube_url
+            FROM token_metadata
+            WHERE id = $1
+        "#,
+        token_metadata_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let attribute_records = sqlx::query!(
+        r#"
+            SELECT value_json, display_type, trait_type
+            FROM token_metadata_attributes
+            WHERE token_metadata_id = $1
+            ORDER BY id
+        "#,
+        token_metadata_id
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let mut attributes = Vec::with_capacity(attribute_records.len());
+    for attribute in attribute_records {
+        attributes.push(Attribute {
+            value: serde_json::from_value(attribute.value_json.unwrap_or_default())?,
+            display_type: attribute.display_type.map(DisplayType::from),
+            trait_type: attribute.trait_type,
+        });
+    }
+
+    let metadata = TokenMetadata {
+        image: record.image,
+        image_data: record.image_data,
+        external_url: record.external_url,
+        description: record.description,
+        name: record.name,
+        attributes: (!attributes.is_empty()).then_some(attributes),
+        background_color: record.background_color,
+        animation_url: record.animation_url,
+        youtube_url: record.youtube_url,
+    };
+
+    let document = MetadataDocument {
+        metadata,
+        raw: record.raw_metadata,
+        parsed: record.metadata_parsed.unwrap_or_default(),
+    };
+
+    Ok((record.token_uri, document))
+}
+
+/// Finds tokens of a contract whose metadata document matches a SQL/JSON path, e.g.
+/// `$.properties.rarity ? (@ == "rare")`
+///
+/// # Errors
+/// This function returns `eyre::ErrReport` if the path is malformed or the query fails
+pub async fn find_tokens_by_json_path(
+    contract_address: FieldElement,
+    json_path: &str,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<Vec<CairoUint256>> {
+    let records = sqlx::query!(
+        r#"
+            SELECT token_id_low, token_id_high
+            FROM token_metadata
+            WHERE contract_address = $1 AND raw_metadata @? $2::TEXT::JSONPATH
+            ORDER BY id
+        "#,
+        format!("{contract_address:#x}"),
+        json_path
+    )
+    .fetch_all(pool)
+    .await?;
+
+    records
+        .into_iter()
+        .map(|record| {
+            Ok(CairoUint256::new(
+                FieldElement::from_dec_str(&record.token_id_low)?,
+                FieldElement::from_dec_str(&record.token_id_high)?,
+            ))
+        })
+        .collect()
+}
+
+/// Records metadata of a token that couldn't be fetched as a failed refresh, creating its
+/// `token_metadata` record if it doesn't have one. Metadata stored before is kept.
+async fn record_fetch_error(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    source: &MetadataSource,
+    error: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            INSERT INTO token_metadata(
+                contract_address,
+                contract_type,
+                token_id_low,
+                token_id_high,
+                token_uri,
+                token_uri_template,
+                token_uri_block,
+                last_refreshed_at,
+                last_refresh_succeeded,
+                last_refresh_error)
+            VALUES($1, $2::TEXT::t_contract_type, $3, $4, $5, $6, $7, NOW(), FALSE, $8)
+            ON CONFLICT (network, contract_address, token_id_low, token_id_high) DO UPDATE
+            SET
+                last_refreshed_at = EXCLUDED.last_refreshed_at,
+                last_refresh_succeeded = EXCLUDED.last_refresh_succeeded,
+                last_refresh_error = EXCLUDED.last_refresh_error
+        "#,
+        format!("{contract_address:#x}"),
+        contract_type.as_str(),
+        token_id.low.to_string(),
+        token_id.high.to_string(),
+        source.token_uri,
+        source.token_uri_template,
+        i64::try_from(source.token_uri_block)?,
+        error
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Records the outcome of a refresh attempt, `error` is `None` if it succeeded
+pub async fn record_refresh(
+    token_metadata_id: i32,
+    error: Option<String>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE token_metadata
+            SET
+                last_refreshed_at = NOW(),
+                last_refresh_succeeded = $1,
+                last_refresh_error = $2
+            WHERE id = $3
+        "#,
+        error.is_none(),
+        error,
+        token_metadata_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Updates the token URI, its template and the block it was read at, held in the `erc721_token`
+/// or `erc1155_token` record of the token
+pub async fn update_token_uri(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    source: &MetadataSource,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    let token_uri_block = i64::try_from(source.token_uri_block)?;
+
+    match contract_type {
+        ContractType::Erc721 => {
+            sqlx::query!(
+                r#"
+                    UPDATE erc721_token
+                    SET token_uri = $1, token_uri_template = $2, token_uri_block = $3
+                    WHERE
+                        contract_address = $4 AND
+                        token_id_low = $5 AND
+                        token_id_high = $6
+                "#,
+                source.token_uri,
+                source.token_uri_template,
+                token_uri_block,
+                format!("{contract_address:#x}"),
+                token_id.low.to_string(),
+                token_id.high.to_string(),
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        ContractType::Erc1155 => {
+            sqlx::query!(
+                r#"
+                    UPDATE erc1155_token
+                    SET token_uri = $1, token_uri_template = $2, token_uri_block = $3
+                    WHERE
+                        contract_address = $4 AND
+                        token_id_low = $5 AND
+                        token_id_high = $6
+                "#,
+                source.token_uri,
+                source.token_uri_template,
+                token_uri_block,
+                format!("{contract_address:#x}"),
+                token_id.low.to_string(),
+                token_id.high.to_string(),
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes attributes of a `token_metadata` record and inserts the given ones, values are kept
+/// as published in `value_json` and scalar ones are copied to the column of their type. The
+/// token is marked for its collection's rarity to be rescored.
+async fn replace_attributes(
+    token_metadata_id: i32,
+    attributes: Option<&[Attribute]>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        "DELETE FROM token_metadata_attributes WHERE token_metadata_id = $1",
+        token_metadata_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    for attribute in attributes.unwrap_or_default() {
+        sqlx::query!(
+            r#"
+                INSERT INTO token_metadata_attributes(
+                    token_metadata_id,
+                    display_type,
+                    trait_type,
+                    value_json,
+                    value_text,
+                    value_numeric,
+                    value_boolean,
+                    value_date)
+                VALUES($1, $2, $3, $4, $5, $6::TEXT::NUMERIC, $7, to_timestamp($8::FLOAT8))
+            "#,
+            token_metadata_id,
+            attribute.display_type.as_ref().map(DisplayType::as_str),
+            attribute.trait_type,
+            serde_json::to_value(&attribute.value)?,
+            attribute.value.as_text(),
+            attribute.value.as_number().map(|value| value.to_string()),
+            attribute.value.as_bool(),
+            attribute.date()
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    // Trait frequencies of the collection moved, see `refresh::rarity`
+    sqlx::query!(
+        r#"
+            INSERT INTO rarity_changes(network, contract_address, token_metadata_id)
+            SELECT network, contract_address, id
+            FROM token_metadata
+            WHERE id = $1
+        "#,
+        token_metadata_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
@@ -1,4 +1,7 @@
-use crate::{common::types::CairoUint256, events::HexFieldElement};
+use crate::{
+    common::types::CairoUint256,
+    events::{HexFieldElement, TransferPosition},
+};
 use starknet::core::types::{FieldElement, EmittedEvent};
 
 #[derive(Debug, Clone)]
@@ -8,6 +11,7 @@ pub struct Erc1155TransferBatch {
     pub transfers: Vec<(CairoUint256, CairoUint256)>,
     pub contract_address: HexFieldElement,
     pub block_number: u64,
+    pub position: TransferPosition,
 }
 
 impl Erc1155TransferBatch {
@@ -17,6 +21,7 @@ impl Erc1155TransferBatch {
         transfers: Vec<(CairoUint256, CairoUint256)>,
         contract_address: FieldElement,
         block_number: u64,
+        position: TransferPosition,
     ) -> Self {
         Erc1155TransferBatch {
             sender,
@@ -24,12 +29,13 @@ impl Erc1155TransferBatch {
             transfers,
             contract_address: HexFieldElement(contract_address),
             block_number,
+            position,
         }
     }
-}
 
-impl From<&EmittedEvent> for Erc1155TransferBatch {
-    fn from(event: &EmittedEvent) -> Self {
+    /// Reads a `TransferBatch` event, `event_index` is its index among the transfer events of
+    /// its contract in its transaction
+    pub fn from_event(event: &EmittedEvent, event_index: u32) -> Self {
         let contract_address = event.from_address;
         let block_number = event.block_number;
         let event_data = &event.data;
@@ -55,18 +61,30 @@ impl From<&EmittedEvent> for Erc1155TransferBatch {
             )
             .collect();
 
-        Erc1155TransferBatch::new(sender, recipient, transfers, contract_address, block_number)
+        let position = TransferPosition {
+            transaction_hash: event.transaction_hash,
+            event_index,
+            transfer_index: 0,
+        };
+
+        Erc1155TransferBatch::new(
+            sender,
+            recipient,
+            transfers,
+            contract_address,
+            block_number,
+            position,
+        )
     }
 }
 
 pub mod process_events {
     use async_trait::async_trait;
     use color_eyre::eyre;
-    use starknet::providers::jsonrpc::{HttpTransport, JsonRpcClient};
 
     use crate::{
         db::postgres::process::ProcessEvent,
-        events::erc1155::transfer_single::Erc1155TransferSingle,
+        events::{erc1155::transfer_single::Erc1155TransferSingle, TransferPosition},
     };
 
     use super::Erc1155TransferBatch;
@@ -75,10 +93,9 @@ pub mod process_events {
     impl ProcessEvent for Erc1155TransferBatch {
         async fn process(
             &self,
-            rpc: &'static JsonRpcClient<HttpTransport>,
             transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
         ) -> eyre::Result<()> {
-            for transfer in &self.transfers {
+            for (transfer_index, transfer) in (0..).zip(&self.transfers) {
                 Erc1155TransferSingle::new(
                     self.sender,
                     self.recipient,
@@ -86,8 +103,9 @@ pub mod process_events {
                     transfer.1,
                     self.contract_address.0,
                     self.block_number,
+                    TransferPosition { transfer_index, ..self.position },
                 )
-                .process(rpc, transaction)
+                .process(transaction)
                 .await?;
             }
 
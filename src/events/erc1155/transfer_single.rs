@@ -1,4 +1,7 @@
-use crate::{common::types::CairoUint256, events::HexFieldElement};
+use crate::{
+    common::types::CairoUint256,
+    events::{HexFieldElement, TransferPosition},
+};
 use starknet::core::types::{EmittedEvent, FieldElement};
 
 #[derive(Debug, Clone)]
@@ -9,6 +12,7 @@ pub struct Erc1155TransferSingle {
     pub amount: CairoUint256,
     pub contract_address: HexFieldElement,
     pub block_number: u64,
+    pub position: TransferPosition,
 }
 
 impl Erc1155TransferSingle {
@@ -19,6 +23,7 @@ impl Erc1155TransferSingle {
         amount: CairoUint256,
         contract_address: FieldElement,
         block_number: u64,
+        position: TransferPosition,
     ) -> Self {
         Erc1155TransferSingle {
             sender: HexFieldElement(sender),
@@ -27,12 +32,13 @@ impl Erc1155TransferSingle {
             amount,
             contract_address: HexFieldElement(contract_address),
             block_number,
+            position,
         }
     }
-}
 
-impl From<&EmittedEvent> for Erc1155TransferSingle {
-    fn from(event: &EmittedEvent) -> Self {
+    /// Reads a `TransferSingle` event, `event_index` is its index among the transfer events of
+    /// its contract in its transaction
+    pub fn from_event(event: &EmittedEvent, event_index: u32) -> Self {
         let contract_address = event.from_address;
         let block_number = event.block_number;
         let event_data = &event.data;
@@ -41,6 +47,11 @@ impl From<&EmittedEvent> for Erc1155TransferSingle {
         let recipient = event_data[2];
         let token_id = CairoUint256::new(event_data[3], event_data[4]);
         let amount = CairoUint256::new(event_data[5], event_data[6]);
+        let position = TransferPosition {
+            transaction_hash: event.transaction_hash,
+            event_index,
+            transfer_index: 0,
+        };
 
         Erc1155TransferSingle::new(
             sender,
@@ -49,6 +60,7 @@ impl From<&EmittedEvent> for Erc1155TransferSingle {
             amount,
             contract_address,
             block_number,
+            position,
         )
     }
 }
@@ -56,18 +68,12 @@ impl From<&EmittedEvent> for Erc1155TransferSingle {
 pub mod process_event {
     use async_trait::async_trait;
     use color_eyre::eyre;
-    use starknet::{
-        core::types::{BlockId, FieldElement},
-        providers::jsonrpc::{HttpTransport, JsonRpcClient},
-    };
+    use starknet::core::types::FieldElement;
 
     use crate::{
-        common::types::CairoUint256,
-        db::postgres::process::ProcessEvent,
-        rpc::metadata::{
-            contract,
-            token::{self, TokenMetadata},
-        },
+        common::types::{CairoUint256, ContractType},
+        db::postgres::{self, process::ProcessEvent},
+        events::{HexFieldElement, TransferPosition},
     };
 
     use super::Erc1155TransferSingle;
@@ -76,12 +82,11 @@ pub mod process_event {
     impl ProcessEvent for Erc1155TransferSingle {
         async fn process(
             &self,
-            rpc: &'static JsonRpcClient<HttpTransport>,
             transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
         ) -> eyre::Result<()> {
             if self.sender == FieldElement::ZERO {
                 println!("[erc1155] processing mint");
-                self::process_mint(self, rpc, transaction).await
+                self::process_mint(self, transaction).await
             } else {
                 println!("[erc1155] processing transfer");
                 self::process_transfer(self, transaction).await
@@ -91,105 +96,51 @@ pub mod process_event {
 
     pub async fn process_mint(
         event: &Erc1155TransferSingle,
-        rpc: &JsonRpcClient<HttpTransport>,
         transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
     ) -> eyre::Result<()> {
-        let block_id = BlockId::Number(event.block_number);
-        let block_number = i64::try_from(event.block_number).unwrap();
-        let token_uri = self::fetch_and_insert_metadata(event, rpc, &mut *transaction).await?;
-        println!("[process_mint] got uri {:?} for token #{}", token_uri, event.token_id.low);
-
-        // Check contract metadata
-        let contract_metadata_id = sqlx::query!(
-            r#"
-                SELECT id
-                FROM contract_metadata 
-                WHERE
-                    contract_address = $1 AND
-                    contract_type = 'ERC1155'
-            "#,
-            event.contract_address.to_string()
+        // Get contract metadata, registering the contract if it's new
+        let contract_metadata_id = postgres::contract::get_or_register(
+            event.contract_address.0,
+            ContractType::Erc1155,
+            event.block_number,
+            &mut *transaction,
         )
-        .fetch_one(&mut *transaction)
-        .await
-        .map(|record| record.id);
-
-        // If no contract metadata exists, insert a new record, or use the existing one
-        let contract_metadata_id = match contract_metadata_id {
-            Ok(id) => id,
-            Err(_) => {
-                println!("[process_mint] no metadata found, inserting a new one");
-                let name = contract::get_name(event.contract_address.0, &block_id, rpc).await;
-                let symbol = contract::get_symbol(event.contract_address.0, &block_id, rpc).await;
-                println!("[process_mint] name: {}, symbol: {}", &name, &symbol);
-
-                sqlx::query!(
-                    r#"
-                    INSERT INTO contract_metadata(
-                        contract_address,
-                        contract_type,
-                        name,
-                        symbol,
-                        last_updated_block)
-                    VALUES ($1, 'ERC1155', $2, $3, $4)
-                    RETURNING id
-                "#,
-                    event.contract_address.to_string(),
-                    name,
-                    symbol,
-                    block_number
-                )
-                .fetch_one(&mut *transaction)
-                .await?
-                .id
-            }
-        };
+        .await?;
 
-        // Check if ERC1155 token record exists for given token id
-        let erc1155_token_exists = sqlx::query!(
+        // Create the ERC1155 record if there isn't one for given token id
+        sqlx::query!(
             r#"
-                SELECT EXISTS(
-                    SELECT * FROM erc1155_token
-                    WHERE contract_address = $1 AND
-                    token_id_low = $2 AND
-                    token_id_high = $3
+                INSERT INTO erc1155_token(
+                    contract_id,
+                    contract_address,
+                    token_id_low,
+                    token_id_high,
+                    last_updated_block
                 )
+                VALUES($1, $2, $3, $4, $5)
+                ON CONFLICT (network, contract_address, token_id_low, token_id_high) DO NOTHING
             "#,
+            contract_metadata_id,
             event.contract_address.to_string(),
             event.token_id.low.to_string(),
             event.token_id.high.to_string(),
+            i64::try_from(event.block_number).unwrap()
         )
-        .fetch_one(&mut *transaction)
-        .await?
-        .exists
-        .unwrap_or_default();
+        .execute(&mut *transaction)
+        .await?;
 
-        // If there's no ERC1155 record, create a new one
-        if !erc1155_token_exists {
-            sqlx::query!(
-                r#"
-                    INSERT INTO erc1155_token(
-                        contract_id,
-                        contract_address,
-                        token_id_low,
-                        token_id_high,
-                        token_uri,
-                        last_updated_block
-                    )
-                    VALUES($1, $2, $3, $4, $5, $6)
-                "#,
-                contract_metadata_id,
-                event.contract_address.to_string(),
-                event.token_id.low.to_string(),
-                event.token_id.high.to_string(),
-                token_uri,
-                i64::try_from(event.block_number).unwrap()
-            )
-            .execute(&mut *transaction)
-            .await?;
-        }
+        // Token URI and metadata are filled in by the metadata workers
+        postgres::metadata_jobs::enqueue(
+            event.contract_address.0,
+            ContractType::Erc1155,
+            event.token_id,
+            event.block_number,
+            &mut *transaction,
+        )
+        .await?;
 
-        Ok(())
+        // Credit the minted amount to the recipient
+        self::process_transfer(event, transaction).await
     }
 
     pub async fn process_transfer(
@@ -198,89 +149,93 @@ pub mod process_event {
     ) -> eyre::Result<()> {
         let block_number = i64::try_from(event.block_number).unwrap();
 
-        // Get the corresponding ERC1155 token id
-        let token_id = sqlx::query!(
-            r#"
-                SELECT id
-                FROM erc1155_token
-                WHERE
-                    contract_address = $1 AND 
-                    token_id_low = $2 AND
-                    token_id_high = $3
-            "#,
-            event.contract_address.to_string(),
-            event.token_id.low.to_string(),
-            event.token_id.high.to_string(),
+        // Get the corresponding ERC1155 token id, tokens minted before our starting block are
+        // bootstrapped from chain state
+        let token_id = postgres::bootstrap::erc1155_token(
+            event.contract_address.0,
+            event.token_id,
+            event.block_number,
+            &mut *transaction,
         )
-        .fetch_one(&mut *transaction)
-        .await
-        .map(|record| record.id);
+        .await?;
 
-        let token_id = match token_id {
-            Ok(record) => record,
-            Err(_) => eyre::bail!("no matching token in db"),
-        };
+        // First, update from balance. Mints have no sender balance to update
+        if event.sender != FieldElement::ZERO {
+            postgres::bootstrap::erc1155_balance(
+                token_id,
+                event.contract_address.0,
+                event.token_id,
+                event.sender.0,
+                event.block_number,
+                &mut *transaction,
+            )
+            .await?;
+            self::update_balance(
+                token_id,
+                &event.sender,
+                event.amount,
+                false,
+                block_number,
+                &event.position,
+                &mut *transaction,
+            )
+            .await?;
+        }
 
-        // First, update from balance
-        let balance_record = sqlx::query!(
-            r#"
-                SELECT id, balance_low, balance_high
-                FROM erc1155_balances
-                WHERE erc1155_id = $1 AND
-                account = $2
-            "#,
+        // Update to balance
+        postgres::bootstrap::erc1155_balance(
             token_id,
-            event.sender.to_string()
+            event.contract_address.0,
+            event.token_id,
+            event.recipient.0,
+            event.block_number,
+            &mut *transaction,
         )
-        .fetch_one(&mut *transaction)
-        .await
-        .ok();
-
-        match balance_record {
-            Some(record) => {
-                let before_balance = CairoUint256::new(
-                    FieldElement::from_dec_str(&record.balance_low)
-                        .expect("balance_low isn't a felt"),
-                    FieldElement::from_dec_str(&record.balance_high)
-                        .expect("balance_high isn't a felt"),
-                );
-                let new_balance = before_balance - event.amount;
+        .await?;
+        self::update_balance(
+            token_id,
+            &event.recipient,
+            event.amount,
+            true,
+            block_number,
+            &event.position,
+            &mut *transaction,
+        )
+        .await?;
 
-                sqlx::query!(
-                    r#"
-                        UPDATE erc1155_balances
-                        SET balance_low = $1, balance_high = $2
-                        WHERE id = $3
-                    "#,
-                    new_balance.low.to_string(),
-                    new_balance.high.to_string(),
-                    record.id
-                )
-                .execute(&mut *transaction)
-                .await?;
-            }
-            None => {
-                println!("Impossible state, from balance 0");
-            }
-        }
+        Ok(())
+    }
 
-        // Update to balance
+    /// Adds `amount` to (or subtracts it from) the balance of `account` and logs the change in
+    /// `erc1155_balance_changes`
+    ///
+    /// Changes are keyed by the transfer's `position`, a transfer that was already applied, e.g.
+    /// in a batch replayed after a restart, is left out
+    async fn update_balance(
+        erc1155_id: i32,
+        account: &HexFieldElement,
+        amount: CairoUint256,
+        is_credit: bool,
+        block_number: i64,
+        position: &TransferPosition,
+        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> eyre::Result<()> {
+        // Locked so a balance seeded meanwhile isn't overwritten, see `seed_erc1155_balance`
         let balance_record = sqlx::query!(
             r#"
-                SELECT id, balance_low, balance_high
+                SELECT balance_low, balance_high
                 FROM erc1155_balances
                 WHERE erc1155_id = $1 AND
                 account = $2
+                FOR UPDATE
             "#,
-            token_id,
-            event.recipient.to_string()
+            erc1155_id,
+            account.to_string()
         )
-        .fetch_one(&mut *transaction)
-        .await
-        .ok();
+        .fetch_optional(&mut *transaction)
+        .await?;
 
-        match balance_record {
-            // Update the existing balance
+        let new_balance = match balance_record {
             Some(record) => {
                 let before_balance = CairoUint256::new(
                     FieldElement::from_dec_str(&record.balance_low)
@@ -288,122 +243,88 @@ pub mod process_event {
                     FieldElement::from_dec_str(&record.balance_high)
                         .expect("balance_high isn't a felt"),
                 );
-                let new_balance = before_balance + event.amount;
 
-                // Update the existing balance
-                sqlx::query!(
-                    r#"
-                        UPDATE erc1155_balances
-                        SET balance_low = $1, balance_high = $2
-                        WHERE id = $3
-                    "#,
-                    new_balance.low.to_string(),
-                    new_balance.high.to_string(),
-                    record.id
-                )
-                .execute(&mut *transaction)
-                .await?;
+                if is_credit {
+                    before_balance + amount
+                } else {
+                    before_balance - amount
+                }
             }
+            None if is_credit => amount,
             None => {
-                // Insert new balance
-                sqlx::query!(
-                    r#"
-                        INSERT INTO erc1155_balances(
-                            erc1155_id,
-                            account,
-                            balance_low,
-                            balance_high,
-                            last_updated_block)
-                        VALUES ($1, $2, $3, $4, $5)
-                    "#,
-                    token_id,
-                    event.recipient.to_string(),
-                    event.amount.low.to_string(),
-                    event.amount.high.to_string(),
-                    block_number
-                )
-                .execute(&mut *transaction)
-                .await?;
+                println!("Impossible state, from balance 0");
+                return Ok(());
             }
-        }
-
-        Ok(())
-    }
-
-    async fn fetch_and_insert_metadata(
-        event: &Erc1155TransferSingle,
-        rpc: &JsonRpcClient<HttpTransport>,
-        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> eyre::Result<String> {
-        let token_uri =
-            token::get_erc1155_uri(event.contract_address.0, rpc, event.token_id).await;
-        let metadata_result = token::get_token_metadata(&token_uri).await;
-        let metadata = match metadata_result {
-            Ok(metadata) => metadata,
-            Err(_) => TokenMetadata::default(),
         };
 
-        // Insert token_metadata
-        let token_metadata_id = sqlx::query!(
+        let change = sqlx::query!(
             r#"
-                INSERT INTO token_metadata(
-                    contract_address,
-                    contract_type,
-                    token_id_low,
-                    token_id_high,
-                    -- Metadata
-                    image,
-                    image_data,
-                    external_url,
-                    description,
-                    name,
-                    background_color,
-                    animation_url,
-                    youtube_url)
-                VALUES($1, 'ERC1155', $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                INSERT INTO erc1155_balance_changes(
+                    erc1155_id,
+                    account,
+                    amount_low,
+                    amount_high,
+                    is_credit,
+                    balance_low,
+                    balance_high,
+                    block,
+                    transaction_hash,
+                    event_index,
+                    transfer_index)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                ON CONFLICT (
+                    erc1155_id,
+                    account,
+                    is_credit,
+                    transaction_hash,
+                    event_index,
+                    transfer_index) DO NOTHING
                 RETURNING id
             "#,
-            event.contract_address.to_string(),
-            event.token_id.low.to_string(),
-            event.token_id.high.to_string(),
-            metadata.image,
-            metadata.image_data,
-            metadata.external_url,
-            metadata.description,
-            metadata.name,
-            metadata.background_color,
-            metadata.animation_url,
-            metadata.youtube_url
+            erc1155_id,
+            account.to_string(),
+            amount.low.to_string(),
+            amount.high.to_string(),
+            is_credit,
+            new_balance.low.to_string(),
+            new_balance.high.to_string(),
+            block_number,
+            format!("{:#x}", position.transaction_hash),
+            i32::try_from(position.event_index)?,
+            i32::try_from(position.transfer_index)?
         )
-        .fetch_one(&mut *transaction)
-        .await?
-        .id;
+        .fetch_optional(&mut *transaction)
+        .await?;
 
-        // Insert token metadata attributes
-        if let Some(attributes) = metadata.attributes {
-            for attribute in &attributes {
-                sqlx::query!(
-                    r#"
-                    INSERT INTO token_metadata_attributes(
-                        token_metadata_id,
-                        value,
-                        display_type,
-                        trait_type)
-                    VALUES($1, $2, $3, $4)
-                "#,
-                    token_metadata_id,
-                    serde_json::to_string(&attribute.value)
-                        .expect("attribute.value serialize failed"),
-                    serde_json::to_string(&attribute.display_type)
-                        .expect("attribute.display_type serialize failed"),
-                    serde_json::to_string(&attribute.trait_type)
-                        .expect("attribute.trait_type serialize failed")
-                )
-                .execute(&mut *transaction)
-                .await?;
-            }
+        if change.is_none() {
+            println!("[erc1155] transfer applied already, skipping {position:?}");
+            return Ok(());
         }
 
-        Ok(token_uri)
+        sqlx::query!(
+            r#"
+                INSERT INTO erc1155_balances(
+                    erc1155_id,
+                    account,
+                    balance_low,
+                    balance_high,
+                    last_updated_block)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (erc1155_id, account) DO UPDATE
+                SET
+                    balance_low = EXCLUDED.balance_low,
+                    balance_high = EXCLUDED.balance_high,
+                    last_updated_block = EXCLUDED.last_updated_block
+            "#,
+            erc1155_id,
+            account.to_string(),
+            new_balance.low.to_string(),
+            new_balance.high.to_string(),
+            block_number
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Ok(())
     }
 }
@@ -46,30 +46,20 @@ pub mod process_event {
     use async_trait::async_trait;
     use color_eyre::eyre;
     use sqlx::{Postgres, Transaction};
-    use starknet::{
-        core::types::{FieldElement, BlockId},
-        providers::jsonrpc::{HttpTransport, JsonRpcClient},
-    };
+    use starknet::core::types::FieldElement;
 
     use super::Erc721Transfer;
     use crate::{
-        db::postgres::process::ProcessEvent,
-        rpc::metadata::{
-            contract,
-            token::{self, TokenMetadata},
-        },
+        common::types::ContractType,
+        db::postgres::{self, process::ProcessEvent},
     };
 
     #[async_trait]
     impl ProcessEvent for Erc721Transfer {
-        async fn process(
-            &self,
-            rpc: &'static JsonRpcClient<HttpTransport>,
-            transaction: &mut Transaction<'_, Postgres>,
-        ) -> eyre::Result<()> {
+        async fn process(&self, transaction: &mut Transaction<'_, Postgres>) -> eyre::Result<()> {
             if self.sender == FieldElement::ZERO {
                 println!("[erc721] processing mint");
-                self::process_mint(self, rpc, transaction).await
+                self::process_mint(self, transaction).await
             } else {
                 println!("[erc721] processing transfer");
                 self::process_transfer(self, transaction).await
@@ -79,58 +69,15 @@ pub mod process_event {
 
     pub async fn process_mint(
         event: &Erc721Transfer,
-        rpc: &JsonRpcClient<HttpTransport>,
         transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
     ) -> eyre::Result<()> {
-        let block_id = BlockId::Number(event.block_number);
-        let block_number = i64::try_from(event.block_number).unwrap();
-        let token_uri = fetch_and_insert_metadata(event, rpc, &mut *transaction).await.ok();
-        println!("[process_mint] got uri {:?} for token #{}", token_uri, event.token_id.low);
-
-        // Check contract metadata
-        let contract_metadata_id = sqlx::query!(
-            r#"
-                SELECT id
-                FROM contract_metadata 
-                WHERE
-                    contract_address = $1 AND
-                    contract_type = 'ERC721'
-            "#,
-            event.contract_address.to_string()
+        // Get contract metadata, registering the contract if it's new
+        let contract_metadata_id = postgres::bootstrap::erc721_contract(
+            event.contract_address.0,
+            event.block_number,
+            &mut *transaction,
         )
-        .fetch_one(&mut *transaction)
-        .await
-        .map(|record| record.id);
-
-        let contract_metadata_id = match contract_metadata_id {
-            Ok(id) => id,
-            Err(_) => {
-                println!("[process_mint] no metadata found, inserting a new one");
-                let name = contract::get_name(event.contract_address.0, &block_id, rpc).await;
-                let symbol = contract::get_symbol(event.contract_address.0, &block_id, rpc).await;
-                println!("[process_mint] name: {}, symbol: {}", &name, &symbol);
-
-                sqlx::query!(
-                    r#"
-                    INSERT INTO contract_metadata(
-                        contract_address,
-                        contract_type,
-                        name,
-                        symbol,
-                        last_updated_block)
-                    VALUES ($1, 'ERC721', $2, $3, $4)
-                    RETURNING id
-                "#,
-                    event.contract_address.to_string(),
-                    name,
-                    symbol,
-                    block_number
-                )
-                .fetch_one(&mut *transaction)
-                .await?
-                .id
-            }
-        };
+        .await?;
 
         // Insert Erc721 data
         let inserted_id = sqlx::query!(
@@ -141,9 +88,13 @@ pub mod process_event {
                     token_id_low,
                     token_id_high,
                     latest_owner,
-                    token_uri,
                     last_updated_block)
-                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (network, contract_address, token_id_low, token_id_high) DO UPDATE
+                SET
+                    contract_id = EXCLUDED.contract_id,
+                    latest_owner = EXCLUDED.latest_owner,
+                    last_updated_block = EXCLUDED.last_updated_block
                 RETURNING id
             "#,
             event.contract_address.to_string(),
@@ -151,24 +102,23 @@ pub mod process_event {
             event.token_id.low.to_string(),
             event.token_id.high.to_string(),
             event.recipient.to_string(),
-            token_uri,
             i64::try_from(event.block_number).unwrap()
         )
         .fetch_one(&mut *transaction)
         .await?
         .id;
 
-        // Add address to owners
-        sqlx::query!(
-            r#"
-                INSERT INTO erc721_owners(erc721_id, owner, block)
-                VALUES($1, $2, $3)
-            "#,
-            inserted_id,
-            event.recipient.to_string(),
-            block_number
+        // Open the first ownership interval
+        self::insert_owner(inserted_id, event, &mut *transaction).await?;
+
+        // Token URI and metadata are filled in by the metadata workers
+        postgres::metadata_jobs::enqueue(
+            event.contract_address.0,
+            ContractType::Erc721,
+            event.token_id,
+            event.block_number,
+            &mut *transaction,
         )
-        .execute(&mut *transaction)
         .await?;
 
         Ok(())
@@ -180,50 +130,23 @@ pub mod process_event {
     ) -> eyre::Result<()> {
         let block_number = i64::try_from(event.block_number).unwrap();
 
-        // Find the ERC721 entry with given contract address and id
-        let erc721_id = sqlx::query!(
-            r#"
-                SELECT id
-                FROM erc721_token
-                WHERE
-                    contract_address = $1 AND
-                    token_id_low = $2 AND
-                    token_id_high = $3
-            "#,
-            event.contract_address.to_string(),
-            event.token_id.low.to_string(),
-            event.token_id.high.to_string(),
+        // Find the ERC721 entry with given contract address and id, tokens minted before our
+        // starting block are bootstrapped from chain state
+        let contract_metadata_id = postgres::bootstrap::erc721_contract(
+            event.contract_address.0,
+            event.block_number,
+            &mut *transaction,
         )
-        .fetch_one(&mut *transaction)
-        .await;
-
-        let erc721_id = match erc721_id {
-            Ok(record) => record.id,
-            Err(_) => {
-                sqlx::query!(
-                    r#"
-                        INSERT INTO erc721_token(
-                            contract_address,
-                            token_id_low,
-                            token_id_high,
-                            latest_owner,
-                            token_uri,
-                            last_updated_block)
-                        VALUES ($1, $2, $3, $4, $5, $6)
-                        RETURNING id
-                    "#,
-                    event.contract_address.to_string(),
-                    event.token_id.low.to_string(),
-                    event.token_id.high.to_string(),
-                    event.recipient.to_string(),
-                    String::new(),
-                    i64::try_from(event.block_number).unwrap()
-                )
-                .fetch_one(&mut *transaction)
-                .await?
-                .id
-            }
-        };
+        .await?;
+        let erc721_id = postgres::bootstrap::erc721_token(
+            contract_metadata_id,
+            event.contract_address.0,
+            event.token_id,
+            event.sender.0,
+            event.block_number,
+            &mut *transaction,
+        )
+        .await?;
 
         // Update latest owner
         sqlx::query!(
@@ -240,102 +163,49 @@ pub mod process_event {
         .await?;
 
         // Update owners list
-        sqlx::query!(
-            r#"
-                INSERT INTO erc721_owners(erc721_id, owner, block)
-                VALUES($1, $2, $3)
-            "#,
-            erc721_id,
-            event.recipient.to_string(),
-            block_number
-        )
-        .execute(&mut *transaction)
-        .await?;
+        self::insert_owner(erc721_id, event, &mut *transaction).await?;
 
         Ok(())
     }
 
-    /// Fetches metadata for given event's token ID and insert a new metadata record for it
-    ///
-    /// Returns token URI
-    ///
-    /// # Panics
+    /// Closes the open ownership interval of the token and opens a new one for the recipient,
+    /// starting at the event block
     ///
-    /// Panics if database reads or insertions fail
-    async fn fetch_and_insert_metadata(
+    /// Replaying the same transfer leaves the recipient's interval open and doesn't add a new one
+    async fn insert_owner(
+        erc721_id: i32,
         event: &Erc721Transfer,
-        rpc: &JsonRpcClient<HttpTransport>,
         transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> eyre::Result<String> {
-        let token_uri =
-            token::get_erc721_uri(event.contract_address.0, rpc, event.token_id).await;
-        let metadata_result = token::get_token_metadata(&token_uri).await;
-        let metadata = match metadata_result {
-            Ok(metadata) => metadata,
-            Err(_) => TokenMetadata::default(),
-        };
+    ) -> eyre::Result<()> {
+        let block_number = i64::try_from(event.block_number).unwrap();
 
-        // Insert token_metadata
-        let token_metadata_id = sqlx::query!(
+        sqlx::query!(
             r#"
-                INSERT INTO token_metadata(
-                    contract_address,
-                    contract_type,
-                    token_id_low,
-                    token_id_high,
-                    -- Metadata
-                    image,
-                    image_data,
-                    external_url,
-                    description,
-                    name,
-                    background_color,
-                    animation_url,
-                    youtube_url)
-                VALUES($1, 'ERC721', $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
-                RETURNING id
+                UPDATE erc721_owners
+                SET valid_to_block = $1
+                WHERE erc721_id = $2 AND valid_to_block IS NULL AND owner <> $3
             "#,
-            event.contract_address.to_string(),
-            event.token_id.low.to_string(),
-            event.token_id.high.to_string(),
-            metadata.image,
-            metadata.image_data,
-            metadata.external_url,
-            metadata.description,
-            metadata.name,
-            metadata.background_color,
-            metadata.animation_url,
-            metadata.youtube_url
+            block_number,
+            erc721_id,
+            event.recipient.to_string(),
         )
-        .fetch_one(&mut *transaction)
-        .await?
-        .id;
+        .execute(&mut *transaction)
+        .await?;
 
-        // Insert token metadata attributes
-        if let Some(attributes) = metadata.attributes {
-            for attribute in &attributes {
-                sqlx::query!(
-                    r#"
-                    INSERT INTO token_metadata_attributes(
-                        token_metadata_id,
-                        value,
-                        display_type,
-                        trait_type)
-                    VALUES($1, $2, $3, $4)
-                "#,
-                    token_metadata_id,
-                    serde_json::to_string(&attribute.value)
-                        .expect("attribute.value serialize failed"),
-                    serde_json::to_string(&attribute.display_type)
-                        .expect("attribute.display_type serialize failed"),
-                    serde_json::to_string(&attribute.trait_type)
-                        .expect("attribute.trait_type serialize failed")
-                )
-                .execute(&mut *transaction)
-                .await?;
-            }
-        }
+        sqlx::query!(
+            r#"
+                INSERT INTO erc721_owners(erc721_id, owner, valid_from_block)
+                VALUES($1, $2, $3)
+                ON CONFLICT (erc721_id, owner, valid_from_block) DO UPDATE
+                SET valid_to_block = NULL
+            "#,
+            erc721_id,
+            event.recipient.to_string(),
+            block_number
+        )
+        .execute(&mut *transaction)
+        .await?;
 
-        Ok(token_uri)
+        Ok(())
     }
 }
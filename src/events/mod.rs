@@ -4,19 +4,16 @@ pub mod erc721;
 use async_trait::async_trait;
 use color_eyre::eyre;
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
-use starknet::{
-    core::types::{BlockId, EmittedEvent, FieldElement},
-    providers::jsonrpc::{HttpTransport, JsonRpcClient},
-};
-use std::{str::FromStr, default};
+use sqlx::Postgres;
+use starknet::core::types::{BlockId, EmittedEvent, FieldElement};
+use std::{collections::HashMap, str::FromStr, default};
 
 use crate::{
     common::starknet_constants::{
         TRANSFER_BATCH_EVENT_KEY, TRANSFER_EVENT_KEY, TRANSFER_SINGLE_EVENT_KEY,
     },
-    db::postgres::process::ProcessEvent,
-    rpc::metadata::contract,
+    db::postgres::{cache::ContractCache, process::ProcessEvent},
+    rpc::provider::RpcProvider,
 };
 
 use self::{
@@ -33,23 +30,19 @@ pub enum Event {
 
 #[async_trait]
 impl ProcessEvent for Event {
-    async fn process(
-        &self,
-        rpc: &'static JsonRpcClient<HttpTransport>,
-        transaction: &mut sqlx::Transaction<'_, Postgres>,
-    ) -> eyre::Result<()> {
+    async fn process(&self, transaction: &mut sqlx::Transaction<'_, Postgres>) -> eyre::Result<()> {
         match self {
             Self::Erc721Transfer(event) => {
                 println!("[process] erc721 transfer");
-                event.process(rpc, transaction).await
+                event.process(transaction).await
             }
             Self::Erc1155TransferSingle(event) => {
                 println!("[process] erc1155 transfer single");
-                event.process(rpc, transaction).await
+                event.process(transaction).await
             }
             Self::Erc1155TransferBatch(event) => {
                 println!("[process] erc1155 transfer batch");
-                event.process(rpc, transaction).await
+                event.process(transaction).await
             }
         }
     }
@@ -85,14 +78,14 @@ impl EventBatch {
     }
 }
 
-pub struct EventHandler<'a> {
-    rpc: &'a JsonRpcClient<HttpTransport>,
-    pool: &'a Pool<Postgres>,
+pub struct EventHandler<'a, R> {
+    rpc: &'a R,
+    cache: &'a ContractCache,
 }
 
-impl<'a> EventHandler<'a> {
-    pub fn new(rpc: &'a JsonRpcClient<HttpTransport>, pool: &'a Pool<Postgres>) -> Self {
-        EventHandler { rpc, pool }
+impl<'a, R: RpcProvider> EventHandler<'a, R> {
+    pub fn new(rpc: &'a R, cache: &'a ContractCache) -> Self {
+        EventHandler { rpc, cache }
     }
 
     pub async fn read_events<'fi>(
@@ -103,11 +96,19 @@ impl<'a> EventHandler<'a> {
         filter: EventFilter<'fi>
     ) -> eyre::Result<EventBatch> {
         let mut event_infos = Vec::<Event>::new();
+        // Next index among the transfer events of a contract in a transaction, it's the same
+        // whether every contract or a whitelist is read
+        let mut event_indexes = HashMap::<(FieldElement, FieldElement), u32>::new();
 
         // For every emitted event, try to extract Event information out of it
         // If it fails, ignore the error; most likely the contract is erc20 and
         // we don't want to index them atm
         for event in events {
+            let next_index =
+                event_indexes.entry((event.transaction_hash, event.from_address)).or_default();
+            let event_index = *next_index;
+            *next_index += 1;
+
             // If we're using whitelist, skip the events that don't 
             if let EventFilter::Whitelist(whitelist) = filter {
                 if !whitelist.contains(&event.from_address) {
@@ -116,7 +117,7 @@ impl<'a> EventHandler<'a> {
             }
 
 
-            if let Ok(event_info) = self.read_event(event).await {
+            if let Ok(event_info) = self.read_event(event, event_index).await {
                 event_infos.push(event_info);
             }
         }
@@ -124,62 +125,47 @@ impl<'a> EventHandler<'a> {
         Ok(EventBatch::new(batch_id, from_block_number, event_infos))
     }
 
-    pub async fn read_event(&self, event: &EmittedEvent) -> eyre::Result<Event> {
+    /// Reads an event, `event_index` is its index among the transfer events of its contract in
+    /// its transaction
+    pub async fn read_event(&self, event: &EmittedEvent, event_index: u32) -> eyre::Result<Event> {
         let block_id = BlockId::Number(event.block_number);
         let contract_address = event.from_address;
 
-        let blacklisted = sqlx::query!(
-            r#"
-                SELECT EXISTS (
-                    SELECT 1
-                    FROM blacklisted_contracts
-                    WHERE address = $1
-                )
-            "#,
-            contract_address.to_string()
-        )
-        .fetch_one(self.pool)
-        .await?
-        .exists
-        .unwrap_or_default();
-
-        if blacklisted {
+        if self.cache.is_blacklisted(contract_address) {
             eyre::bail!("Contract blacklisted")
         }
 
         let keys = &event.keys;
         if keys.contains(&TRANSFER_EVENT_KEY) {
             // Both ERC20 and ERC721 contracts use same event key to represent transfers so
-            // we have to check if the contract is ERC721 and blacklist if not so.
-            let is_erc721 = contract::is_erc721(contract_address, &block_id, self.rpc).await?;
+            // we have to check if the contract is ERC721, once per contract class.
+            let is_erc721 = self.cache.is_erc721(contract_address, &block_id, self.rpc).await?;
 
             if is_erc721 {
                 Ok(Event::Erc721Transfer(Erc721Transfer::from(event)))
             } else {
-                // Blacklist the non-ERC721 token
-                sqlx::query!(
-                    r#"
-                        INSERT INTO blacklisted_contracts(address)
-                        VALUES ($1)
-                        ON CONFLICT DO NOTHING
-                    "#,
-                    contract_address.to_string()
-                )
-                .execute(self.pool)
-                .await?;
-
                 eyre::bail!("No matching event")
             }
         } else if keys.contains(&TRANSFER_SINGLE_EVENT_KEY) {
-            Ok(Event::Erc1155TransferSingle(Erc1155TransferSingle::from(event)))
+            Ok(Event::Erc1155TransferSingle(Erc1155TransferSingle::from_event(event, event_index)))
         } else if keys.contains(&TRANSFER_BATCH_EVENT_KEY) {
-            Ok(Event::Erc1155TransferBatch(Erc1155TransferBatch::from(event)))
+            Ok(Event::Erc1155TransferBatch(Erc1155TransferBatch::from_event(event, event_index)))
         } else {
             eyre::bail!("No matching event")
         }
     }
 }
 
+/// Where a transfer happened on chain, telling a replayed transfer from a new one
+#[derive(Debug, Clone, Copy)]
+pub struct TransferPosition {
+    pub transaction_hash: FieldElement,
+    /// Index of the event among the transfer events of its contract in the transaction
+    pub event_index: u32,
+    /// Index of the transfer in its `TransferBatch` event, 0 for other events
+    pub transfer_index: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct HexFieldElement(FieldElement);
 
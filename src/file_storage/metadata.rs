@@ -1,47 +1,19 @@
-use std::str::FromStr;
-
-use crate::rpc::metadata::token::TokenMetadata;
+use crate::rpc::{
+    http,
+    metadata::token::{self, MetadataType, TokenMetadata},
+};
 
 use super::{s3::AwsS3Storage, svg_to_png::svg_to_png};
 
 const MAX_CONTENT_LENGTH: u32 = 8 * 1024 * 1024 * 50;
 
-enum UriType<'a> {
-    Ipfs(&'a str),
-    Http(&'a str),
-    Other(&'a str),
-}
-
-fn get_uri_type(uri: &str) -> UriType<'_> {
-    if uri.starts_with("ipfs://") {
-        UriType::Ipfs(uri)
-    } else if uri.starts_with("http://") || uri.starts_with("https://") {
-        UriType::Http(uri)
-    } else {
-        UriType::Other(uri)
-    }
-}
-
 pub async fn store_metadata(
     key: &str,
     metadata: &TokenMetadata,
     storage: &AwsS3Storage,
 ) -> Option<String> {
     if let Some(image) = &metadata.image {
-        let uri_type = get_uri_type(image);
-
-        let image_data = match uri_type {
-            UriType::Http(http_url) => fetch_http_content(http_url).await,
-            UriType::Ipfs(ipfs_url) => {
-                let ipfs_hash = ipfs_url.trim_start_matches("ipfs://");
-                let ipfs_url = format!("https://cloudflare-ipfs.com/ipfs/{ipfs_hash}");
-                fetch_http_content(&ipfs_url).await
-            }
-            UriType::Other(other_url) => {
-                eprintln!("Unknown url {other_url}");
-                None
-            }
-        };
+        let image_data = fetch_content(image).await;
 
         if let Some((ctype, data)) = image_data {
             Some(storage.upload("shovel-metadata", key, &ctype, data).await.unwrap())
@@ -66,20 +38,7 @@ pub async fn store_metadata(
             None
         }
     } else if let Some(animation_url) = &metadata.animation_url {
-        let uri_type = get_uri_type(animation_url);
-
-        let anim_data = match uri_type {
-            UriType::Http(http_url) => fetch_http_content(http_url).await,
-            UriType::Ipfs(ipfs_url) => {
-                let ipfs_hash = ipfs_url.trim_start_matches("ipfs://");
-                let ipfs_url = format!("https://cloudflare-ipfs.com/ipfs/{ipfs_hash}");
-                fetch_http_content(&ipfs_url).await
-            }
-            UriType::Other(other_url) => {
-                eprintln!("Unknown url {other_url}");
-                None
-            }
-        };
+        let anim_data = fetch_content(animation_url).await;
 
         if let Some((ctype, data)) = anim_data {
             Some(storage.upload("shovel-metadata", key, &ctype, data).await.unwrap())
@@ -93,65 +52,54 @@ pub async fn store_metadata(
 }
 
 async fn fetch_http_content(url: &str) -> Option<(String, Vec<u8>)> {
-    let Ok(url) = reqwest::Url::from_str(url) else {
-        eprintln!("Failed to parse url {url}");
-        return None;
-    };
-
-    // let (content_type, content_length) = match get_content_type_and_length(url.clone()).await {
-    //     Some((t, l)) => (t, l),
-    //     // HEAD request failed or Content-Length header missing
-    //     _ => {
-    //         eprintln!("Content length doesn't exist");
-    //         return None;
-    //     }
-    // };
-
-    let Some((content_type, content_length)) = get_content_type_and_length(url.clone()).await else {
-        // HEAD request failed or Content-Length header missing
-        eprintln!("Content length doesn't exist");
-        return None;
-    };
-
-    if content_length > MAX_CONTENT_LENGTH {
-        dbg!(format!("Content length exceeds max length {content_length}"));
-        return None;
-    }
-
-    // If successful request, get response bytes
-    let url_request = reqwest::get(url).await.map(reqwest::Response::bytes);
-
-    match url_request {
-        Ok(response) => response.await.ok().map(|v| (content_type, v.to_vec())),
+    match http::fetcher().fetch(url, MAX_CONTENT_LENGTH as usize).await {
+        Ok(content) => {
+            let content_type =
+                content.content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+            Some((content_type, content.bytes))
+        }
         Err(e) => {
-            dbg!("Couldn't fetch data", format!("{e:?}"));
+            eprintln!("Couldn't fetch {url}: {e}");
             None
         }
     }
 }
 
-/// Extracts content length from URL
-async fn get_content_type_and_length(url: reqwest::Url) -> Option<(String, u32)> {
-    let client = reqwest::Client::new();
-    let header_request = client.head(url).send().await;
-
-    if let Ok(head_response) = header_request {
-        let headers = head_response.headers();
-
-        let content_length = headers
-            .get("content-length")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| str::parse::<u32>(v).ok());
+/// Fetches media behind a URI of any scheme `token::get_metadata_type` knows, HTTP content is
+/// read up to `MAX_CONTENT_LENGTH`. SVG images are cached as PNG, like `image_data`.
+async fn fetch_content(uri: &str) -> Option<(String, Vec<u8>)> {
+    let (content_type, bytes) = match token::get_metadata_type(uri) {
+        MetadataType::Http(http_url) => fetch_http_content(http_url).await?,
+        MetadataType::OnChain(other_url) => {
+            eprintln!("Unknown url {other_url}");
+            return None;
+        }
+        _ => match token::fetch_uri(uri, MAX_CONTENT_LENGTH as usize).await {
+            Ok(content) => (
+                content.content_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+                content.bytes,
+            ),
+            Err(e) => {
+                eprintln!("Couldn't fetch {uri}: {e}");
+                return None;
+            }
+        },
+    };
 
-        let content_type = headers
-            .get("content-type")
-            .and_then(|v| v.to_str().ok())
-            .map(std::string::ToString::to_string);
+    if bytes.len() > MAX_CONTENT_LENGTH as usize {
+        dbg!(format!("Content length exceeds max length {}", bytes.len()));
+        return None;
+    }
 
-        content_type.zip(content_length)
-    } else {
-        eprintln!("Header request failed");
-        // Header request failed, return empty
-        None
+    if content_type.starts_with("image/svg+xml") {
+        return match svg_to_png(&bytes) {
+            Ok(png_data) => Some(("image/png".to_string(), png_data)),
+            Err(e) => {
+                dbg!("SVG conversion failed", format!("{e:?}"));
+                None
+            }
+        };
     }
+
+    Some((content_type, bytes))
 }
@@ -1,21 +1,25 @@
 #![warn(clippy::all, clippy::pedantic, clippy::style, rust_2018_idioms)]
 #![allow(clippy::unreadable_literal, clippy::module_name_repetitions, clippy::too_many_lines)]
+mod cli;
 mod common;
 mod db;
 mod events;
 mod file_storage;
+mod refresh;
 mod rpc;
 use db::postgres::process::ProcessEvent;
 use events::EventBatch;
 use sqlx::{Pool, Postgres};
-use starknet::providers::Provider;
-use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc};
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc, time::Duration};
 use tokio::sync::{mpsc, Mutex};
 
 use color_eyre::eyre;
 use dotenv::dotenv;
 
-use crate::{db::postgres::update_last_synced_block, rpc::StarknetRpc};
+use crate::{
+    db::postgres::{cache::ContractCache, update_last_synced_block},
+    rpc::{provider::RpcProvider, StarknetRpc},
+};
 
 // Default starting block 1630 is around the first ERC721 Transfer event
 const DEFAULT_STARTING_BLOCK: u64 = 1630;
@@ -26,6 +30,10 @@ const MAX_TASK_COUNT: usize = 10;
 // different tasks until they are executed
 const EVENT_CHANNEL_BUFFER_SIZE: usize = MAX_TASK_COUNT * 2;
 const LAST_SYNC_UPDATE_INTERVAL: u64 = 5;
+// Delay before reading a block range again after the RPC gave up on it
+const BATCH_RETRY_DELAY: Duration = Duration::from_secs(10);
+// Number of concurrent metadata workers unless set by `METADATA_WORKERS`
+const DEFAULT_METADATA_WORKERS: usize = 8;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -33,31 +41,84 @@ async fn main() -> eyre::Result<()> {
 
     let pool = db::postgres::connect().await?;
 
+    // Run one-off commands, e.g. `shovel refresh <contract_address> [token_id]`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some((command, args)) = args.split_first() {
+        return cli::run(command, args, &pool).await;
+    }
+
     // Drop everything from tables ('course for testing)
     db::postgres::drop_everything(&pool).await?;
 
-    // Both RPC and pool are needed to be instantiated once and used read-only. That's why we're
-    // leaking and getting static references out of them
-    let rpc: &'static StarknetRpc = Box::leak(Box::new(StarknetRpc::mainnet().unwrap()));
-    let pool: &'static Pool<Postgres> = Box::leak(Box::new(pool));
+    // RPC is instantiated once and shared read-only between tasks, pool is already a shared
+    // handle
+    let rpc = Arc::new(StarknetRpc::mainnet().unwrap());
+
+    // Contract classification is shared between reader tasks, so every class is read once
+    let cache = Arc::new(ContractCache::load(pool.clone()).await?);
 
     let (event_tx, event_rx) = mpsc::channel::<Box<EventBatch>>(EVENT_CHANNEL_BUFFER_SIZE);
 
     // Spawn the writer thread
-    tokio::spawn(async move {
-        let rpc = <&StarknetRpc>::clone(&rpc);
-        let pool = <&Pool<Postgres>>::clone(&pool);
-        write_events(event_rx, rpc, pool).await.unwrap();
+    let writer_pool = pool.clone();
+    let writer = tokio::spawn(async move {
+        write_events(event_rx, &writer_pool).await.unwrap();
         println!("Writer thread closed");
     });
 
-    let start_block = db::postgres::last_synced_block(pool).await.unwrap_or(DEFAULT_STARTING_BLOCK);
+    // Spawn the RPC endpoint health monitor
+    let monitor_rpc = rpc.clone();
+    tokio::spawn(async move {
+        monitor_rpc.inner().inner().monitor().await;
+    });
+
+    // Spawn the metadata workers, filling in metadata of minted tokens and bootstrapped chain
+    // state behind the writer
+    let metadata_workers = std::env::var("METADATA_WORKERS")
+        .map_or(DEFAULT_METADATA_WORKERS, |count| {
+            count.parse().expect("METADATA_WORKERS should be a number")
+        });
+    let (jobs_rpc, jobs_cache, jobs_pool) = (rpc.clone(), cache.clone(), pool.clone());
+    tokio::spawn(async move {
+        refresh::jobs::run_workers(metadata_workers, &jobs_cache, jobs_rpc.inner(), &jobs_pool)
+            .await;
+    });
+
+    // Spawn the collection metadata refresher
+    let (contract_rpc, contract_pool) = (rpc.clone(), pool.clone());
+    tokio::spawn(async move {
+        refresh::contract::refresh_contracts(contract_rpc.inner(), &contract_pool).await;
+    });
+
+    // Spawn the token metadata refresher
+    let (token_rpc, token_pool) = (rpc.clone(), pool.clone());
+    tokio::spawn(async move {
+        refresh::token::refresh_tokens(token_rpc.inner(), &token_pool).await;
+    });
+
+    // Spawn the rarity scorer
+    let rarity_pool = pool.clone();
+    tokio::spawn(async move {
+        refresh::rarity::score_collections(&rarity_pool).await;
+    });
+
+    // An explicit starting block wins over the synced one, anything we missed before it is
+    // bootstrapped from chain state when first seen
+    let start_block = match std::env::var("STARTING_BLOCK") {
+        Ok(block) => block.parse().expect("STARTING_BLOCK should be a block number"),
+        Err(_) => db::postgres::last_synced_block(&pool).await.unwrap_or(DEFAULT_STARTING_BLOCK),
+    };
     let batch_id = Arc::new(Mutex::new(0_u64));
+    // A replayed head never moves, readers stop at the end of the archive instead of waiting
+    let replay = rpc::is_replay();
     let mut reader_threads = Vec::new();
 
     for thread_id in 0..MAX_TASK_COUNT {
         let event_tx = event_tx.clone();
         let batch_id = batch_id.clone();
+        let rpc = rpc.clone();
+        let pool = pool.clone();
+        let cache = cache.clone();
 
         let thread_handle = tokio::spawn(async move {
             loop {
@@ -77,6 +138,10 @@ async fn main() -> eyre::Result<()> {
                             break;
                         }
                     }
+                    if replay {
+                        println!("[tx-{thread_id}] end of the archive before block {from_block}");
+                        return;
+                    }
                 }
 
                 println!(
@@ -86,36 +151,40 @@ async fn main() -> eyre::Result<()> {
                     from_block + BLOCK_RANGE,
                     current_batch_id,
                 );
-                let pool = <&Pool<Postgres>>::clone(&pool);
-                let rpc = <&StarknetRpc>::clone(&rpc);
-                let handler = events::EventHandler::new(rpc.inner(), pool);
+                let handler = events::EventHandler::new(rpc.inner(), &cache);
 
                 let empty_batch = EventBatch::new(current_batch_id, from_block, vec![]);
                 let whitelist = db::postgres::whitelist(&pool).await;
-                match rpc.get_transfer_events(from_block, BLOCK_RANGE).await {
-                    Ok(transfer_events) => {
-                        let batch = match handler
-                            .read_events(
-                                current_batch_id,
-                                from_block,
-                                &transfer_events,
-                                events::EventFilter::Whitelist(&whitelist),
-                            )
-                            .await
-                        {
-                            Ok(batch) => batch,
-                            Err(_) => empty_batch,
-                        };
-                        println!("[tx-{}], got {} events", thread_id, batch.events().len());
-
-                        event_tx.send(Box::new(batch)).await.unwrap();
-                        println!("[tx-{thread_id}] sent to the channel");
-                    }
-                    Err(e) => {
-                        eprintln!("[tx-{thread_id}] failure: {e}");
-                        event_tx.send(Box::new(empty_batch)).await.expect("Send batch");
+                let filter = events::EventFilter::Whitelist(&whitelist);
+
+                // Skipping the range would lose its events, so it's read again once the
+                // providers recover
+                let transfer_events = loop {
+                    match rpc.get_transfer_events(from_block, BLOCK_RANGE, &filter).await {
+                        Ok(transfer_events) => break transfer_events,
+                        // Calls that weren't recorded never will be
+                        Err(e) if replay => {
+                            eprintln!("[tx-{thread_id}] failure, stopping: {e}");
+                            return;
+                        }
+                        Err(e) => {
+                            eprintln!("[tx-{thread_id}] failure, retrying: {e}");
+                            tokio::time::sleep(BATCH_RETRY_DELAY).await;
+                        }
                     }
-                }
+                };
+
+                let batch = match handler
+                    .read_events(current_batch_id, from_block, &transfer_events, filter)
+                    .await
+                {
+                    Ok(batch) => batch,
+                    Err(_) => empty_batch,
+                };
+                println!("[tx-{}], got {} events", thread_id, batch.events().len());
+
+                event_tx.send(Box::new(batch)).await.unwrap();
+                println!("[tx-{thread_id}] sent to the channel");
             }
         });
 
@@ -126,6 +195,10 @@ async fn main() -> eyre::Result<()> {
         thread.await.unwrap();
     }
 
+    // Readers only stop when replaying, the writer finishes once it wrote what they sent
+    drop(event_tx);
+    writer.await?;
+
     Ok(())
 }
 
@@ -137,8 +210,9 @@ async fn main() -> eyre::Result<()> {
 ///
 /// In order for this mechanism to work properly it should work faster than the
 /// event handler tasks. Considering most of the performance bottleneck is
-/// blockchain reads, this function and `ProcessEvent` implementations should do
-/// the least amount of blockchain reads, ideally none.
+/// blockchain reads, this function and `ProcessEvent` implementations don't
+/// read the chain. Token metadata and bootstrapped chain state are queued for
+/// the metadata workers instead of being read here.
 ///
 /// # Errors
 /// This function returns `eyre::ErrReport` if there's problem with starting
@@ -149,7 +223,6 @@ async fn main() -> eyre::Result<()> {
 /// which should be something impossible.
 async fn write_events(
     mut event_rx: mpsc::Receiver<Box<EventBatch>>,
-    rpc: &'static StarknetRpc,
     pool: &Pool<Postgres>,
 ) -> eyre::Result<()> {
     let mut latest_batch_id: u64 = 0;
@@ -173,7 +246,7 @@ async fn write_events(
             let mut transaction = pool.begin().await?;
 
             for event in pending_batch.into_events() {
-                if let Err(e) = event.process(rpc.inner(), &mut transaction).await {
+                if let Err(e) = event.process(&mut transaction).await {
                     eprintln!("[rx] error while writing, {e}");
                 }
             }
@@ -0,0 +1,108 @@
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres};
+use starknet::core::types::{BlockId, BlockTag, FieldElement};
+use std::time::Duration;
+
+use crate::{
+    db::postgres::contract::{fetch_collection_metadata, update_collection_metadata},
+    rpc::provider::RpcProvider,
+};
+
+// Collection metadata older than this gets refreshed
+const CONTRACT_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// How often we look for stale collections
+const CONTRACT_REFRESH_POLL_INTERVAL: Duration = Duration::from_secs(60);
+// Max number of contracts refreshed at every poll
+const CONTRACT_REFRESH_BATCH_SIZE: i64 = 50;
+// Wait after the first failed refresh of a contract, doubled after every following one
+const CONTRACT_RETRY_BASE_DELAY: Duration = Duration::from_secs(60);
+
+/// Periodically re-reads owner, base URI, contract URI and the collection JSON of every
+/// contract that wasn't refreshed in the last `CONTRACT_REFRESH_INTERVAL`
+///
+/// A failed refresh is recorded in the record and retried with backoff, so failing contracts
+/// don't hold back the others. Errors of the refresher itself are logged and it keeps going.
+pub async fn refresh_contracts(rpc: &impl RpcProvider, pool: &Pool<Postgres>) {
+    loop {
+        if let Err(e) = refresh_stale_contracts(rpc, pool).await {
+            eprintln!("[refresh] failed to refresh collections: {e}");
+        }
+
+        tokio::time::sleep(CONTRACT_REFRESH_POLL_INTERVAL).await;
+    }
+}
+
+/// Refreshes the contracts whose collection metadata has been stale the longest, see
+/// `refresh_contracts`
+async fn refresh_stale_contracts(
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    let stale_contracts = sqlx::query!(
+        r#"
+            SELECT id, contract_address
+            FROM contract_metadata
+            WHERE
+                (metadata_refreshed_at IS NULL OR
+                    metadata_refreshed_at < NOW() - make_interval(secs => $1)) AND
+                (metadata_retry_at IS NULL OR metadata_retry_at <= NOW())
+            ORDER BY metadata_refreshed_at NULLS FIRST
+            LIMIT $2
+        "#,
+        CONTRACT_REFRESH_INTERVAL.as_secs_f64(),
+        CONTRACT_REFRESH_BATCH_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for record in stale_contracts {
+        let Ok(contract_address) = FieldElement::from_hex_be(&record.contract_address) else {
+            eprintln!("[refresh] invalid contract address {}", record.contract_address);
+            continue;
+        };
+
+        println!("[refresh] refreshing collection metadata of {}", record.contract_address);
+        let block_id = BlockId::Tag(BlockTag::Latest);
+
+        // Read before the transaction, so it isn't held open while the RPC and hosts respond
+        match fetch_collection_metadata(contract_address, &block_id, rpc).await {
+            Ok(read) => {
+                let mut transaction = pool.begin().await?;
+                update_collection_metadata(record.id, &read, &mut transaction).await?;
+                transaction.commit().await?;
+            }
+            Err(e) => {
+                eprintln!("[refresh] failed for {}: {e}", record.contract_address);
+                record_failure(record.id, &e.to_string(), pool).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Records a failed refresh and pushes the next attempt back, doubling the wait after every
+/// failure up to `CONTRACT_REFRESH_INTERVAL`
+async fn record_failure(id: i32, error: &str, pool: &Pool<Postgres>) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE contract_metadata
+            SET
+                metadata_refresh_failures = metadata_refresh_failures + 1,
+                metadata_refresh_error = $1,
+                metadata_retry_at = NOW() + make_interval(secs => LEAST(
+                    $2::FLOAT8 * power(2, LEAST(metadata_refresh_failures, 16)),
+                    $3::FLOAT8
+                ))
+            WHERE id = $4
+        "#,
+        error,
+        CONTRACT_RETRY_BASE_DELAY.as_secs_f64(),
+        CONTRACT_REFRESH_INTERVAL.as_secs_f64(),
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use color_eyre::eyre;
+use futures_util::future::join_all;
+use sqlx::{Pool, Postgres, Transaction};
+use std::time::Duration;
+
+use crate::{
+    db::postgres::{
+        self,
+        bootstrap_jobs::{self, BootstrapJob, BootstrapTask},
+        cache::ContractCache,
+        metadata_jobs::{self, MetadataJob},
+    },
+    rpc::provider::RpcProvider,
+};
+
+// How often idle workers look for new jobs
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long a worker holds a job before others may take it over, e.g. after a crash
+const JOB_LEASE: Duration = Duration::from_secs(300);
+// Jobs are given up on after this many failed attempts
+const MAX_JOB_ATTEMPTS: i32 = 10;
+// Wait after the first failure, doubled after every following one up to the max
+const JOB_RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+const JOB_RETRY_MAX_DELAY: Duration = Duration::from_secs(3600);
+
+/// Runs `worker_count` workers reading chain state of bootstrapped contracts, tokens and
+/// balances, see `bootstrap_jobs`, and filling in token URIs and metadata of queued tokens, see
+/// `metadata_jobs`
+pub async fn run_workers(
+    worker_count: usize,
+    cache: &ContractCache,
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) {
+    join_all((0..worker_count).map(|worker_id| work(worker_id, cache, rpc, pool))).await;
+}
+
+async fn work(
+    worker_id: usize,
+    cache: &ContractCache,
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) {
+    loop {
+        if !work_once(worker_id, cache, rpc, pool).await {
+            tokio::time::sleep(JOB_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Processes the next due job, bootstrap jobs first since the balances they seed are partial
+/// until then. Returns whether there was one.
+async fn work_once(
+    worker_id: usize,
+    cache: &ContractCache,
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) -> bool {
+    match bootstrap_jobs::claim(JOB_LEASE, pool).await {
+        Ok(Some(job)) => {
+            if let Err(e) = process_bootstrap_job(&job, cache, rpc, pool).await {
+                let retry_in = retry_in(job.attempts);
+                log_failure(worker_id, &job, retry_in, &e);
+
+                if let Err(e) = bootstrap_jobs::fail(&job, &e.to_string(), retry_in, pool).await {
+                    eprintln!("[metadata-{worker_id}] failed to record failure of {job:?}: {e}");
+                }
+            }
+            return true;
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("[metadata-{worker_id}] failed to claim a bootstrap job: {e}"),
+    }
+
+    match metadata_jobs::claim(JOB_LEASE, pool).await {
+        Ok(Some(job)) => {
+            if let Err(e) = process_job(&job, rpc, pool).await {
+                let retry_in = retry_in(job.attempts);
+                log_failure(worker_id, &job, retry_in, &e);
+
+                if let Err(e) = metadata_jobs::fail(&job, &e.to_string(), retry_in, pool).await {
+                    eprintln!("[metadata-{worker_id}] failed to record failure of {job:?}: {e}");
+                }
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("[metadata-{worker_id}] failed to claim a job: {e}");
+            false
+        }
+    }
+}
+
+/// Wait before the next attempt of a job that failed `attempts` times, `None` once it's given
+/// up on
+fn retry_in(attempts: i32) -> Option<Duration> {
+    (attempts < MAX_JOB_ATTEMPTS).then(|| retry_delay(attempts))
+}
+
+fn log_failure(
+    worker_id: usize,
+    job: &impl std::fmt::Debug,
+    retry_in: Option<Duration>,
+    error: &eyre::Report,
+) {
+    match retry_in {
+        Some(retry_in) => {
+            eprintln!("[metadata-{worker_id}] {job:?} failed, retrying in {retry_in:?}: {error}");
+        }
+        None => eprintln!("[metadata-{worker_id}] {job:?} failed, giving up: {error}"),
+    }
+}
+
+/// Reads the chain state a bootstrap job is about and stores it, see `postgres::bootstrap`
+///
+/// Everything is read before the transaction is opened, so it isn't held while we wait on the
+/// network
+async fn process_bootstrap_job(
+    job: &BootstrapJob,
+    cache: &ContractCache,
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    let BootstrapJob { contract_address, task, block_number, .. } = *job;
+
+    match task {
+        BootstrapTask::Collection => {
+            let collection =
+                postgres::contract::read_collection(contract_address, block_number, cache, rpc)
+                    .await?;
+
+            let mut transaction = pool.begin().await?;
+            postgres::contract::store_collection(contract_address, &collection, &mut transaction)
+                .await?;
+            complete_bootstrap_job(job, transaction).await
+        }
+        BootstrapTask::Erc721Tokens => {
+            let tokens =
+                postgres::bootstrap::enumerate_erc721(contract_address, block_number, rpc).await?;
+
+            let mut transaction = pool.begin().await?;
+            postgres::bootstrap::insert_erc721_tokens(
+                contract_address,
+                block_number,
+                &tokens,
+                &mut transaction,
+            )
+            .await?;
+            complete_bootstrap_job(job, transaction).await
+        }
+        BootstrapTask::Erc1155Balance { token_id, account } => {
+            let seed = postgres::bootstrap::read_erc1155_balance(
+                contract_address,
+                token_id,
+                account,
+                block_number,
+                rpc,
+            )
+            .await?;
+
+            let mut transaction = pool.begin().await?;
+            postgres::bootstrap::seed_erc1155_balance(
+                contract_address,
+                token_id,
+                account,
+                block_number,
+                seed,
+                &mut transaction,
+            )
+            .await?;
+            complete_bootstrap_job(job, transaction).await
+        }
+    }
+}
+
+async fn complete_bootstrap_job(
+    job: &BootstrapJob,
+    mut transaction: Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    bootstrap_jobs::complete(job, &mut transaction).await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Reads token URI and metadata of the token and stores them along with the token URI of its
+/// token record
+///
+/// Metadata is fetched before the transaction is opened, so it isn't held while we wait on the
+/// network
+async fn process_job(
+    job: &MetadataJob,
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    let MetadataJob { contract_address, contract_type, token_id, block_number, .. } = *job;
+
+    let fetched = postgres::token::fetch_metadata(
+        contract_address,
+        contract_type,
+        token_id,
+        block_number,
+        rpc,
+    )
+    .await?;
+
+    let mut transaction = pool.begin().await?;
+    postgres::token::store_metadata(
+        contract_address,
+        contract_type,
+        token_id,
+        &fetched,
+        &mut transaction,
+    )
+    .await?;
+    postgres::token::update_token_uri(
+        contract_address,
+        contract_type,
+        token_id,
+        &fetched.source,
+        &mut transaction,
+    )
+    .await?;
+    metadata_jobs::complete(job, &mut transaction).await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Wait before the next attempt of a job that failed `attempts` times
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1)).unwrap_or_default().min(16);
+    JOB_RETRY_BASE_DELAY.saturating_mul(2_u32.pow(exponent)).min(JOB_RETRY_MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_max() {
+        assert_eq!(retry_delay(1), Duration::from_secs(10));
+        assert_eq!(retry_delay(2), Duration::from_secs(20));
+        assert_eq!(retry_delay(4), Duration::from_secs(80));
+        assert_eq!(retry_delay(9), JOB_RETRY_MAX_DELAY);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod contract;
+pub mod jobs;
+pub mod rarity;
+pub mod token;
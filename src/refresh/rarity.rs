@@ -0,0 +1,583 @@
+//! Scores how rare every token of a collection is from the frequencies of its traits
+//!
+//! Storing attributes of a token marks it in `rarity_changes`. Only attributes of marked tokens
+//! are read again, but the whole collection is rescored since a changed frequency moves every
+//! token's score.
+//! Two scores are kept, see `TokenRarity`, along with the frequency of every trait.
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres, Transaction};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+// How often we look for collections whose attributes changed
+const RARITY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Number of traits of a token is scored as a trait of its own under this trait type
+pub const TRAIT_COUNT: &str = "Trait Count";
+
+/// Traits of a token as (trait type, value)
+#[derive(Debug, Clone)]
+pub struct TokenTraits {
+    pub token_metadata_id: i32,
+    pub traits: Vec<(String, String)>,
+}
+
+/// Number of tokens having a trait, `value` is `None` for tokens without the trait type
+#[derive(Debug, PartialEq)]
+pub struct TraitFrequency {
+    pub trait_type: String,
+    pub value: Option<String>,
+    pub token_count: usize,
+    pub frequency: f64,
+}
+
+/// Scores of a token, ranks start at 1 for the rarest and tokens scoring the same share a rank
+#[derive(Debug, PartialEq)]
+pub struct TokenRarity {
+    pub token_metadata_id: i32,
+    /// Product of the frequencies of the token's traits, lower is rarer
+    pub statistical_score: f64,
+    pub statistical_rank: usize,
+    /// Sum of the inverse frequencies of the token's traits, higher is rarer
+    pub rarity_score: f64,
+    pub rarity_rank: usize,
+}
+
+/// Scores every token of a collection
+///
+/// Lacking a trait type other tokens have counts as a trait, with a `None` value, and so does
+/// the number of distinct traits of the token, under `TRAIT_COUNT`.
+#[allow(clippy::cast_precision_loss)]
+pub fn score(tokens: &[TokenTraits]) -> (Vec<TraitFrequency>, Vec<TokenRarity>) {
+    let mut token_traits: Vec<BTreeSet<(String, Option<String>)>> = tokens
+        .iter()
+        .map(|token| {
+            let mut traits: BTreeSet<_> = token
+                .traits
+                .iter()
+                .map(|(trait_type, value)| (trait_type.clone(), Some(value.clone())))
+                .collect();
+            traits.insert((TRAIT_COUNT.to_string(), Some(traits.len().to_string())));
+            traits
+        })
+        .collect();
+
+    let trait_types: BTreeSet<String> = token_traits
+        .iter()
+        .flat_map(|traits| traits.iter().map(|(trait_type, _)| trait_type.clone()))
+        .collect();
+    for traits in &mut token_traits {
+        let missing: Vec<_> = trait_types
+            .iter()
+            .filter(|trait_type| !traits.iter().any(|(other, _)| other == *trait_type))
+            .cloned()
+            .collect();
+        traits.extend(missing.into_iter().map(|trait_type| (trait_type, None)));
+    }
+
+    let mut counts: BTreeMap<&(String, Option<String>), usize> = BTreeMap::new();
+    for trait_ in token_traits.iter().flatten() {
+        *counts.entry(trait_).or_default() += 1;
+    }
+
+    let total = tokens.len() as f64;
+    let mut rarities: Vec<TokenRarity> = tokens
+        .iter()
+        .zip(&token_traits)
+        .map(|(token, traits)| {
+            let frequencies = traits.iter().map(|trait_| counts[trait_] as f64 / total);
+            TokenRarity {
+                token_metadata_id: token.token_metadata_id,
+                statistical_score: frequencies.clone().product(),
+                statistical_rank: 0,
+                rarity_score: frequencies.map(|frequency| 1.0 / frequency).sum(),
+                rarity_rank: 0,
+            }
+        })
+        .collect();
+
+    let statistical_ranks =
+        ranks(&rarities, |a, b| a.statistical_score.total_cmp(&b.statistical_score));
+    let rarity_ranks = ranks(&rarities, |a, b| b.rarity_score.total_cmp(&a.rarity_score));
+    for ((rarity, statistical_rank), rarity_rank) in
+        rarities.iter_mut().zip(statistical_ranks).zip(rarity_ranks)
+    {
+        rarity.statistical_rank = statistical_rank;
+        rarity.rarity_rank = rarity_rank;
+    }
+
+    let frequencies = counts
+        .into_iter()
+        .map(|((trait_type, value), token_count)| TraitFrequency {
+            trait_type: trait_type.clone(),
+            value: value.clone(),
+            token_count,
+            frequency: token_count as f64 / total,
+        })
+        .collect();
+
+    (frequencies, rarities)
+}
+
+/// Rank of every token once sorted rarest first, tokens comparing equal share the rank of the
+/// first of them
+fn ranks(
+    rarities: &[TokenRarity],
+    rarest_first: impl Fn(&TokenRarity, &TokenRarity) -> Ordering,
+) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..rarities.len()).collect();
+    order.sort_by(|&a, &b| rarest_first(&rarities[a], &rarities[b]));
+
+    let mut ranks = vec![0; rarities.len()];
+    for (position, &index) in order.iter().enumerate() {
+        ranks[index] = match position.checked_sub(1).map(|previous| order[previous]) {
+            Some(previous) if rarest_first(&rarities[previous], &rarities[index]).is_eq() => {
+                ranks[previous]
+            }
+            _ => position + 1,
+        };
+    }
+    ranks
+}
+
+/// Periodically rescores every collection with tokens marked in `rarity_changes`
+///
+/// Marks are read and the collection is scored without a transaction, scores are then written
+/// and the marks that were read deleted at once. Tokens marked meanwhile keep their mark and
+/// are scored in the next round. There must be a single scorer.
+pub async fn score_collections(pool: &Pool<Postgres>) {
+    loop {
+        let changed = sqlx::query!(
+            r#"
+                SELECT network, contract_address
+                FROM rarity_changes
+                ORDER BY marked_at
+                LIMIT 1
+            "#
+        )
+        .fetch_optional(pool)
+        .await;
+
+        let changed = match changed {
+            Ok(changed) => changed,
+            Err(e) => {
+                eprintln!("[rarity] failed to look for changed collections: {e}");
+                tokio::time::sleep(RARITY_POLL_INTERVAL).await;
+                continue;
+            }
+        };
+        let Some(collection) = changed else {
+            tokio::time::sleep(RARITY_POLL_INTERVAL).await;
+            continue;
+        };
+
+        println!("[rarity] scoring {}", collection.contract_address);
+        if let Err(e) =
+            score_collection(&collection.network, &collection.contract_address, pool).await
+        {
+            eprintln!("[rarity] failed for {}: {e}", collection.contract_address);
+
+            // Other changed collections go first
+            let postponed = sqlx::query!(
+                r#"
+                    UPDATE rarity_changes
+                    SET marked_at = NOW()
+                    WHERE network = $1 AND contract_address = $2
+                "#,
+                collection.network,
+                collection.contract_address
+            )
+            .execute(pool)
+            .await;
+            if let Err(e) = postponed {
+                eprintln!("[rarity] failed to postpone {}: {e}", collection.contract_address);
+            }
+            tokio::time::sleep(RARITY_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Rescores a collection, reading attributes of its marked tokens only and the traits other
+/// tokens were last scored with from `rarity_token_traits`
+///
+/// Any changed frequency moves the score of every token, so all of them are scored again, but
+/// only scores that changed are written.
+async fn score_collection(
+    network: &str,
+    contract_address: &str,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    let marks = sqlx::query!(
+        r#"
+            SELECT id, token_metadata_id
+            FROM rarity_changes
+            WHERE network = $1 AND contract_address = $2
+        "#,
+        network,
+        contract_address
+    )
+    .fetch_all(pool)
+    .await?;
+    let mark_ids: Vec<i64> = marks.iter().map(|mark| mark.id).collect();
+    let mut changed_ids: Vec<i32> = marks.iter().map(|mark| mark.token_metadata_id).collect();
+    changed_ids.sort_unstable();
+    changed_ids.dedup();
+
+    let changed = load_traits(network, contract_address, &changed_ids, pool).await?;
+    let mut tokens = load_scored_traits(network, contract_address, &changed_ids, pool).await?;
+    tokens.extend(changed.iter().cloned());
+    tokens.sort_by_key(|token| token.token_metadata_id);
+    let (frequencies, rarities) = score(&tokens);
+
+    let mut transaction = pool.begin().await?;
+    replace_scored_traits(network, contract_address, &changed_ids, &changed, &mut transaction)
+        .await?;
+    replace_frequencies(network, contract_address, frequencies, &mut transaction).await?;
+    update_rarities(network, contract_address, rarities, &mut transaction).await?;
+
+    sqlx::query!("DELETE FROM rarity_changes WHERE id = ANY($1)", &mark_ids)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Replaces the traits the changed tokens are scored with, tokens that are no longer scored
+/// have none
+async fn replace_scored_traits(
+    network: &str,
+    contract_address: &str,
+    changed_ids: &[i32],
+    changed: &[TokenTraits],
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!("DELETE FROM rarity_token_traits WHERE token_metadata_id = ANY($1)", changed_ids)
+        .execute(&mut *transaction)
+        .await?;
+
+    let mut ids = Vec::new();
+    let mut trait_types = Vec::new();
+    let mut values = Vec::new();
+    for token in changed {
+        if token.traits.is_empty() {
+            ids.push(token.token_metadata_id);
+            trait_types.push(None);
+            values.push(None);
+        }
+        for (trait_type, value) in &token.traits {
+            ids.push(token.token_metadata_id);
+            trait_types.push(Some(trait_type.clone()));
+            values.push(Some(value.clone()));
+        }
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO rarity_token_traits(
+                token_metadata_id,
+                network,
+                contract_address,
+                trait_type,
+                value)
+            SELECT scored.token_metadata_id, $1, $2, scored.trait_type, scored.value
+            FROM UNNEST($3::INT[], $4::TEXT[], $5::TEXT[])
+                AS scored(token_metadata_id, trait_type, value)
+        "#,
+        network,
+        contract_address,
+        &ids,
+        &trait_types as &[Option<String>],
+        &values as &[Option<String>]
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Replaces trait frequencies of a collection
+async fn replace_frequencies(
+    network: &str,
+    contract_address: &str,
+    frequencies: Vec<TraitFrequency>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        "DELETE FROM trait_frequencies WHERE network = $1 AND contract_address = $2",
+        network,
+        contract_address
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let mut trait_types = Vec::with_capacity(frequencies.len());
+    let mut values = Vec::with_capacity(frequencies.len());
+    let mut token_counts = Vec::with_capacity(frequencies.len());
+    let mut ratios = Vec::with_capacity(frequencies.len());
+    for frequency in frequencies {
+        trait_types.push(frequency.trait_type);
+        values.push(frequency.value);
+        token_counts.push(i32::try_from(frequency.token_count)?);
+        ratios.push(frequency.frequency);
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO trait_frequencies(
+                network,
+                contract_address,
+                trait_type,
+                value,
+                token_count,
+                frequency)
+            SELECT $1, $2, frequency.trait_type, frequency.value, frequency.token_count,
+                frequency.frequency
+            FROM UNNEST($3::TEXT[], $4::TEXT[], $5::INT[], $6::FLOAT8[])
+                AS frequency(trait_type, value, token_count, frequency)
+        "#,
+        network,
+        contract_address,
+        &trait_types,
+        &values as &[Option<String>],
+        &token_counts,
+        &ratios
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Writes scores of a collection that changed and deletes those of tokens no longer scored
+async fn update_rarities(
+    network: &str,
+    contract_address: &str,
+    rarities: Vec<TokenRarity>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> eyre::Result<()> {
+    // Collections may have many tokens, scores are written at once
+    let mut ids = Vec::with_capacity(rarities.len());
+    let mut statistical_scores = Vec::with_capacity(rarities.len());
+    let mut statistical_ranks = Vec::with_capacity(rarities.len());
+    let mut rarity_scores = Vec::with_capacity(rarities.len());
+    let mut rarity_ranks = Vec::with_capacity(rarities.len());
+    for rarity in rarities {
+        ids.push(rarity.token_metadata_id);
+        statistical_scores.push(rarity.statistical_score);
+        statistical_ranks.push(i32::try_from(rarity.statistical_rank)?);
+        rarity_scores.push(rarity.rarity_score);
+        rarity_ranks.push(i32::try_from(rarity.rarity_rank)?);
+    }
+
+    sqlx::query!(
+        r#"
+            DELETE FROM token_rarity
+            WHERE
+                network = $1 AND
+                contract_address = $2 AND
+                token_metadata_id <> ALL($3)
+        "#,
+        network,
+        contract_address,
+        &ids
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO token_rarity(
+                token_metadata_id,
+                network,
+                contract_address,
+                statistical_score,
+                statistical_rank,
+                rarity_score,
+                rarity_rank)
+            SELECT score.token_metadata_id, $1, $2, score.statistical_score,
+                score.statistical_rank, score.rarity_score, score.rarity_rank
+            FROM UNNEST($3::INT[], $4::FLOAT8[], $5::INT[], $6::FLOAT8[], $7::INT[])
+                AS score(
+                    token_metadata_id,
+                    statistical_score,
+                    statistical_rank,
+                    rarity_score,
+                    rarity_rank)
+            ON CONFLICT (token_metadata_id) DO UPDATE
+            SET
+                statistical_score = EXCLUDED.statistical_score,
+                statistical_rank = EXCLUDED.statistical_rank,
+                rarity_score = EXCLUDED.rarity_score,
+                rarity_rank = EXCLUDED.rarity_rank,
+                scored_at = NOW()
+            WHERE
+                (token_rarity.statistical_score, token_rarity.statistical_rank,
+                    token_rarity.rarity_score, token_rarity.rarity_rank)
+                IS DISTINCT FROM
+                (EXCLUDED.statistical_score, EXCLUDED.statistical_rank,
+                    EXCLUDED.rarity_score, EXCLUDED.rarity_rank)
+        "#,
+        network,
+        contract_address,
+        &ids,
+        &statistical_scores,
+        &statistical_ranks,
+        &rarity_scores,
+        &rarity_ranks
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Traits of the given tokens of a collection, values of arrays and objects are compared by
+/// their JSON
+async fn load_traits(
+    network: &str,
+    contract_address: &str,
+    token_metadata_ids: &[i32],
+    pool: &Pool<Postgres>,
+) -> eyre::Result<Vec<TokenTraits>> {
+    let records = sqlx::query!(
+        r#"
+            SELECT
+                token_metadata.id,
+                attribute.trait_type AS "trait_type?",
+                attribute.value_json #>> '{}' AS "value?"
+            FROM token_metadata
+            LEFT JOIN token_metadata_attributes attribute ON
+                attribute.token_metadata_id = token_metadata.id AND
+                attribute.trait_type IS NOT NULL AND
+                attribute.value_json #>> '{}' IS NOT NULL
+            WHERE
+                token_metadata.network = $1 AND
+                token_metadata.contract_address = $2 AND
+                token_metadata.id = ANY($3)
+            ORDER BY token_metadata.id
+        "#,
+        network,
+        contract_address,
+        token_metadata_ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(group_traits(records.into_iter().map(|record| (record.id, record.trait_type, record.value))))
+}
+
+/// Traits every other token of a collection was last scored with, see `load_traits`
+async fn load_scored_traits(
+    network: &str,
+    contract_address: &str,
+    changed_ids: &[i32],
+    pool: &Pool<Postgres>,
+) -> eyre::Result<Vec<TokenTraits>> {
+    let records = sqlx::query!(
+        r#"
+            SELECT scored.token_metadata_id, scored.trait_type, scored.value
+            FROM rarity_token_traits scored
+            WHERE
+                scored.network = $1 AND
+                scored.contract_address = $2 AND
+                scored.token_metadata_id <> ALL($3)
+            ORDER BY scored.token_metadata_id
+        "#,
+        network,
+        contract_address,
+        changed_ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(group_traits(
+        records
+            .into_iter()
+            .map(|record| (record.token_metadata_id, record.trait_type, record.value)),
+    ))
+}
+
+/// Groups (token, trait type, value) rows ordered by token, a token without traits has a single
+/// row without trait type and value
+fn group_traits(
+    records: impl Iterator<Item = (i32, Option<String>, Option<String>)>,
+) -> Vec<TokenTraits> {
+    let mut tokens: Vec<TokenTraits> = Vec::new();
+    for (token_metadata_id, trait_type, value) in records {
+        if tokens.last().map(|token| token.token_metadata_id) != Some(token_metadata_id) {
+            tokens.push(TokenTraits { token_metadata_id, traits: Vec::new() });
+        }
+        if let (Some(trait_type), Some(value), Some(token)) = (trait_type, value, tokens.last_mut())
+        {
+            token.traits.push((trait_type, value));
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(token_metadata_id: i32, traits: &[(&str, &str)]) -> TokenTraits {
+        TokenTraits {
+            token_metadata_id,
+            traits: traits
+                .iter()
+                .map(|(trait_type, value)| ((*trait_type).to_string(), (*value).to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_scores_rarity() {
+        let tokens = [
+            token(1, &[("Background", "Gold"), ("Eyes", "Laser")]),
+            token(2, &[("Background", "Blue"), ("Eyes", "Laser")]),
+            token(3, &[("Background", "Blue")]),
+            token(4, &[("Background", "Blue"), ("Background", "Blue")]),
+        ];
+        let (frequencies, rarities) = score(&tokens);
+
+        let counts: Vec<_> = frequencies
+            .iter()
+            .map(|f| (f.trait_type.as_str(), f.value.as_deref(), f.token_count))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("Background", Some("Blue"), 3),
+                ("Background", Some("Gold"), 1),
+                ("Eyes", None, 2),
+                ("Eyes", Some("Laser"), 2),
+                ("Trait Count", Some("1"), 2),
+                ("Trait Count", Some("2"), 2),
+            ]
+        );
+        assert!((frequencies[0].frequency - 0.75).abs() < f64::EPSILON);
+
+        // 4/1 + 4/2 + 4/2 and 1/4 * 2/4 * 2/4
+        assert!((rarities[0].rarity_score - 8.0).abs() < 1e-9);
+        assert!((rarities[0].statistical_score - 0.0625).abs() < 1e-9);
+        assert!((rarities[1].rarity_score - (4.0 / 3.0 + 4.0)).abs() < 1e-9);
+
+        let ranks: Vec<_> = rarities.iter().map(|r| (r.rarity_rank, r.statistical_rank)).collect();
+        assert_eq!(ranks, [(1, 1), (2, 2), (2, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn test_scores_empty_collection() {
+        let (frequencies, rarities) = score(&[]);
+        assert!(frequencies.is_empty());
+        assert!(rarities.is_empty());
+
+        // Tokens without attributes only differ by their trait count
+        let (frequencies, rarities) = score(&[token(1, &[]), token(2, &[("Hat", "Cap")])]);
+        assert_eq!(frequencies.len(), 4);
+        assert_eq!(rarities[0].rarity_rank, 1);
+        assert_eq!(rarities[1].rarity_rank, 1);
+    }
+}
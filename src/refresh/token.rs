@@ -0,0 +1,386 @@
+use color_eyre::eyre;
+use sqlx::{Pool, Postgres};
+use starknet::core::types::{BlockId, FieldElement};
+use std::{str::FromStr, time::Duration};
+
+use crate::{
+    common::types::{CairoUint256, ContractType},
+    db::postgres::{self, token::MetadataSource},
+    refresh::jobs::retry_delay,
+    rpc::{
+        metadata::token::{self, MetadataDocument},
+        provider::RpcProvider,
+    },
+};
+
+// How often we look for refresh requests and tokens due for a scheduled refresh
+const TOKEN_REFRESH_POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Max number of tokens refreshed at every poll for scheduled refreshes
+const TOKEN_REFRESH_BATCH_SIZE: i64 = 100;
+// Max number of on-demand requests picked at every poll
+const REFRESH_REQUEST_BATCH_SIZE: i64 = 10;
+// How long the refresher holds a request before it's served again, e.g. after a crash
+const REFRESH_REQUEST_LEASE: Duration = Duration::from_secs(3600);
+// Requests are given up on after this many failed attempts
+const MAX_REFRESH_REQUEST_ATTEMPTS: i32 = 5;
+
+/// A `token_metadata` record to be refreshed
+#[derive(Debug, Clone, Copy)]
+pub struct TokenToRefresh {
+    pub token_metadata_id: i32,
+    pub contract_address: FieldElement,
+    pub contract_type: ContractType,
+    pub token_id: CairoUint256,
+}
+
+/// Outcome of a token refresh
+#[derive(Debug)]
+pub enum Refreshed {
+    Changed,
+    Unchanged,
+    /// Token URI or metadata couldn't be read, the stored metadata was kept
+    Failed(String),
+}
+
+/// An on-demand refresh request held by the refresher, with the tokens it covers
+#[derive(Debug)]
+struct RefreshRequest {
+    id: i32,
+    /// Attempts so far, including the current one
+    attempts: i32,
+    tokens: Vec<TokenToRefresh>,
+}
+
+/// Queues an on-demand refresh for a token, or for every token of the contract if `token_id`
+/// is `None`
+pub async fn request_refresh(
+    pool: &Pool<Postgres>,
+    contract_address: FieldElement,
+    token_id: Option<CairoUint256>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            INSERT INTO metadata_refresh_requests(contract_address, token_id_low, token_id_high)
+            VALUES ($1, $2, $3)
+        "#,
+        format!("{contract_address:#x}"),
+        token_id.map(|token_id| token_id.low.to_string()),
+        token_id.map(|token_id| token_id.high.to_string()),
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Serves on-demand refresh requests and refreshes tokens of collections with a refresh policy
+/// (`contract_metadata.token_refresh_interval_secs`) once their metadata gets older than the
+/// interval
+///
+/// A request is removed once every token it covers refreshed, it's retried with backoff
+/// otherwise. Errors of the refresher itself are logged and it keeps going.
+pub async fn refresh_tokens(rpc: &impl RpcProvider, pool: &Pool<Postgres>) {
+    loop {
+        if let Err(e) = serve_requests(rpc, pool).await {
+            eprintln!("[refresh] failed to serve refresh requests: {e}");
+        }
+
+        match scheduled_tokens(pool).await {
+            Ok(tokens) => {
+                refresh_all(&tokens, rpc, pool).await;
+            }
+            Err(e) => eprintln!("[refresh] failed to read tokens due for a refresh: {e}"),
+        }
+
+        tokio::time::sleep(TOKEN_REFRESH_POLL_INTERVAL).await;
+    }
+}
+
+/// Refreshes the tokens of every due on-demand request and completes or fails the request
+async fn serve_requests(rpc: &impl RpcProvider, pool: &Pool<Postgres>) -> eyre::Result<()> {
+    for request in claim_requests(pool).await? {
+        match refresh_all(&request.tokens, rpc, pool).await {
+            None => complete_request(request.id, pool).await?,
+            Some(error) => {
+                let retry_in = (request.attempts < MAX_REFRESH_REQUEST_ATTEMPTS)
+                    .then(|| retry_delay(request.attempts));
+                fail_request(request.id, &error, retry_in, pool).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Refreshes every token in its own transaction
+///
+/// Returns the last failure, `None` if every token refreshed
+async fn refresh_all(
+    tokens: &[TokenToRefresh],
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) -> Option<String> {
+    let mut failure = None;
+
+    for token_to_refresh in tokens {
+        match refresh_token(token_to_refresh, rpc, pool).await {
+            Ok(Refreshed::Changed) => println!(
+                "[refresh] metadata of token #{} of {:#x} changed",
+                token_to_refresh.token_id.low, token_to_refresh.contract_address
+            ),
+            Ok(Refreshed::Unchanged) => {}
+            Ok(Refreshed::Failed(e)) => {
+                eprintln!("[refresh] failed for {token_to_refresh:?}: {e}");
+                failure = Some(e);
+            }
+            Err(e) => {
+                eprintln!("[refresh] failed for {token_to_refresh:?}: {e}");
+                failure = Some(e.to_string());
+            }
+        }
+    }
+
+    failure
+}
+
+/// Re-fetches token URI and metadata of a token and updates `token_metadata` and its attributes
+/// in place if anything changed. The outcome is recorded in the record either way.
+///
+/// Token URI and metadata are read before the transaction is opened, so it isn't held open while
+/// the RPC and hosts respond.
+pub async fn refresh_token(
+    token_to_refresh: &TokenToRefresh,
+    rpc: &impl RpcProvider,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<Refreshed> {
+    let TokenToRefresh { token_metadata_id, contract_address, contract_type, token_id } =
+        *token_to_refresh;
+
+    let fetched = fetch_token(contract_address, contract_type, token_id, rpc).await;
+    let mut transaction = pool.begin().await?;
+
+    let (source, document) = match fetched {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            // Keep what we have, a failing RPC or host shouldn't wipe metadata
+            let error = e.to_string();
+            postgres::token::record_refresh(
+                token_metadata_id,
+                Some(error.clone()),
+                &mut transaction,
+            )
+            .await?;
+            transaction.commit().await?;
+            return Ok(Refreshed::Failed(error));
+        }
+    };
+
+    let (stored_uri, stored_document) =
+        postgres::token::get_token_metadata(token_metadata_id, &mut transaction).await?;
+    let changed =
+        stored_uri.as_deref() != Some(source.token_uri.as_str()) || stored_document != document;
+
+    if changed {
+        postgres::token::update_token_metadata(
+            token_metadata_id,
+            &source,
+            &document,
+            &mut transaction,
+        )
+        .await?;
+        postgres::token::update_token_uri(
+            contract_address,
+            contract_type,
+            token_id,
+            &source,
+            &mut transaction,
+        )
+        .await?;
+    }
+
+    postgres::token::record_refresh(token_metadata_id, None, &mut transaction).await?;
+    transaction.commit().await?;
+
+    Ok(if changed { Refreshed::Changed } else { Refreshed::Unchanged })
+}
+
+/// Reads the token URI of a token at the latest block and fetches the metadata behind it
+///
+/// Returns where the metadata came from and the metadata document
+async fn fetch_token(
+    contract_address: FieldElement,
+    contract_type: ContractType,
+    token_id: CairoUint256,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<(MetadataSource, MetadataDocument)> {
+    // Read at a concrete block rather than the latest tag, so we know which one it was
+    let block_number = rpc.block_number().await?;
+    let block_id = BlockId::Number(block_number);
+    let token_uri = match contract_type {
+        ContractType::Erc721 => {
+            token::get_erc721_uri(contract_address, token_id, &block_id, rpc).await?
+        }
+        ContractType::Erc1155 => {
+            token::get_erc1155_uri(contract_address, token_id, &block_id, rpc).await?
+        }
+    };
+    let (expanded_uri, fetched) = token::get_expanded_token_metadata(&token_uri, token_id).await;
+    let (document, gateway) = fetched?;
+
+    let source = MetadataSource {
+        token_uri_template: (expanded_uri != token_uri).then_some(token_uri),
+        token_uri: expanded_uri,
+        token_uri_block: block_number,
+        gateway,
+    };
+
+    Ok((source, document))
+}
+
+/// Takes the on-demand requests that have been due the longest and holds them for
+/// `REFRESH_REQUEST_LEASE`, expanded into the tokens they cover
+async fn claim_requests(pool: &Pool<Postgres>) -> eyre::Result<Vec<RefreshRequest>> {
+    let records = sqlx::query!(
+        r#"
+            UPDATE metadata_refresh_requests
+            SET
+                attempts = attempts + 1,
+                next_attempt_at = NOW() + make_interval(secs => $1)
+            WHERE id IN (
+                SELECT id
+                FROM metadata_refresh_requests
+                WHERE next_attempt_at <= NOW()
+                ORDER BY next_attempt_at, id
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, attempts, contract_address, token_id_low, token_id_high
+        "#,
+        REFRESH_REQUEST_LEASE.as_secs_f64(),
+        REFRESH_REQUEST_BATCH_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut requests = Vec::new();
+
+    for request in records {
+        let records = sqlx::query!(
+            r#"
+                SELECT
+                    id,
+                    contract_address,
+                    contract_type::TEXT AS "contract_type!",
+                    token_id_low,
+                    token_id_high
+                FROM token_metadata
+                WHERE
+                    contract_address = $1 AND
+                    ($2::VARCHAR IS NULL OR token_id_low = $2) AND
+                    ($3::VARCHAR IS NULL OR token_id_high = $3)
+            "#,
+            request.contract_address,
+            request.token_id_low,
+            request.token_id_high
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut tokens = Vec::new();
+        for record in records {
+            tokens.push(TokenToRefresh {
+                token_metadata_id: record.id,
+                contract_address: FieldElement::from_hex_be(&record.contract_address)?,
+                contract_type: ContractType::from_str(&record.contract_type)?,
+                token_id: CairoUint256::new(
+                    FieldElement::from_dec_str(&record.token_id_low)?,
+                    FieldElement::from_dec_str(&record.token_id_high)?,
+                ),
+            });
+        }
+
+        requests.push(RefreshRequest { id: request.id, attempts: request.attempts, tokens });
+    }
+
+    Ok(requests)
+}
+
+/// Removes a request whose tokens all refreshed
+async fn complete_request(request_id: i32, pool: &Pool<Postgres>) -> eyre::Result<()> {
+    sqlx::query!("DELETE FROM metadata_refresh_requests WHERE id = $1", request_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Records why a request failed and when to serve it again, `None` gives up on it. Given up
+/// requests are kept for inspection.
+async fn fail_request(
+    request_id: i32,
+    error: &str,
+    retry_in: Option<Duration>,
+    pool: &Pool<Postgres>,
+) -> eyre::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE metadata_refresh_requests
+            SET
+                last_error = $1,
+                next_attempt_at = NOW() + make_interval(secs => $2)
+            WHERE id = $3
+        "#,
+        error,
+        retry_in.map(|retry_in| retry_in.as_secs_f64()),
+        request_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns tokens whose collection has a refresh policy and that are due for a refresh
+async fn scheduled_tokens(pool: &Pool<Postgres>) -> eyre::Result<Vec<TokenToRefresh>> {
+    let records = sqlx::query!(
+        r#"
+            SELECT
+                token_metadata.id,
+                token_metadata.contract_address,
+                token_metadata.contract_type::TEXT AS "contract_type!",
+                token_metadata.token_id_low,
+                token_metadata.token_id_high
+            FROM token_metadata
+            INNER JOIN contract_metadata ON
+                contract_metadata.contract_address = token_metadata.contract_address AND
+                contract_metadata.contract_type = token_metadata.contract_type
+            WHERE
+                contract_metadata.token_refresh_interval_secs IS NOT NULL AND (
+                    token_metadata.last_refreshed_at IS NULL OR
+                    token_metadata.last_refreshed_at < NOW() - make_interval(
+                        secs => contract_metadata.token_refresh_interval_secs::FLOAT8
+                    )
+                )
+            ORDER BY token_metadata.last_refreshed_at NULLS FIRST
+            LIMIT $1
+        "#,
+        TOKEN_REFRESH_BATCH_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    records
+        .into_iter()
+        .map(|record| {
+            Ok(TokenToRefresh {
+                token_metadata_id: record.id,
+                contract_address: FieldElement::from_hex_be(&record.contract_address)?,
+                contract_type: ContractType::from_str(&record.contract_type)?,
+                token_id: CairoUint256::new(
+                    FieldElement::from_dec_str(&record.token_id_low)?,
+                    FieldElement::from_dec_str(&record.token_id_high)?,
+                ),
+            })
+        })
+        .collect()
+}
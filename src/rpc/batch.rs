@@ -0,0 +1,256 @@
+//! Coalesces concurrent JSON-RPC requests into batch requests, so bursts of small calls share
+//! a round trip
+//!
+//! Requests wait up to `BatchConfig::linger` for others to join them. Endpoints that reject
+//! batches are remembered and get single requests from then on.
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+use super::transport::RawTransport;
+use crate::common::errors::TransportError;
+
+// Small requests issued in bursts while reading metadata and classifying contracts, anything
+// else is sent right away
+const BATCHED_METHODS: [&str; 3] =
+    ["starknet_call", "starknet_getClassHashAt", "starknet_getClassAt"];
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// Most requests sent in one batch, 1 disables batching
+    pub max_batch_size: usize,
+    /// How long the first request of a batch waits for others to join it
+    pub linger: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self { max_batch_size: 20, linger: Duration::from_millis(5) }
+    }
+}
+
+struct Pending {
+    request: Value,
+    reply: oneshot::Sender<Result<Value, TransportError>>,
+}
+
+struct Shared<T> {
+    transport: T,
+    config: BatchConfig,
+    pending: Mutex<Vec<Pending>>,
+    batches_supported: AtomicBool,
+}
+
+pub struct BatchTransport<T>(Arc<Shared<T>>);
+
+impl<T: RawTransport + 'static> BatchTransport<T> {
+    pub fn new(transport: T, config: BatchConfig) -> Self {
+        Self(Arc::new(Shared {
+            transport,
+            config,
+            pending: Mutex::new(Vec::new()),
+            batches_supported: AtomicBool::new(true),
+        }))
+    }
+
+    fn should_batch(&self, request: &Value) -> bool {
+        let method = request.get("method").and_then(Value::as_str).unwrap_or_default();
+
+        self.0.config.max_batch_size > 1
+            && self.0.batches_supported.load(Ordering::Relaxed)
+            && BATCHED_METHODS.contains(&method)
+    }
+}
+
+#[async_trait]
+impl<T: RawTransport + 'static> RawTransport for BatchTransport<T> {
+    async fn send(&self, request: Value) -> Result<Value, TransportError> {
+        if !self.should_batch(&request) {
+            return self.0.transport.send(request).await;
+        }
+
+        let (reply, response) = oneshot::channel();
+        let (first, full_batch) = {
+            let mut pending = self.0.pending.lock().unwrap();
+            pending.push(Pending { request, reply });
+            let full = pending.len() >= self.0.config.max_batch_size;
+            (pending.len() == 1, full.then(|| std::mem::take(&mut *pending)))
+        };
+
+        // Batches are sent from their own task, so they go out even if the request that
+        // started them is dropped, e.g. after a timeout
+        if let Some(batch) = full_batch {
+            tokio::spawn(flush(self.0.clone(), batch));
+        } else if first {
+            let shared = self.0.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(shared.config.linger).await;
+                let batch = std::mem::take(&mut *shared.pending.lock().unwrap());
+                flush(shared, batch).await;
+            });
+        }
+
+        response
+            .await
+            .map_err(|_| TransportError::BatchUnavailable("batch was dropped".to_string()))?
+    }
+}
+
+/// Sends a batch and hands every response to its request, ids are rewritten so they're unique
+/// within the batch
+async fn flush<T: RawTransport>(shared: Arc<Shared<T>>, batch: Vec<Pending>) {
+    if batch.len() <= 1 || !shared.batches_supported.load(Ordering::Relaxed) {
+        send_each(&shared.transport, batch).await;
+        return;
+    }
+
+    let requests = batch
+        .iter()
+        .enumerate()
+        .map(|(index, pending)| {
+            let mut request = pending.request.clone();
+            request["id"] = Value::from(index);
+            request
+        })
+        .collect();
+
+    match shared.transport.send(Value::Array(requests)).await {
+        Ok(Value::Array(responses)) => {
+            let mut responses: HashMap<usize, Value> = responses
+                .into_iter()
+                .filter_map(|response| {
+                    let index = usize::try_from(response.get("id")?.as_u64()?).ok()?;
+                    Some((index, response))
+                })
+                .collect();
+
+            // Requests the endpoint didn't answer in the batch are sent again on their own
+            let mut unanswered = Vec::new();
+            for (index, pending) in batch.into_iter().enumerate() {
+                match responses.remove(&index) {
+                    Some(mut response) => {
+                        response["id"] = pending.request["id"].clone();
+                        let _ = pending.reply.send(Ok(response));
+                    }
+                    None => unanswered.push(pending),
+                }
+            }
+            send_each(&shared.transport, unanswered).await;
+        }
+        // A single error object instead of an array of responses, or a client error status,
+        // means the endpoint doesn't take batches
+        Ok(_) => reject_batches(&shared, batch).await,
+        Err(TransportError::Status(status)) if status.is_client_error() => {
+            reject_batches(&shared, batch).await;
+        }
+        Err(e) => {
+            let message = e.to_string();
+            for pending in batch {
+                let error = match &e {
+                    TransportError::RateLimited(retry_after) => {
+                        TransportError::RateLimited(*retry_after)
+                    }
+                    TransportError::Status(status) => TransportError::Status(*status),
+                    e if e.is_transient() => TransportError::BatchUnavailable(message.clone()),
+                    _ => TransportError::Batch(message.clone()),
+                };
+                let _ = pending.reply.send(Err(error));
+            }
+        }
+    }
+}
+
+async fn reject_batches<T: RawTransport>(shared: &Shared<T>, batch: Vec<Pending>) {
+    if shared.batches_supported.swap(false, Ordering::Relaxed) {
+        println!("[rpc] endpoint rejected a batch request, falling back to single requests");
+    }
+    send_each(&shared.transport, batch).await;
+}
+
+async fn send_each<T: RawTransport>(transport: &T, batch: Vec<Pending>) {
+    join_all(batch.into_iter().map(|pending| async move {
+        let _ = pending.reply.send(transport.send(pending.request).await);
+    }))
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Echoes the params of every request as its result, optionally rejecting batches
+    #[derive(Default)]
+    struct EchoTransport {
+        reject_batches: bool,
+        sent: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl RawTransport for EchoTransport {
+        async fn send(&self, request: Value) -> Result<Value, TransportError> {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            let echo = |request: &Value| json!({"id": request["id"], "result": request["params"]});
+
+            match request {
+                Value::Array(_) if self.reject_batches => {
+                    Ok(json!({"id": null, "error": {"code": -32600, "message": "no batches"}}))
+                }
+                Value::Array(requests) => Ok(Value::Array(requests.iter().map(echo).collect())),
+                request => Ok(echo(&request)),
+            }
+        }
+    }
+
+    fn call(params: u64) -> Value {
+        json!({"id": 1, "jsonrpc": "2.0", "method": "starknet_call", "params": [params]})
+    }
+
+    async fn send_concurrently(transport: &BatchTransport<EchoTransport>) -> Vec<Value> {
+        join_all((0..4).map(|params| transport.send(call(params))))
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_coalesces_concurrent_requests() {
+        let config = BatchConfig { max_batch_size: 4, linger: Duration::from_secs(1) };
+        let transport = BatchTransport::new(EchoTransport::default(), config);
+
+        // Fills a batch, so it's sent before the linger time is up
+        let responses = send_concurrently(&transport).await;
+        for (params, response) in responses.iter().enumerate() {
+            assert_eq!(response, &json!({"id": 1, "result": [params]}));
+        }
+        assert_eq!(transport.0.transport.sent.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_single_requests() {
+        let config = BatchConfig { max_batch_size: 4, linger: Duration::from_millis(1) };
+        let echo = EchoTransport { reject_batches: true, ..EchoTransport::default() };
+        let transport = BatchTransport::new(echo, config);
+
+        let responses = send_concurrently(&transport).await;
+        for (params, response) in responses.iter().enumerate() {
+            assert_eq!(response, &json!({"id": 1, "result": [params]}));
+        }
+        assert!(!transport.0.batches_supported.load(Ordering::Relaxed));
+
+        // Rejected batch and its requests one by one, then single requests from now on
+        send_concurrently(&transport).await;
+        assert_eq!(transport.0.transport.sent.load(Ordering::Relaxed), 9);
+    }
+}
@@ -0,0 +1,350 @@
+//! Fetches content behind untrusted HTTP(S) URLs, shared by metadata and media fetching
+//!
+//! Token URIs are set by whoever deploys a contract, so every request is bounded in time, body
+//! size and redirects, and can't reach loopback, private or link-local addresses, e.g. cloud
+//! metadata endpoints at `169.254.169.254`. Host names are checked after DNS resolution and only
+//! the addresses that passed are connected to, so a name can't resolve to a public address for
+//! the check and a private one for the connection.
+//!
+//! Gateways we're configured with, e.g. for IPFS and Arweave, are fetched from with
+//! `gateway_fetcher`, which is bounded the same way but may reach private addresses, e.g. a local
+//! IPFS node.
+//!
+//! Nothing is fetched while RPC traffic is replayed, `RPC_MODE=replay`, the archive doesn't hold
+//! these fetches and a replayed run shouldn't depend on the network.
+use color_eyre::eyre::{self, eyre};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Client, Method, RequestBuilder, StatusCode, Url,
+};
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use url::Host;
+
+use super::is_replay;
+
+// Defaults of `HTTP_CONNECT_TIMEOUT_SECS`, `HTTP_TIMEOUT_SECS` and `HTTP_MAX_REDIRECTS`
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+const DEFAULT_USER_AGENT: &str = concat!("shovel/", env!("CARGO_PKG_VERSION"));
+
+/// Content served for a URL
+#[derive(Debug)]
+pub struct HttpContent {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+pub struct HttpFetcher {
+    client: Client,
+    allow_private: bool,
+    // Every request fails, see `is_replay`
+    offline: bool,
+}
+
+impl HttpFetcher {
+    /// Reads the time to connect in `HTTP_CONNECT_TIMEOUT_SECS`, the time a whole request, body
+    /// included, may take in `HTTP_TIMEOUT_SECS`, the redirects followed in
+    /// `HTTP_MAX_REDIRECTS` and the user agent in `HTTP_USER_AGENT`
+    ///
+    /// `HTTP_ALLOW_PRIVATE_ADDRESSES=true` lifts the address checks, e.g. to serve metadata from
+    /// a local host in development
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if a setting is malformed or the client can't be
+    /// built
+    pub fn from_env() -> eyre::Result<Self> {
+        let allow_private =
+            env::var("HTTP_ALLOW_PRIVATE_ADDRESSES").is_ok_and(|allow| allow == "true");
+        Self::with_settings(allow_private)
+    }
+
+    /// Like `from_env`, without the address checks, for hosts we're configured with
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if a setting is malformed or the client can't be
+    /// built
+    pub fn trusted_from_env() -> eyre::Result<Self> {
+        Self::with_settings(true)
+    }
+
+    fn with_settings(allow_private: bool) -> eyre::Result<Self> {
+        let connect_timeout = match env::var("HTTP_CONNECT_TIMEOUT_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => DEFAULT_CONNECT_TIMEOUT,
+        };
+        let timeout = match env::var("HTTP_TIMEOUT_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => DEFAULT_TIMEOUT,
+        };
+        let max_redirects = match env::var("HTTP_MAX_REDIRECTS") {
+            Ok(max_redirects) => max_redirects.parse()?,
+            Err(_) => DEFAULT_MAX_REDIRECTS,
+        };
+        let user_agent =
+            env::var("HTTP_USER_AGENT").unwrap_or_else(|_| DEFAULT_USER_AGENT.to_string());
+
+        // Redirect targets go through the same checks as the URL itself
+        let redirect_policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= max_redirects {
+                return attempt.error(format!("more than {max_redirects} redirects"));
+            }
+            match check_url(attempt.url(), allow_private) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e.to_string()),
+            }
+        });
+
+        // Proxies would resolve the host instead of us, keep them out of the way
+        let builder = Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(timeout)
+            .redirect(redirect_policy)
+            .user_agent(user_agent)
+            .no_proxy();
+        let builder =
+            if allow_private { builder } else { builder.dns_resolver(Arc::new(PublicResolver)) };
+
+        Ok(Self { client: builder.build()?, allow_private, offline: is_replay() })
+    }
+
+    /// Fetches the content behind a URL, the body is read up to `max_bytes`
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the URL isn't allowed, the request fails or
+    /// times out, or the body is larger than `max_bytes`
+    pub async fn fetch(&self, url: &str, max_bytes: usize) -> eyre::Result<HttpContent> {
+        let request = self.request(Method::GET, Url::parse(url)?)?;
+        Self::send(request, max_bytes).await
+    }
+
+    /// Starts a request to `url`, to be sent with `send` once it's set up, e.g. with credentials
+    /// or a shorter timeout
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the URL isn't allowed or RPC traffic is being
+    /// replayed
+    pub fn request(&self, method: Method, url: Url) -> eyre::Result<RequestBuilder> {
+        if self.offline {
+            eyre::bail!("{url} isn't fetched while replaying RPC traffic");
+        }
+        check_url(&url, self.allow_private)?;
+        Ok(self.client.request(method, url))
+    }
+
+    /// Sends a request started with `request`, the body is read up to `max_bytes`
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the request fails or times out, or the body is
+    /// larger than `max_bytes`
+    pub async fn send(request: RequestBuilder, max_bytes: usize) -> eyre::Result<HttpContent> {
+        let mut response = request.send().await?.error_for_status()?;
+        if response.content_length().is_some_and(|length| length > max_bytes as u64) {
+            eyre::bail!("body is larger than {max_bytes} bytes");
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(ToString::to_string);
+
+        // Content length may be missing or wrong, count what's actually sent
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if bytes.len() + chunk.len() > max_bytes {
+                eyre::bail!("body is larger than {max_bytes} bytes");
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        Ok(HttpContent { bytes, content_type })
+    }
+}
+
+/// Fetcher shared by every HTTP metadata and media fetch, configured from env on first use
+///
+/// # Panics
+/// This function panics if the HTTP settings are malformed
+pub fn fetcher() -> &'static HttpFetcher {
+    static FETCHER: OnceLock<HttpFetcher> = OnceLock::new();
+    FETCHER.get_or_init(|| HttpFetcher::from_env().expect("HTTP settings should be valid"))
+}
+
+/// Fetcher shared by every IPFS and Arweave gateway fetch, configured from env on first use
+///
+/// # Panics
+/// This function panics if the HTTP settings are malformed
+pub fn gateway_fetcher() -> &'static HttpFetcher {
+    static FETCHER: OnceLock<HttpFetcher> = OnceLock::new();
+    FETCHER.get_or_init(|| HttpFetcher::trusted_from_env().expect("HTTP settings should be valid"))
+}
+
+/// Whether fetching again might succeed, e.g. after a timeout, a connection failure, a rate
+/// limit or a server error. Errors of every gateway that was tried count.
+pub fn is_transient(error: &eyre::Report) -> bool {
+    error.chain().filter_map(|cause| cause.downcast_ref::<reqwest::Error>()).any(|e| {
+        e.is_timeout()
+            || e.is_connect()
+            || e.is_request()
+            || e.is_body()
+            || e.status().is_some_and(|status| {
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            })
+    })
+}
+
+/// Checks the scheme and, for IP literals that are never resolved, the address of a URL
+fn check_url(url: &Url, allow_private: bool) -> eyre::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        eyre::bail!("{} URLs aren't fetched", url.scheme());
+    }
+
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        Some(Host::Domain(_)) => return Ok(()),
+        None => eyre::bail!("URL has no host"),
+    };
+    if !allow_private && !is_public(ip) {
+        return Err(eyre!("{ip} isn't a public address"));
+    }
+
+    Ok(())
+}
+
+/// Resolves host names to their public addresses only
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("{host} doesn't resolve to a public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether an address is reachable on the public internet, rather than loopback, private,
+/// link-local, shared, reserved or multicast
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(a == 0 // This network
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || (a == 100 && (64..128).contains(&b)) // Shared address space, carrier-grade NAT
+        || (a == 198 && (b == 18 || b == 19)) // Benchmarking
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a >= 240) // Reserved and broadcast
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let first_segment = segments[0];
+
+    // NAT64 and 6to4 addresses reach the IPv4 address they embed
+    let embedded_v4 =
+        |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return is_public_v4(embedded_v4(segments[6], segments[7]));
+    }
+    if first_segment == 0x2002 {
+        return is_public_v4(embedded_v4(segments[1], segments[2]));
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (first_segment & 0xfe00) == 0xfc00 // Unique local
+        || (first_segment & 0xffc0) == 0xfe80) // Link-local
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+            "2002:a9fe:a9fe::",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "104.16.0.1", "2606:4700::1111", "64:ff9b::101:101", "2002:101:101::"]
+        {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_classifies_fetch_errors() {
+        let refused = Client::new().get("http://127.0.0.1:1/").send().await.unwrap_err();
+        assert!(is_transient(&eyre::Report::new(refused).wrap_err("no gateway served it")));
+
+        assert!(!is_transient(&eyre!("body is larger than 10 bytes")));
+        assert!(!is_transient(&serde_json::from_str::<u8>("{").unwrap_err().into()));
+    }
+
+    #[test]
+    fn test_checks_urls() {
+        let check = |url: &str| check_url(&Url::parse(url).unwrap(), false);
+
+        assert!(check("https://example.com/1.json").is_ok());
+        assert!(check("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check("http://[::1]:8080/").is_err());
+        // Decimal form of 127.0.0.1
+        assert!(check("http://2130706433/").is_err());
+        assert!(check("file:///etc/passwd").is_err());
+        assert!(check_url(&Url::parse("http://127.0.0.1/").unwrap(), true).is_ok());
+    }
+
+    #[test]
+    fn test_fetches_nothing_while_replaying() {
+        let fetcher = HttpFetcher { client: Client::new(), allow_private: true, offline: true };
+        let url = Url::parse("https://example.com/1.json").unwrap();
+        assert!(fetcher.request(Method::GET, url).is_err());
+    }
+}
@@ -1,40 +1,114 @@
 pub mod token {
-    use crate::common::{
-        starknet_constants::{TOKEN_URI_SELECTOR, ZERO_FELT},
-        traits::ToUtf8String,
-        types::CairoUint256,
+    use crate::{
+        common::{
+            starknet_constants::TOKEN_URI_SELECTOR, traits::ToUtf8String, types::CairoUint256,
+        },
+        rpc::{
+            http,
+            ipfs::{self, IpfsPath},
+            provider::{call_first, optional, RpcProvider},
+        },
+    };
+    use base64::{
+        alphabet,
+        engine::{general_purpose, DecodePaddingMode},
+        Engine as _,
     };
-    use base64::{engine::general_purpose, Engine as _};
     use color_eyre::eyre;
-    use reqwest::Client;
-    use serde::{Deserialize, Serialize};
-    use serde_json::Number;
+    use reqwest::{Method, Url};
+    use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+    use serde_json::{Map, Number, Value};
     use starknet::{
-        core::types::{BlockId, BlockTag, FieldElement, FunctionCall},
+        core::types::{BlockId, FieldElement, FunctionCall},
         macros::selector,
-        providers::{
-            jsonrpc::{HttpTransport, JsonRpcClient},
-            Provider,
-        },
     };
-    use std::env;
+    use std::{env, time::Duration};
     use urlencoding;
 
+    // `date` attributes above this are taken as milliseconds, it's in year 5138 in seconds
+    const MAX_SECONDS_TIMESTAMP: f64 = 1e11;
+    // Timestamps Postgres can store, from 4714-11-24 BC up to 294277-01-01
+    const MIN_STORED_TIMESTAMP: f64 = -210_866_803_200.0;
+    const MAX_STORED_TIMESTAMP: f64 = 9_224_318_016_000.0;
+    // Largest metadata document we download over HTTP
+    const MAX_METADATA_BYTES: usize = 10 * 1024 * 1024;
+    // Placeholder for the token id in ERC1155 URIs
+    pub const ID_PLACEHOLDER: &str = "{id}";
+    // Gateway `ar://` URIs are fetched from unless set by `ARWEAVE_GATEWAYS`
+    const DEFAULT_ARWEAVE_GATEWAY: &str = "https://arweave.net";
+    // How long an Arweave gateway gets to serve an item
+    const ARWEAVE_GATEWAY_TIMEOUT: Duration = Duration::from_secs(10);
+    // Standard base64 that doesn't insist on padding, which data URIs often leave out
+    const BASE64: general_purpose::GeneralPurpose = general_purpose::GeneralPurpose::new(
+        &alphabet::STANDARD,
+        general_purpose::GeneralPurposeConfig::new()
+            .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+
+    /// URI schemes we resolve metadata and media from, see `get_metadata_type`
     pub enum MetadataType<'a> {
         Http(&'a str),
-        Ipfs(&'a str),
+        Ipfs(IpfsPath),
+        /// Arweave transaction id and the path within it
+        Arweave(&'a str),
+        /// RFC 2397 data URI, see `DataUri`
+        Data(&'a str),
+        /// Anything else, expected to be bare JSON
         OnChain(&'a str),
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    /// How marketplaces display an attribute, parsed from either `snake_case`, e.g.
+    /// `boost_percentage`, or `PascalCase`, e.g. `BoostPercentage`
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(from = "String", into = "String")]
     pub enum DisplayType {
         Number,
         BoostPercentage,
         BoostNumber,
+        /// Value is a unix timestamp
         Date,
+        /// Any other display type, kept as published
+        Other(String),
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    impl DisplayType {
+        /// Canonical name it's stored as, the `snake_case` one for known types
+        pub fn as_str(&self) -> &str {
+            match self {
+                Self::Number => "number",
+                Self::BoostPercentage => "boost_percentage",
+                Self::BoostNumber => "boost_number",
+                Self::Date => "date",
+                Self::Other(display_type) => display_type,
+            }
+        }
+    }
+
+    impl From<String> for DisplayType {
+        fn from(display_type: String) -> Self {
+            let normalized: String = display_type
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect();
+
+            match normalized.as_str() {
+                "number" => Self::Number,
+                "boostpercentage" => Self::BoostPercentage,
+                "boostnumber" => Self::BoostNumber,
+                "date" => Self::Date,
+                _ => Self::Other(display_type),
+            }
+        }
+    }
+
+    impl From<DisplayType> for String {
+        fn from(display_type: DisplayType) -> Self {
+            display_type.as_str().to_string()
+        }
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
     #[serde(untagged)]
     pub enum AttributeValue {
         String(String),
@@ -43,34 +117,153 @@ pub mod token {
         StringVec(Vec<String>),
         NumberVec(Vec<Number>),
         BoolVec(Vec<bool>),
+        Object(Map<String, Value>),
+        /// Anything else, e.g. `null` or mixed arrays
+        Other(Value),
+    }
+
+    impl Default for AttributeValue {
+        fn default() -> Self {
+            Self::Other(Value::Null)
+        }
+    }
+
+    impl AttributeValue {
+        pub fn as_text(&self) -> Option<&str> {
+            match self {
+                Self::String(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        /// Numeric value, including numbers published as strings, e.g. `"10"`
+        pub fn as_number(&self) -> Option<Number> {
+            match self {
+                Self::Number(value) => Some(value.clone()),
+                Self::String(value) => value.trim().parse().ok(),
+                _ => None,
+            }
+        }
+
+        pub fn as_bool(&self) -> Option<bool> {
+            match self {
+                Self::Bool(value) => Some(*value),
+                _ => None,
+            }
+        }
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
     pub struct Attribute {
+        #[serde(default, alias = "displayType")]
         pub display_type: Option<DisplayType>,
+        #[serde(default, alias = "traitType", alias = "trait")]
         pub trait_type: Option<String>,
+        #[serde(default)]
         pub value: AttributeValue,
     }
 
-    #[derive(Debug, Default, Deserialize, Serialize)]
+    impl Attribute {
+        /// Unix timestamp in seconds of a `date` attribute, ones that are obviously in
+        /// milliseconds are converted. `None` for dates we can't store, e.g. out of range.
+        pub fn date(&self) -> Option<f64> {
+            if self.display_type != Some(DisplayType::Date) {
+                return None;
+            }
+
+            let timestamp = self.value.as_number()?.as_f64()?;
+            let timestamp =
+                if timestamp > MAX_SECONDS_TIMESTAMP { timestamp / 1000.0 } else { timestamp };
+            (MIN_STORED_TIMESTAMP..MAX_STORED_TIMESTAMP).contains(&timestamp).then_some(timestamp)
+        }
+    }
+
+    /// Parses attributes published either as a list of attribute objects or as a map of trait
+    /// types to values. Attributes that don't parse are left out rather than failing the document.
+    fn parse_attributes(attributes: &Value) -> Option<Vec<Attribute>> {
+        match attributes {
+            Value::Array(attributes) => Some(
+                attributes
+                    .iter()
+                    .filter_map(|attribute| Attribute::deserialize(attribute).ok())
+                    .collect(),
+            ),
+            Value::Object(attributes) => Some(
+                attributes
+                    .iter()
+                    .map(|(trait_type, value)| Attribute {
+                        display_type: None,
+                        trait_type: Some(trait_type.clone()),
+                        value: AttributeValue::deserialize(value).unwrap_or_default(),
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn deserialize_attributes<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<Attribute>>, D::Error> {
+        Ok(parse_attributes(&Value::deserialize(deserializer)?))
+    }
+
+    #[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
     pub struct TokenMetadata {
         pub image: Option<String>,
         pub image_data: Option<String>,
         pub external_url: Option<String>,
         pub description: Option<String>,
         pub name: Option<String>,
+        #[serde(default, deserialize_with = "deserialize_attributes")]
         pub attributes: Option<Vec<Attribute>>,
         pub background_color: Option<String>,
         pub animation_url: Option<String>,
         pub youtube_url: Option<String>,
     }
 
-    /// Gets the token URI for a given token ID
+    /// Token metadata document as published, along with the known fields parsed out of it
+    ///
+    /// Fields outside `TokenMetadata`, e.g. `properties` or `edition`, are only kept in `raw`
+    #[derive(Debug, Default, PartialEq)]
+    pub struct MetadataDocument {
+        /// Known fields, empty if they didn't parse
+        pub metadata: TokenMetadata,
+        /// Whole document, `None` if there's no JSON document
+        pub raw: Option<Value>,
+        /// Whether the known fields parsed out of `raw`
+        pub parsed: bool,
+    }
+
+    impl MetadataDocument {
+        /// Parses the known fields out of a document, which is kept whole even if they don't.
+        /// Attributes are read from `traits` for documents that don't have `attributes`.
+        pub fn from_raw(raw: Value) -> Self {
+            match TokenMetadata::deserialize(&raw) {
+                Ok(mut metadata) => {
+                    if metadata.attributes.is_none() {
+                        metadata.attributes = raw.get("traits").and_then(parse_attributes);
+                    }
+                    Self { metadata, raw: Some(raw), parsed: true }
+                }
+                Err(_) => {
+                    Self { metadata: TokenMetadata::default(), raw: Some(raw), parsed: false }
+                }
+            }
+        }
+    }
+
+    /// Gets the token URI for a given token ID at given block, empty if the contract doesn't have
+    /// `tokenURI`
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the RPC call fails transiently
     pub async fn get_erc721_uri(
         address: FieldElement,
-        rpc: &JsonRpcClient<HttpTransport>,
         token_id: CairoUint256,
-    ) -> String {
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
         // token_uri(uint256) | tokenURI(uint256)
         // uint256 -> [ felt, felt ]
         let request = FunctionCall {
@@ -79,203 +272,713 @@ pub mod token {
             calldata: vec![token_id.low, token_id.high],
         };
 
-        let Ok(token_uri_response) = rpc.call(request, BlockId::Tag(BlockTag::Latest)).await else {
-            return String::new();
+        let response = rpc.call(request, block_id).await;
+        let Some(token_uri_response) = optional(response)? else {
+            return Ok(String::new());
         };
 
-        // If tokenURI function is EIP721Metadata compliant, it should return one felt
-        // Otherwise we also consider the case where contracts returns a felt array
-        let is_felt_array = token_uri_response.len() > 1;
-
-        if is_felt_array {
-            token_uri_response.to_utf8_string()
-        } else {
-            token_uri_response.get(0).unwrap_or(&ZERO_FELT).to_utf8_string()
-        }
+        // A short string, a Cairo 0 felt array or a Cairo 1 `ByteArray`
+        Ok(token_uri_response.to_utf8_string()?)
     }
 
+    /// Gets the token URI for a given token ID at given block, empty if the contract doesn't
+    /// have any of `uri`, `tokenURI` or `token_uri`
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if an RPC call fails transiently
     pub async fn get_erc1155_uri(
         address: FieldElement,
-        rpc: &JsonRpcClient<HttpTransport>,
         token_id: CairoUint256,
-    ) -> String {
-        let possible_selectors =
-            vec![selector!("uri"), selector!("tokenURI"), selector!("token_uri")];
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
+        let possible_selectors = [selector!("uri"), selector!("tokenURI"), selector!("token_uri")];
+        let calldata = [token_id.low, token_id.high];
 
-        let mut token_uri_response: Option<Vec<FieldElement>> = None;
+        let response = call_first(address, &possible_selectors, &calldata, block_id, rpc).await;
+        let token_uri_response = optional(response)?;
 
-        for selector in possible_selectors {
-            let request = FunctionCall {
-                contract_address: address,
-                entry_point_selector: selector,
-                calldata: vec![token_id.low, token_id.high],
-            };
+        let Some(token_uri_response) = token_uri_response else {
+            return Ok(String::new());
+        };
 
-            if let Ok(felt_array) = rpc.call(request, BlockId::Tag(BlockTag::Latest)).await {
-                token_uri_response = Some(felt_array);
-                break;
-            };
+        Ok(token_uri_response.to_utf8_string()?)
+    }
+
+    /// Gets the token metadata document for a given uri, along with the IPFS gateway that served
+    /// it if it's on IPFS
+    pub async fn get_token_metadata(uri: &str) -> eyre::Result<(MetadataDocument, Option<String>)> {
+        let (raw, gateway) = resolve_json_metadata::<Option<Value>>(uri).await?;
+        Ok((raw.map_or_else(MetadataDocument::default, MetadataDocument::from_raw), gateway))
+    }
+
+    /// Expands the `{id}` placeholder ERC1155 URIs may hold into the token id, first as the 64
+    /// lowercase hex characters the standard asks for, then in decimal, which many contracts host
+    /// their metadata at. A URI without the placeholder is returned as is.
+    pub fn expand_uri_template(uri: &str, token_id: CairoUint256) -> Vec<String> {
+        if !uri.contains(ID_PLACEHOLDER) {
+            return vec![uri.to_string()];
         }
 
-        let Some(token_uri_response) = token_uri_response else {
-            return String::new();
-        };
+        vec![
+            uri.replace(ID_PLACEHOLDER, &token_id.to_hex()),
+            uri.replace(ID_PLACEHOLDER, &token_id.to_decimal()),
+        ]
+    }
 
-        let is_felt_array = token_uri_response.len() > 1;
+    /// Gets token metadata from the first expansion of the token URI that serves it, see
+    /// `expand_uri_template`
+    ///
+    /// Returns the expanded URI the metadata was fetched from, or the first one if none served
+    /// it, along with the outcome of `get_token_metadata`
+    pub async fn get_expanded_token_metadata(
+        uri: &str,
+        token_id: CairoUint256,
+    ) -> (String, eyre::Result<(MetadataDocument, Option<String>)>) {
+        let mut expansions = expand_uri_template(uri, token_id).into_iter();
+        let first = expansions.next().expect("a URI has one expansion at least");
 
-        if is_felt_array {
-            token_uri_response.to_utf8_string()
-        } else {
-            token_uri_response.get(0).unwrap_or(&ZERO_FELT).to_utf8_string()
+        let fetched = get_token_metadata(&first).await;
+        if fetched.is_ok() {
+            return (first, fetched);
+        }
+        for expansion in expansions {
+            if let Ok(metadata) = get_token_metadata(&expansion).await {
+                return (expansion, Ok(metadata));
+            }
         }
+
+        (first, fetched)
     }
 
-    /// Gets token metadata for a given uri
-    pub async fn get_token_metadata(uri: &str) -> eyre::Result<TokenMetadata> {
-        let client = Client::new();
+    /// Resolves given uri and parses the JSON document behind it, used for both token and
+    /// collection level metadata
+    pub async fn get_json_metadata<T: DeserializeOwned + Default>(uri: &str) -> eyre::Result<T> {
+        resolve_json_metadata(uri).await.map(|(metadata, _)| metadata)
+    }
 
-        let metadata_type = get_metadata_type(uri);
+    async fn resolve_json_metadata<T: DeserializeOwned + Default>(
+        uri: &str,
+    ) -> eyre::Result<(T, Option<String>)> {
+        // Tokens without metadata have an empty URI
+        if uri.trim().is_empty() {
+            return Ok((T::default(), None));
+        }
 
-        match metadata_type {
-            MetadataType::Ipfs(uri) => Ok(get_ipfs_metadata(uri, &client).await?),
-            MetadataType::Http(uri) => Ok(get_http_metadata(uri, &client).await?),
-            MetadataType::OnChain(uri) => Ok(get_onchain_metadata(uri)?),
+        // If it is only the JSON without the data format information, parse it as is
+        if let MetadataType::OnChain(uri) = get_metadata_type(uri) {
+            return Ok((serde_json::from_str(uri)?, None));
         }
+
+        let content = fetch_uri(uri, MAX_METADATA_BYTES).await?;
+        let metadata: T = serde_json::from_str(&content.text()?)?;
+        Ok((metadata, content.gateway))
     }
 
-    async fn get_ipfs_metadata(uri: &str, client: &Client) -> eyre::Result<TokenMetadata> {
-        let username = env::var("IPFS_USERNAME")?;
-        let password = env::var("IPFS_PASSWORD")?;
+    /// Content behind a URI
+    #[derive(Debug)]
+    pub struct UriContent {
+        pub bytes: Vec<u8>,
+        /// Media type with its parameters, e.g. `application/json; charset=utf-8`
+        pub content_type: Option<String>,
+        /// IPFS gateway that served the content, if it's on IPFS
+        pub gateway: Option<String>,
+    }
 
-        let mut ipfs_url = "https://ipfs.infura.io:5001/api/v0/cat?arg=".to_string();
-        let ipfs_hash = uri.trim_start_matches("ipfs://");
-        ipfs_url.push_str(ipfs_hash);
+    impl UriContent {
+        /// Content as text, decoded as the charset of its content type says
+        ///
+        /// # Errors
+        /// This function returns `eyre::ErrReport` if the content isn't valid in its charset
+        pub fn text(&self) -> eyre::Result<String> {
+            let charset = self.content_type.as_deref().and_then(|content_type| {
+                content_type.split(';').skip(1).find_map(|param| {
+                    let (name, value) = param.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("charset")
+                        .then(|| value.trim().trim_matches('"'))
+                })
+            });
+
+            match charset.map(str::to_ascii_lowercase).as_deref() {
+                Some("iso-8859-1" | "latin1") => {
+                    Ok(self.bytes.iter().copied().map(char::from).collect())
+                }
+                // US-ASCII, the default of data URIs, is a subset of UTF-8
+                _ => Ok(String::from_utf8(self.bytes.clone())?),
+            }
+        }
+    }
 
-        let req = client.post(ipfs_url).basic_auth(&username, Some(&password));
-        let resp = req.send().await?;
-        let metadata: TokenMetadata = resp.json().await?;
-        Ok(metadata)
+    /// Fetches the content behind a URI of any scheme in `MetadataType`, used for both metadata
+    /// and media. Bodies fetched over HTTP, from gateways included, are read up to `max_bytes`.
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the content can't be fetched or decoded
+    pub async fn fetch_uri(uri: &str, max_bytes: usize) -> eyre::Result<UriContent> {
+        match get_metadata_type(uri) {
+            MetadataType::Http(uri) => {
+                let content = http::fetcher().fetch(uri, max_bytes).await?;
+                Ok(UriContent {
+                    bytes: content.bytes,
+                    content_type: content.content_type,
+                    gateway: None,
+                })
+            }
+            MetadataType::Ipfs(path) => {
+                let content = ipfs::resolver().fetch(&path, max_bytes).await?;
+                println!("[metadata] {path} served by {}", content.gateway);
+
+                Ok(UriContent {
+                    bytes: content.bytes,
+                    content_type: content.content_type,
+                    gateway: Some(content.gateway),
+                })
+            }
+            MetadataType::Arweave(path) => get_arweave_content(path, max_bytes).await,
+            MetadataType::Data(uri) => {
+                let data_uri = DataUri::parse(uri)?;
+                let content_type = match &data_uri.charset {
+                    Some(charset) => format!("{}; charset={charset}", data_uri.media_type),
+                    None => data_uri.media_type,
+                };
+
+                Ok(UriContent {
+                    bytes: data_uri.data,
+                    content_type: Some(content_type),
+                    gateway: None,
+                })
+            }
+            MetadataType::OnChain(json) => Ok(UriContent {
+                bytes: json.as_bytes().to_vec(),
+                content_type: Some("application/json".to_string()),
+                gateway: None,
+            }),
+        }
     }
 
-    async fn get_http_metadata(uri: &str, client: &Client) -> eyre::Result<TokenMetadata> {
-        let resp = client.get(uri).send().await?;
-        let metadata: TokenMetadata = resp.json().await?;
-        Ok(metadata)
+    /// Fetches `ar://<transaction id>[/path]` from the gateways in `ARWEAVE_GATEWAYS`, tried in
+    /// order, or `arweave.net`
+    async fn get_arweave_content(path: &str, max_bytes: usize) -> eyre::Result<UriContent> {
+        let gateways = env::var("ARWEAVE_GATEWAYS").unwrap_or(DEFAULT_ARWEAVE_GATEWAY.to_string());
+        let mut errors = Vec::new();
+        // Kept so the error tells whether asking again might help, see `http::is_transient`
+        let mut transient_error = None;
+
+        for gateway in gateways.split(',').map(str::trim).filter(|gateway| !gateway.is_empty()) {
+            match get_arweave_content_from(gateway, path, max_bytes).await {
+                Ok(content) => {
+                    return Ok(UriContent {
+                        bytes: content.bytes,
+                        content_type: content.content_type,
+                        gateway: None,
+                    })
+                }
+                Err(e) => {
+                    errors.push(format!("{gateway}: {e}"));
+                    if http::is_transient(&e) {
+                        transient_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        let message = format!("no Arweave gateway served {path} ({})", errors.join(", "));
+        Err(match transient_error {
+            Some(e) => e.wrap_err(message),
+            None => eyre::eyre!(message),
+        })
     }
 
-    fn get_onchain_metadata(uri: &str) -> eyre::Result<TokenMetadata> {
-        // Try to split from the comma as it is the standard with on chain metadata
-        let url_encoded = urlencoding::decode(uri).map(|s| String::from(s.as_ref()));
-        let uri_string = match url_encoded {
-            Ok(encoded) => encoded,
-            Err(_) => String::from(uri),
-        };
+    async fn get_arweave_content_from(
+        gateway: &str,
+        path: &str,
+        max_bytes: usize,
+    ) -> eyre::Result<http::HttpContent> {
+        let fetcher = http::gateway_fetcher();
+        let url = Url::parse(&format!("{}/{path}", gateway.trim_end_matches('/')))?;
+        let request = fetcher.request(Method::GET, url)?.timeout(ARWEAVE_GATEWAY_TIMEOUT);
 
-        match uri_string.split_once(',') {
-            Some(("data:application/json;base64", uri)) => {
-                // If it is base64 encoded, decode it, parse and return
-                let decoded = general_purpose::STANDARD.decode(uri)?;
-                let decoded = std::str::from_utf8(&decoded)?;
-                let metadata: TokenMetadata = serde_json::from_str(decoded)?;
-                Ok(metadata)
-            }
-            Some(("data:application/json", uri)) => {
-                // If it is plain json, parse it and return
-                //println!("Handling {:?}", uri);
-                let metadata: TokenMetadata = serde_json::from_str(uri)?;
-                Ok(metadata)
+        http::HttpFetcher::send(request, max_bytes).await
+    }
+
+    /// An RFC 2397 `data:[<media type>][;base64],<data>` URI
+    #[derive(Debug, PartialEq, Eq)]
+    pub struct DataUri {
+        /// Media type without its parameters, `text/plain` if it's left out
+        pub media_type: String,
+        pub charset: Option<String>,
+        pub data: Vec<u8>,
+    }
+
+    impl DataUri {
+        /// Parses a data URI, percent-encoded and base64 data are both decoded
+        ///
+        /// # Errors
+        /// This function returns `eyre::ErrReport` if `uri` isn't a data URI or its base64 data
+        /// is malformed
+        pub fn parse(uri: &str) -> eyre::Result<Self> {
+            let rest =
+                strip_scheme(uri.trim(), "data:").ok_or_else(|| eyre::eyre!("not a data URI"))?;
+            let (header, data) =
+                rest.split_once(',').ok_or_else(|| eyre::eyre!("data URI without data"))?;
+
+            let mut params = header.split(';');
+            let media_type = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let mut charset = None;
+            let mut is_base64 = false;
+
+            for param in params.map(str::trim) {
+                if param.eq_ignore_ascii_case("base64") {
+                    is_base64 = true;
+                } else if param.eq_ignore_ascii_case("utf8") || param.eq_ignore_ascii_case("utf-8")
+                {
+                    // Common but non standard `data:application/json;utf8,`
+                    charset = Some("utf-8".to_string());
+                } else if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("charset") {
+                        charset = Some(value.trim().trim_matches('"').to_string());
+                    }
+                }
             }
-            _ => match serde_json::from_str(uri) {
-                // If it is only the URI without the data format information, try to format it
-                // and if it fails, return empty metadata
-                Ok(v) => Ok(v),
-                Err(_) => Ok(TokenMetadata::default()),
-            },
+
+            let decoded = urlencoding::decode_binary(data.as_bytes());
+            let data = if is_base64 {
+                let encoded: Vec<u8> =
+                    decoded.iter().copied().filter(|byte| !byte.is_ascii_whitespace()).collect();
+                BASE64.decode(encoded)?
+            } else {
+                decoded.into_owned()
+            };
+
+            let media_type =
+                if media_type.is_empty() { "text/plain".to_string() } else { media_type };
+            Ok(Self { media_type, charset, data })
         }
     }
 
-    fn get_metadata_type(uri: &str) -> MetadataType<'_> {
-        if uri.starts_with("ipfs://") {
-            MetadataType::Ipfs(uri)
-        } else if uri.starts_with("http://") || uri.starts_with("https://") {
+    fn strip_scheme<'a>(uri: &'a str, scheme: &str) -> Option<&'a str> {
+        let head = uri.get(..scheme.len())?;
+        head.eq_ignore_ascii_case(scheme).then(|| &uri[scheme.len()..])
+    }
+
+    /// Classifies the uri, this is the one place URI schemes are registered. Gateway URLs of
+    /// IPFS content are resolved through our own gateways.
+    pub fn get_metadata_type(uri: &str) -> MetadataType<'_> {
+        let uri = uri.trim();
+
+        if strip_scheme(uri, "data:").is_some() {
+            MetadataType::Data(uri)
+        } else if let Some(path) = strip_scheme(uri, "ar://") {
+            MetadataType::Arweave(path.trim_start_matches('/'))
+        } else if let Some(path) = IpfsPath::parse(uri) {
+            MetadataType::Ipfs(path)
+        } else if strip_scheme(uri, "http://").is_some() || strip_scheme(uri, "https://").is_some()
+        {
             MetadataType::Http(uri)
         } else {
             MetadataType::OnChain(uri)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn parse(uri: &str) -> DataUri {
+            DataUri::parse(uri).unwrap()
+        }
+
+        #[test]
+        fn test_parses_data_uris() {
+            let uri = parse(r#"data:application/json,{"name":"a%20b"}"#);
+            assert_eq!(uri.media_type, "application/json");
+            assert_eq!(uri.charset, None);
+            assert_eq!(uri.data, br#"{"name":"a b"}"#);
+
+            let uri = parse(r#"data:application/json;utf8,{"name":"100%"}"#);
+            assert_eq!(uri.charset.as_deref(), Some("utf-8"));
+            assert_eq!(uri.data, br#"{"name":"100%"}"#);
+
+            let uri = parse("data:image/svg+xml;base64,PHN2Zy8+");
+            assert_eq!(uri.media_type, "image/svg+xml");
+            assert_eq!(uri.data, b"<svg/>");
+
+            // Percent-encoded base64 without padding
+            assert_eq!(parse("data:;base64,PHN2Zy8%2B").data, b"<svg/>");
+            assert_eq!(parse("data:;base64,YQ").data, b"a");
+
+            let uri = parse("DATA:text/plain;charset=\"ISO-8859-1\",caf%E9");
+            assert_eq!(uri.media_type, "text/plain");
+            assert_eq!(uri.charset.as_deref(), Some("ISO-8859-1"));
+            assert_eq!(uri.data, b"caf\xe9");
+
+            assert_eq!(parse("data:,hi").media_type, "text/plain");
+            assert!(DataUri::parse("data:text/plain").is_err());
+            assert!(DataUri::parse("data:;base64,!!!").is_err());
+        }
+
+        #[test]
+        fn test_decodes_text_in_charset() {
+            let content = |content_type: &str, bytes: &[u8]| UriContent {
+                bytes: bytes.to_vec(),
+                content_type: Some(content_type.to_string()),
+                gateway: None,
+            };
+
+            assert_eq!(
+                content("text/plain; charset=ISO-8859-1", b"caf\xe9").text().unwrap(),
+                "café"
+            );
+            assert_eq!(content("application/json", "café".as_bytes()).text().unwrap(), "café");
+            assert!(content("application/json", b"caf\xe9").text().is_err());
+        }
+
+        #[test]
+        fn test_keeps_raw_document() {
+            let raw = serde_json::json!({
+                "name": "Duck #1",
+                "edition": 1,
+                "properties": { "rarity": "rare" }
+            });
+            let document = MetadataDocument::from_raw(raw.clone());
+            assert!(document.parsed);
+            assert_eq!(document.metadata.name.as_deref(), Some("Duck #1"));
+            assert_eq!(document.raw, Some(raw));
+
+            // Known fields that don't fit don't lose the document
+            let raw = serde_json::json!({ "name": 1, "dna": "abc" });
+            let document = MetadataDocument::from_raw(raw.clone());
+            assert!(!document.parsed);
+            assert_eq!(document.metadata, TokenMetadata::default());
+            assert_eq!(document.raw, Some(raw));
+        }
+
+        #[test]
+        fn test_parses_attributes_leniently() {
+            let document = MetadataDocument::from_raw(serde_json::json!({
+                "attributes": [
+                    { "trait_type": "Background", "value": "Gold" },
+                    { "display_type": "boost_percentage", "trait_type": "Speed", "value": 10 },
+                    {
+                        "displayType": "Date",
+                        "traitType": "Birthday",
+                        "value": 1_546_360_800_000_u64
+                    },
+                    { "display_type": "ranking", "value": { "rank": 1 } },
+                    { "trait_type": ["not", "a", "name"], "value": 1 }
+                ]
+            }));
+            assert!(document.parsed);
+
+            let attributes = document.metadata.attributes.unwrap();
+            assert_eq!(attributes.len(), 4);
+            assert_eq!(attributes[0].value.as_text(), Some("Gold"));
+            assert_eq!(attributes[1].display_type, Some(DisplayType::BoostPercentage));
+            assert_eq!(attributes[2].display_type, Some(DisplayType::Date));
+            assert_eq!(attributes[2].date(), Some(1_546_360_800.0));
+            let date = |value: f64| Attribute {
+                display_type: Some(DisplayType::Date),
+                trait_type: None,
+                value: AttributeValue::Number(Number::from_f64(value).unwrap()),
+            };
+            assert_eq!(date(-1e12).date(), None);
+            assert_eq!(date(1e300).date(), None);
+            assert_eq!(attributes[3].display_type, Some(DisplayType::Other("ranking".to_string())));
+            assert!(matches!(attributes[3].value, AttributeValue::Object(_)));
+
+            // Map of trait types to values under `traits`
+            let document = MetadataDocument::from_raw(serde_json::json!({
+                "traits": { "Eyes": "Laser", "Level": "3" }
+            }));
+            let attributes = document.metadata.attributes.unwrap();
+            assert_eq!(attributes.len(), 2);
+            assert_eq!(attributes[0].trait_type.as_deref(), Some("Eyes"));
+            assert_eq!(attributes[1].value.as_number(), Some(Number::from(3)));
+        }
+
+        #[tokio::test]
+        async fn test_parses_on_chain_json() {
+            let (document, _) = get_token_metadata(r#"{ "name": "Token" }"#).await.unwrap();
+            assert_eq!(document.metadata.name.as_deref(), Some("Token"));
+
+            assert!(get_token_metadata(r#"{ "name": "Tok"#).await.is_err());
+            assert_eq!(get_token_metadata("").await.unwrap().0, MetadataDocument::default());
+        }
+
+        #[test]
+        fn test_expands_id_template() {
+            let token_id = CairoUint256::new(FieldElement::from(26u32), FieldElement::ZERO);
+
+            assert_eq!(
+                expand_uri_template("https://example.com/{id}.json", token_id),
+                vec![
+                    format!("https://example.com/{}1a.json", "0".repeat(62)),
+                    "https://example.com/26.json".to_string()
+                ]
+            );
+            assert_eq!(
+                expand_uri_template("https://example.com/26.json", token_id),
+                vec!["https://example.com/26.json".to_string()]
+            );
+        }
+
+        #[test]
+        fn test_classifies_uris() {
+            let is = |uri: &str, expected: &str| {
+                let metadata_type = match get_metadata_type(uri) {
+                    MetadataType::Http(_) => "http",
+                    MetadataType::Ipfs(_) => "ipfs",
+                    MetadataType::Arweave(_) => "arweave",
+                    MetadataType::Data(_) => "data",
+                    MetadataType::OnChain(_) => "onchain",
+                };
+                assert_eq!(metadata_type, expected, "{uri}");
+            };
+
+            is("https://example.com/1.json", "http");
+            is("ipfs://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG/1.json", "ipfs");
+            is("ar://bNbA3TEQVL60xlgCcqdz4ZPHFZ711cZ3hmkpGttDt_U/1.json", "arweave");
+            is("data:application/json;base64,e30=", "data");
+            is(r#"{"name":"a"}"#, "onchain");
+        }
+    }
 }
 
 pub mod contract {
-    use crate::common::starknet_constants::{NAME_SELECTOR, SYMBOL_SELECTOR, ZERO_FELT};
+    use crate::common::starknet_constants::{NAME_SELECTOR, SYMBOL_SELECTOR};
     use crate::common::traits::ToUtf8String;
+    use crate::rpc::provider::{call_first, optional, RpcProvider};
     use color_eyre::eyre;
+    use serde::{Deserialize, Serialize};
+    use starknet::core::types::{BlockId, FieldElement, FunctionCall};
     use starknet::core::types::{ContractClass, LegacyContractAbiEntry};
-    use starknet::providers::Provider;
-    use starknet::{
-        core::types::{BlockId, FieldElement, FunctionCall},
-        providers::jsonrpc::{HttpTransport, JsonRpcClient},
-    };
+    use starknet::macros::selector;
 
+    /// Collection level metadata returned by `contractURI()`, as described by OpenSea
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    pub struct CollectionMetadata {
+        pub name: Option<String>,
+        pub description: Option<String>,
+        pub image: Option<String>,
+        pub external_link: Option<String>,
+        pub seller_fee_basis_points: Option<i32>,
+        pub fee_recipient: Option<String>,
+    }
+
+    /// Gets the name of the contract, empty if it doesn't have one
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the RPC call fails transiently
     pub async fn get_name(
         address: FieldElement,
         block_id: &BlockId,
-        rpc: &JsonRpcClient<HttpTransport>,
-    ) -> String {
-        let request = FunctionCall {
-            contract_address: address,
-            entry_point_selector: NAME_SELECTOR,
-            calldata: vec![],
-        };
-
-        let result = rpc.call(request, block_id).await.unwrap_or_default();
-        let result = result.get(0).unwrap_or(&ZERO_FELT);
-
-        result.to_utf8_string()
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
+        call_for_string(address, &[NAME_SELECTOR], block_id, rpc).await
     }
 
+    /// Gets the symbol of the contract, empty if it doesn't have one
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the RPC call fails transiently
     pub async fn get_symbol(
         address: FieldElement,
         block_id: &BlockId,
-        rpc: &JsonRpcClient<HttpTransport>,
-    ) -> String {
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
+        call_for_string(address, &[SYMBOL_SELECTOR], block_id, rpc).await
+    }
+
+    /// Gets the owner of an `Ownable` contract, `None` if the contract doesn't expose one
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the RPC call fails transiently
+    pub async fn get_owner(
+        address: FieldElement,
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<Option<FieldElement>> {
         let request = FunctionCall {
             contract_address: address,
-            entry_point_selector: SYMBOL_SELECTOR,
+            entry_point_selector: selector!("owner"),
             calldata: vec![],
         };
 
-        let result = rpc.call(request, block_id).await.unwrap_or_default();
-        let result = result.get(0).unwrap_or(&ZERO_FELT);
+        let result = optional(rpc.call(request, block_id).await)?;
+        Ok(result.and_then(|result| result.first().copied()))
+    }
 
-        result.to_utf8_string()
+    /// Gets the collection metadata URI, `contractURI()` | `contract_uri()`
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if an RPC call fails transiently
+    pub async fn get_contract_uri(
+        address: FieldElement,
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
+        let selectors = [selector!("contractURI"), selector!("contract_uri")];
+        call_for_string(address, &selectors, block_id, rpc).await
     }
 
-    /// Returns if given address is EIP721 compliant
-    /// This function is required as on Starknet ERC20 and ERC721 tokens have
-    /// same "Transfer" event key.
-    pub async fn is_erc721(
+    /// Gets the base URI token URIs are built from, `baseURI()` | `base_uri()`
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if an RPC call fails transiently
+    pub async fn get_base_uri(
         address: FieldElement,
         block_id: &BlockId,
-        rpc: &JsonRpcClient<HttpTransport>,
-    ) -> eyre::Result<bool> {
-        let abi = match rpc.get_class_at(block_id, address).await? {
-            ContractClass::Sierra(_) => eyre::bail!("sierra not supported yet"),
-            ContractClass::Legacy(leg) => leg.abi,
-        };
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
+        let selectors = [selector!("baseURI"), selector!("base_uri")];
+        call_for_string(address, &selectors, block_id, rpc).await
+    }
 
-        let Some(abi) = abi else {
-            return Ok(false);
+    /// Calls the selectors, see `call_first`, and decodes the result as a Cairo string. Returns
+    /// an empty string if the contract has none of them, and the error if a call fails
+    /// transiently, so we don't mistake a rate limited call for a missing value.
+    ///
+    /// A result that isn't a valid string is logged and taken as empty, so it doesn't hold back
+    /// indexing of the contract.
+    async fn call_for_string(
+        address: FieldElement,
+        selectors: &[FieldElement],
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<String> {
+        let result = optional(call_first(address, selectors, &[], block_id, rpc).await)?;
+        let Some(result) = result else {
+            return Ok(String::new());
         };
 
-        // Simplest check we can do is to check "ownerOf" function.
-        for abi_entry in abi {
-            if let LegacyContractAbiEntry::Function(function_abi_entry) = abi_entry {
-                if function_abi_entry.name == "ownerOf" || function_abi_entry.name == "owner_of" {
-                    return Ok(true);
+        Ok(result.to_utf8_string().unwrap_or_else(|e| {
+            eprintln!("[metadata] can't decode string of {address:#x}: {e}");
+            String::new()
+        }))
+    }
+
+    /// ABI of a contract class
+    #[derive(Debug)]
+    pub enum ContractAbi {
+        Legacy(Vec<LegacyContractAbiEntry>),
+        /// JSON ABI of a Sierra class, as it was declared
+        Sierra(String),
+    }
+
+    impl ContractAbi {
+        /// Returns the names of the functions the class exposes, including those of its
+        /// interfaces
+        ///
+        /// # Errors
+        /// This function returns `eyre::ErrReport` if a Sierra ABI can't be parsed
+        pub fn function_names(&self) -> eyre::Result<Vec<String>> {
+            match self {
+                Self::Legacy(abi) => Ok(abi
+                    .iter()
+                    .filter_map(|entry| match entry {
+                        LegacyContractAbiEntry::Function(function) => Some(function.name.clone()),
+                        _ => None,
+                    })
+                    .collect()),
+                Self::Sierra(abi) => {
+                    let entries: Vec<SierraAbiEntry> = serde_json::from_str(abi)?;
+                    let mut names = Vec::new();
+                    SierraAbiEntry::collect_function_names(&entries, &mut names);
+                    Ok(names)
                 }
             }
         }
 
-        Ok(false)
+        /// Returns the ABI as stored in `contract_classes`
+        ///
+        /// # Errors
+        /// This function returns `eyre::ErrReport` if a legacy ABI can't be serialized
+        pub fn to_json(&self) -> eyre::Result<String> {
+            match self {
+                Self::Legacy(abi) => Ok(serde_json::to_string(abi)?),
+                Self::Sierra(abi) => Ok(abi.clone()),
+            }
+        }
+    }
+
+    /// Entry of a Sierra ABI, only as far as we need it to classify the class. Cairo 1 contracts
+    /// list their functions at the top level, later ones group them in interfaces.
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum SierraAbiEntry {
+        Function {
+            name: String,
+        },
+        Interface {
+            items: Vec<SierraAbiEntry>,
+        },
+        #[serde(other)]
+        Other,
+    }
+
+    impl SierraAbiEntry {
+        fn collect_function_names(entries: &[Self], names: &mut Vec<String>) {
+            for entry in entries {
+                match entry {
+                    Self::Function { name } => names.push(name.clone()),
+                    Self::Interface { items } => Self::collect_function_names(items, names),
+                    Self::Other => {}
+                }
+            }
+        }
+    }
+
+    /// Gets the ABI of the class deployed at given address, `None` if a legacy class doesn't
+    /// have one
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if the RPC call fails
+    pub async fn get_abi(
+        address: FieldElement,
+        block_id: &BlockId,
+        rpc: &impl RpcProvider,
+    ) -> eyre::Result<Option<ContractAbi>> {
+        match rpc.get_class_at(block_id, address).await? {
+            ContractClass::Sierra(class) => Ok(Some(ContractAbi::Sierra(class.abi))),
+            ContractClass::Legacy(class) => Ok(class.abi.map(ContractAbi::Legacy)),
+        }
+    }
+
+    /// Returns if a contract with given ABI is EIP721 compliant
+    /// This function is required as on Starknet ERC20 and ERC721 tokens have
+    /// same "Transfer" event key.
+    ///
+    /// # Errors
+    /// This function returns `eyre::ErrReport` if a Sierra ABI can't be parsed
+    pub fn is_erc721(abi: &ContractAbi) -> eyre::Result<bool> {
+        // Simplest check we can do is to check "ownerOf" function.
+        let names = abi.function_names()?;
+        Ok(names.iter().any(|name| name == "ownerOf" || name == "owner_of"))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_classifies_sierra_abis() {
+            let erc721 = r#"[
+                {"type": "impl", "name": "ERC721Impl", "interface_name": "IERC721"},
+                {"type": "interface", "name": "IERC721", "items": [
+                    {"type": "function", "name": "owner_of", "inputs": [], "outputs": [],
+                        "state_mutability": "view"}
+                ]},
+                {"type": "event", "name": "Transfer", "kind": "struct", "members": []}
+            ]"#;
+            let erc20 = r#"[
+                {"type": "function", "name": "balance_of", "inputs": [], "outputs": [],
+                    "state_mutability": "view"}
+            ]"#;
+
+            assert!(is_erc721(&ContractAbi::Sierra(erc721.to_string())).unwrap());
+            assert!(!is_erc721(&ContractAbi::Sierra(erc20.to_string())).unwrap());
+            assert!(is_erc721(&ContractAbi::Sierra("not an abi".to_string())).is_err());
+        }
     }
 }
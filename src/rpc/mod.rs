@@ -1,37 +1,168 @@
+pub mod batch;
+pub mod http;
+pub mod ipfs;
 pub mod metadata;
+pub mod pool;
+pub mod provider;
+pub mod record;
+pub mod retry;
+pub mod state;
+pub mod transport;
 
-use crate::common::{
-    errors::ConfigError,
-    starknet_constants::{TRANSFER_BATCH_EVENT_KEY, TRANSFER_EVENT_KEY, TRANSFER_SINGLE_EVENT_KEY},
+use crate::{
+    common::{
+        errors::ConfigError,
+        starknet_constants::{
+            TRANSFER_BATCH_EVENT_KEY, TRANSFER_EVENT_KEY, TRANSFER_SINGLE_EVENT_KEY,
+        },
+    },
+    events::EventFilter as ContractFilter,
 };
 use color_eyre::eyre::Result;
+use futures_util::future::join_all;
 use reqwest::Url;
 use starknet::{
-    core::types::{BlockId, EmittedEvent, EventFilter, EventsPage, FieldElement},
-    providers::{
-        jsonrpc::{HttpTransport, JsonRpcClient},
-        Provider,
-    },
+    core::types::{BlockId, EmittedEvent, EventFilter, FieldElement},
+    providers::jsonrpc::JsonRpcClient,
 };
-use std::env;
+use std::{env, str::FromStr, sync::Arc, time::Duration};
+
+use self::{
+    batch::{BatchConfig, BatchTransport},
+    pool::RpcPool,
+    provider::RpcProvider,
+    record::{Archive, RecordingTransport, ReplayTransport},
+    retry::{RetryPolicy, RetryProvider},
+    transport::{HttpRawTransport, LimitedTransport, RateLimiter, RateLimits, ShovelTransport},
+};
+
+pub type EndpointClient = JsonRpcClient<ShovelTransport>;
 
-pub struct StarknetRpc(JsonRpcClient<HttpTransport>);
+pub struct StarknetRpc<R = RetryProvider<RpcPool<EndpointClient>>>(R);
 
 impl StarknetRpc {
+    /// Connects to the endpoints in `STARKNET_MAINNET_RPC`, a comma separated list of
+    /// `<url>[|<weight>[|<requests per second>[|<max in flight>]]]`, retrying failed calls with
+    /// the default `RetryPolicy`. Missing or empty rate limits mean unlimited.
+    ///
+    /// Concurrent calls are batched as set by `RPC_BATCH_SIZE` and `RPC_BATCH_LINGER_MS`, see
+    /// `BatchConfig`
+    ///
+    /// `RPC_MODE=record` appends every response to the archive at `RPC_ARCHIVE`, and
+    /// `RPC_MODE=replay` answers calls from that archive only, see `record`
     pub fn mainnet() -> Result<Self, ConfigError> {
-        let rpc_url = env::var("STARKNET_MAINNET_RPC")?;
-        let parsed_url = Url::parse(&rpc_url)?;
-        Ok(Self(JsonRpcClient::new(HttpTransport::new(parsed_url))))
+        let archive = match env::var("RPC_MODE").as_deref() {
+            Err(_) | Ok("live") => None,
+            Ok("record") => Some(Arc::new(Archive::create(env::var("RPC_ARCHIVE")?)?)),
+            Ok("replay") => return Self::replay(env::var("RPC_ARCHIVE")?),
+            Ok(mode) => return Err(ConfigError::InvalidRpcMode(mode.to_string())),
+        };
+
+        let rpc_urls = env::var("STARKNET_MAINNET_RPC")?;
+
+        let default_batch = BatchConfig::default();
+        let batch_config = BatchConfig {
+            max_batch_size: parse_field(env::var("RPC_BATCH_SIZE").ok().as_deref())?
+                .unwrap_or(default_batch.max_batch_size),
+            linger: parse_field(env::var("RPC_BATCH_LINGER_MS").ok().as_deref())?
+                .map_or(default_batch.linger, Duration::from_millis),
+        };
+
+        let mut endpoints = Vec::new();
+        for endpoint in rpc_urls.split(',') {
+            let mut fields = endpoint.trim().split('|').map(str::trim);
+            let rpc_url = fields.next().unwrap_or_default();
+            let weight = parse_field(fields.next())?.unwrap_or(1);
+            let limits = RateLimits {
+                requests_per_second: parse_field(fields.next())?,
+                max_in_flight: parse_field(fields.next())?,
+            };
+
+            let parsed_url = Url::parse(rpc_url)?;
+            let limiter = Arc::new(RateLimiter::new(rpc_url.to_string(), limits));
+            let transport = BatchTransport::new(
+                HttpRawTransport::new(parsed_url, limiter.clone()),
+                batch_config,
+            );
+            // Rate limits are applied before batching, so every batched call counts against them
+            let transport = LimitedTransport::new(transport, limiter);
+            // Requests are recorded before they're batched, so they can be replayed one by one
+            let transport = match &archive {
+                Some(archive) => {
+                    ShovelTransport::new(RecordingTransport::new(transport, archive.clone()))
+                }
+                None => ShovelTransport::new(transport),
+            };
+            endpoints.push((rpc_url.to_string(), weight, JsonRpcClient::new(transport)));
+        }
+
+        Ok(Self(RetryProvider::new(RpcPool::new(endpoints), RetryPolicy::default())))
     }
 
-    pub fn inner(&self) -> &JsonRpcClient<HttpTransport> {
+    /// Answers calls from a recorded archive without touching the network. A call that wasn't
+    /// recorded fails right away instead of being retried.
+    pub fn replay(archive_path: String) -> Result<Self, ConfigError> {
+        println!("[rpc] replaying {archive_path}");
+        let transport = ShovelTransport::new(ReplayTransport::load(&archive_path)?);
+        let endpoints = vec![(archive_path, 1, JsonRpcClient::new(transport))];
+        let policy = RetryPolicy { max_attempts: 1, ..RetryPolicy::default() };
+
+        Ok(Self(RetryProvider::new(RpcPool::new(endpoints), policy)))
+    }
+}
+
+/// Whether calls are answered from a recorded archive, `RPC_MODE=replay`. The run stops at the
+/// last recorded head and nothing else is fetched from the network then, see `http`.
+pub fn is_replay() -> bool {
+    env::var("RPC_MODE").is_ok_and(|mode| mode == "replay")
+}
+
+// Parses an optional setting, empty ones are skipped
+fn parse_field<T: FromStr>(field: Option<&str>) -> Result<Option<T>, T::Err> {
+    field.filter(|field| !field.is_empty()).map(str::parse).transpose()
+}
+
+impl<R: RpcProvider> StarknetRpc<R> {
+    pub fn inner(&self) -> &R {
         &self.0
     }
 
+    /// Gets transfer events between `start_block` and `start_block + range`
+    ///
+    /// With a whitelist, events of every whitelisted contract are requested on their own and in
+    /// parallel, then merged by block. Events of a contract keep their order, events of different
+    /// contracts in the same block are ordered by whitelist.
     pub async fn get_transfer_events(
         &self,
         start_block: u64,
         range: u64,
+        filter: &ContractFilter<'_>,
+    ) -> Result<Vec<EmittedEvent>> {
+        let ContractFilter::Whitelist(addresses) = filter else {
+            return self.get_contract_transfer_events(None, start_block, range).await;
+        };
+
+        let requests = addresses
+            .iter()
+            .map(|address| self.get_contract_transfer_events(Some(*address), start_block, range));
+        let contract_events = join_all(requests).await;
+
+        let mut events = Vec::new();
+        for contract_events in contract_events {
+            events.append(&mut contract_events?);
+        }
+        // Stable, so events of a contract stay in order
+        events.sort_by_key(|event| event.block_number);
+
+        Ok(events)
+    }
+
+    /// Gets transfer events of a contract, or of every contract if no address is given
+    async fn get_contract_transfer_events(
+        &self,
+        address: Option<FieldElement>,
+        start_block: u64,
+        range: u64,
     ) -> Result<Vec<EmittedEvent>> {
         let keys: Vec<FieldElement> =
             Vec::from([TRANSFER_EVENT_KEY, TRANSFER_SINGLE_EVENT_KEY, TRANSFER_BATCH_EVENT_KEY]);
@@ -39,33 +170,21 @@ impl StarknetRpc {
         let transfer_filter = EventFilter {
             from_block: Some(BlockId::Number(start_block)),
             to_block: Some(BlockId::Number(start_block + range)),
-            address: None,
+            address,
             keys: Some(vec![keys]),
         };
 
         let mut continuation_token: Option<String> = None;
         let chunk_size: u64 = 1024;
 
-        let mut get_events_resp: EventsPage;
         let mut events: Vec<EmittedEvent> = Vec::new();
-        let mut tries = 0;
 
         loop {
-            // TODO: Loop until we get a response from the rpc client, it is reasonable
-            // to add a MAX_ITER here
-            get_events_resp = match self
+            // Retries, if any, are up to the provider
+            let mut get_events_resp = self
                 .0
                 .get_events(transfer_filter.clone(), continuation_token.clone(), chunk_size)
-                .await
-            {
-                Ok(events_response) => events_response,
-                Err(e) => {
-                    eprintln!("Error: {e}");
-                    tries += 1;
-                    println!("[rpc] error while getting events, retrying #{tries}");
-                    continue;
-                }
-            };
+                .await?;
 
             println!("[rpc] got {} events", get_events_resp.events.len());
             events.append(&mut get_events_resp.events);
@@ -0,0 +1,282 @@
+//! Spreads RPC calls over several weighted endpoints, skipping unhealthy ones and failing over
+//! to the next endpoint when one can't answer
+use async_trait::async_trait;
+use color_eyre::eyre;
+use starknet::core::types::{
+    BlockId, ContractClass, EventFilter, EventsPage, FieldElement, FunctionCall,
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    provider::{is_transient, RpcProvider},
+    transport::measure_limiter_wait,
+};
+
+// Weight of the latest call in error rate and latency averages
+const HEALTH_SMOOTHING: f64 = 0.2;
+// Endpoints failing more often than this are unhealthy
+const MAX_ERROR_RATE: f64 = 0.5;
+// Endpoints slower than this on average are unhealthy
+const MAX_LATENCY: Duration = Duration::from_secs(10);
+// Endpoints this many blocks behind the highest head we've seen are unhealthy
+const MAX_HEAD_LAG: u64 = 5;
+// How often every endpoint's head is checked, unhealthy endpoints recover through these checks
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Default)]
+struct Health {
+    // Moving averages of failed calls (0 to 1) and call latency in seconds
+    error_rate: f64,
+    latency: f64,
+    head: Option<u64>,
+}
+
+struct Endpoint<P> {
+    url: String,
+    weight: usize,
+    provider: P,
+    health: Mutex<Health>,
+}
+
+impl<P: RpcProvider> Endpoint<P> {
+    /// Gets the latest block number and keeps it as the head of the endpoint
+    async fn block_number(&self) -> eyre::Result<u64> {
+        let head = self.provider.block_number().await?;
+        self.health.lock().unwrap().head = Some(head);
+        Ok(head)
+    }
+}
+
+pub struct RpcPool<P> {
+    endpoints: Vec<Endpoint<P>>,
+    // Endpoint indices, every endpoint repeated by its weight
+    schedule: Vec<usize>,
+    cursor: AtomicUsize,
+}
+
+impl<P: RpcProvider> RpcPool<P> {
+    /// Creates a pool of `(url, weight, provider)` endpoints, the url is only used for logging
+    ///
+    /// # Panics
+    /// This function panics if no endpoints are given
+    pub fn new(endpoints: Vec<(String, usize, P)>) -> Self {
+        assert!(!endpoints.is_empty(), "RPC pool needs at least one endpoint");
+
+        let endpoints: Vec<Endpoint<P>> = endpoints
+            .into_iter()
+            .map(|(url, weight, provider)| Endpoint {
+                url,
+                weight: weight.max(1),
+                provider,
+                health: Mutex::new(Health::default()),
+            })
+            .collect();
+
+        let schedule = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(index, endpoint)| std::iter::repeat(index).take(endpoint.weight))
+            .collect();
+
+        Self { endpoints, schedule, cursor: AtomicUsize::new(0) }
+    }
+
+    /// Checks the head of every endpoint forever, keeps lag up to date and lets endpoints
+    /// marked unhealthy recover once they answer again
+    pub async fn monitor(&self) {
+        loop {
+            for endpoint in &self.endpoints {
+                let started_at = Instant::now();
+                let (result, waited) = measure_limiter_wait(endpoint.block_number()).await;
+                self.record(endpoint, started_at.elapsed().saturating_sub(waited), &result);
+            }
+
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Highest head any endpoint reported
+    fn head(&self) -> Option<u64> {
+        self.endpoints.iter().filter_map(|endpoint| endpoint.health.lock().unwrap().head).max()
+    }
+
+    fn is_healthy(&self, endpoint: &Endpoint<P>, head: Option<u64>) -> bool {
+        let health = endpoint.health.lock().unwrap();
+        let lag = match (head, health.head) {
+            (Some(head), Some(endpoint_head)) => head.saturating_sub(endpoint_head),
+            _ => 0,
+        };
+
+        health.error_rate <= MAX_ERROR_RATE
+            && health.latency <= MAX_LATENCY.as_secs_f64()
+            && lag <= MAX_HEAD_LAG
+    }
+
+    /// Returns endpoint indices in the order they should be tried, the next ones in the weighted
+    /// round robin first. Unhealthy endpoints come last, in case every endpoint is unhealthy.
+    fn route(&self) -> Vec<usize> {
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let mut order = Vec::with_capacity(self.endpoints.len());
+        for offset in 0..self.schedule.len() {
+            let index = self.schedule[(start + offset) % self.schedule.len()];
+            if !order.contains(&index) {
+                order.push(index);
+            }
+        }
+
+        let head = self.head();
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) =
+            order.into_iter().partition(|index| self.is_healthy(&self.endpoints[*index], head));
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Updates health of an endpoint after a call that took `latency`, not counting time spent
+    /// waiting for rate limits. Errors the node answered with count as successful calls.
+    fn record<T>(&self, endpoint: &Endpoint<P>, latency: Duration, result: &eyre::Result<T>) {
+        let failed = matches!(result, Err(e) if is_transient(e));
+        let latency = latency.as_secs_f64();
+
+        let mut health = endpoint.health.lock().unwrap();
+        health.error_rate += HEALTH_SMOOTHING * (f64::from(u8::from(failed)) - health.error_rate);
+        if !failed {
+            health.latency += HEALTH_SMOOTHING * (latency - health.latency);
+        }
+    }
+
+    /// Runs `request` on endpoints in routing order until one of them answers
+    async fn request<'a, T, F, Fut>(&'a self, request: F) -> eyre::Result<T>
+    where
+        F: Fn(&'a Endpoint<P>) -> Fut,
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        let mut last_error = None;
+
+        for index in self.route() {
+            let endpoint = &self.endpoints[index];
+            let started_at = Instant::now();
+            let (result, waited) = measure_limiter_wait(request(endpoint)).await;
+            self.record(endpoint, started_at.elapsed().saturating_sub(waited), &result);
+
+            match result {
+                Err(e) if is_transient(&e) => {
+                    println!("[rpc] {} failed, failing over: {e}", endpoint.url);
+                    last_error = Some(e);
+                }
+                result => return result,
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| eyre::eyre!("no rpc endpoints")))
+    }
+}
+
+#[async_trait]
+impl<P: RpcProvider> RpcProvider for RpcPool<P> {
+    async fn block_number(&self) -> eyre::Result<u64> {
+        self.request(Endpoint::block_number).await
+    }
+
+    async fn get_events(
+        &self,
+        filter: EventFilter,
+        continuation_token: Option<String>,
+        chunk_size: u64,
+    ) -> eyre::Result<EventsPage> {
+        self.request(|endpoint| {
+            endpoint.provider.get_events(filter.clone(), continuation_token.clone(), chunk_size)
+        })
+        .await
+    }
+
+    async fn call(
+        &self,
+        request: FunctionCall,
+        block_id: &BlockId,
+    ) -> eyre::Result<Vec<FieldElement>> {
+        self.request(|endpoint| endpoint.provider.call(request.clone(), block_id)).await
+    }
+
+    async fn get_class_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<ContractClass> {
+        self.request(|endpoint| endpoint.provider.get_class_at(block_id, contract_address)).await
+    }
+
+    async fn get_class_hash_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<FieldElement> {
+        self.request(|endpoint| endpoint.provider.get_class_hash_at(block_id, contract_address))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::provider::mock::MockProvider;
+
+    #[tokio::test]
+    async fn test_fails_over_unreachable_endpoint() {
+        let contract = FieldElement::from(1_u32);
+        let selector = FieldElement::from(2_u32);
+        let healthy = MockProvider { block_number: 10, ..MockProvider::default() }.with_call(
+            contract,
+            selector,
+            vec![],
+            vec![FieldElement::ONE],
+        );
+        let unreachable = MockProvider { unreachable: true, ..MockProvider::default() };
+        let pool = RpcPool::new(vec![
+            ("unreachable".to_string(), 1, unreachable),
+            ("healthy".to_string(), 1, healthy),
+        ]);
+
+        let request = FunctionCall {
+            contract_address: contract,
+            entry_point_selector: selector,
+            calldata: vec![],
+        };
+        // Every other call starts with the unreachable endpoint
+        for _ in 0..8 {
+            let result = pool.call(request.clone(), &BlockId::Number(1)).await.unwrap();
+            assert_eq!(result, vec![FieldElement::ONE]);
+        }
+
+        // Unreachable endpoint is tried last once it's unhealthy
+        assert!(pool.endpoints[0].health.lock().unwrap().error_rate > MAX_ERROR_RATE);
+        assert_eq!(pool.route(), vec![1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_node_errors_dont_fail_over() {
+        let pool = RpcPool::new(vec![
+            ("first".to_string(), 1, MockProvider::default()),
+            ("second".to_string(), 1, MockProvider::default()),
+        ]);
+
+        let request = FunctionCall {
+            contract_address: FieldElement::ONE,
+            entry_point_selector: FieldElement::ONE,
+            calldata: vec![],
+        };
+        let error = pool.call(request, &BlockId::Number(1)).await.unwrap_err();
+
+        assert!(!is_transient(&error));
+        assert!(pool
+            .endpoints
+            .iter()
+            .all(|endpoint| endpoint.health.lock().unwrap().error_rate < f64::EPSILON));
+    }
+}
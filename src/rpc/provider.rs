@@ -0,0 +1,258 @@
+//! The subset of starknet RPC shovel uses
+//!
+//! Everything that talks to the chain takes an `RpcProvider` instead of a concrete client, so
+//! the JSON-RPC client, the sequencer gateway, wrappers around them or an in-memory mock can be
+//! plugged in.
+use async_trait::async_trait;
+use color_eyre::eyre;
+use futures_util::future::join_all;
+use starknet::{
+    core::types::{BlockId, ContractClass, EventFilter, EventsPage, FieldElement, FunctionCall},
+    providers::{jsonrpc::JsonRpcClientError, Provider, ProviderError},
+};
+use std::any::Any;
+
+use crate::common::errors::{RpcError, TransportError};
+
+/// Errors the node answers with are returned as `RpcError::Starknet`, failures that may go away
+/// when asking again as `RpcError::Unavailable`. Anything else, e.g. a malformed response, is
+/// permanent.
+#[async_trait]
+pub trait RpcProvider: Send + Sync {
+    /// Gets the number of the latest block
+    async fn block_number(&self) -> eyre::Result<u64>;
+
+    /// Gets a page of events matching the filter
+    async fn get_events(
+        &self,
+        filter: EventFilter,
+        continuation_token: Option<String>,
+        chunk_size: u64,
+    ) -> eyre::Result<EventsPage>;
+
+    /// Calls a view function at given block
+    async fn call(
+        &self,
+        request: FunctionCall,
+        block_id: &BlockId,
+    ) -> eyre::Result<Vec<FieldElement>>;
+
+    /// Gets the class deployed at given address
+    async fn get_class_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<ContractClass>;
+
+    /// Gets the hash of the class deployed at given address
+    async fn get_class_hash_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<FieldElement>;
+}
+
+#[async_trait]
+impl<P> RpcProvider for P
+where
+    P: Provider + Send + Sync,
+    P::Error: 'static,
+{
+    async fn block_number(&self) -> eyre::Result<u64> {
+        Provider::block_number(self).await.map_err(into_report)
+    }
+
+    async fn get_events(
+        &self,
+        filter: EventFilter,
+        continuation_token: Option<String>,
+        chunk_size: u64,
+    ) -> eyre::Result<EventsPage> {
+        Provider::get_events(self, filter, continuation_token, chunk_size)
+            .await
+            .map_err(into_report)
+    }
+
+    async fn call(
+        &self,
+        request: FunctionCall,
+        block_id: &BlockId,
+    ) -> eyre::Result<Vec<FieldElement>> {
+        Provider::call(self, request, block_id).await.map_err(into_report)
+    }
+
+    async fn get_class_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<ContractClass> {
+        Provider::get_class_at(self, block_id, contract_address).await.map_err(into_report)
+    }
+
+    async fn get_class_hash_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<FieldElement> {
+        Provider::get_class_hash_at(self, block_id, contract_address).await.map_err(into_report)
+    }
+}
+
+/// Whether asking again, or asking another node, might succeed: timeouts, rate limits, server
+/// errors and connection failures
+pub fn is_transient(error: &eyre::Report) -> bool {
+    matches!(error.downcast_ref::<RpcError>(), Some(RpcError::Unavailable(_)))
+}
+
+/// Whether the node answered with an error, e.g. a contract missing the entry point we called
+pub fn is_node_error(error: &eyre::Report) -> bool {
+    matches!(error.downcast_ref::<RpcError>(), Some(RpcError::Starknet(_)))
+}
+
+/// Turns errors the node answered with into `None` and keeps any other
+pub fn optional<T>(result: eyre::Result<T>) -> eyre::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if is_node_error(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Calls every selector at once, so the calls can share a batch, and returns the result of the
+/// first one in order the contract responds to. Any other error than the node's of an earlier
+/// selector is returned instead, as we can't tell if the contract would have responded to it.
+///
+/// # Errors
+/// This function returns the error of the last selector if the contract responds to none
+pub async fn call_first(
+    address: FieldElement,
+    selectors: &[FieldElement],
+    calldata: &[FieldElement],
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<Vec<FieldElement>> {
+    let calls = selectors.iter().map(|selector| {
+        let request = FunctionCall {
+            contract_address: address,
+            entry_point_selector: *selector,
+            calldata: calldata.to_vec(),
+        };
+        rpc.call(request, block_id)
+    });
+
+    let mut last_error = eyre::eyre!("no selectors to call");
+    for result in join_all(calls).await {
+        match result {
+            Ok(result) => return Ok(result),
+            Err(e) if is_node_error(&e) => last_error = e,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error)
+}
+
+fn into_report<E>(error: ProviderError<E>) -> eyre::Report
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let transient = match &error {
+        ProviderError::StarknetError(_) => return RpcError::Starknet(error.to_string()).into(),
+        ProviderError::Other(e) => {
+            match (e as &dyn Any).downcast_ref::<JsonRpcClientError<TransportError>>() {
+                Some(JsonRpcClientError::TransportError(e)) => e.is_transient(),
+                _ => false,
+            }
+        }
+        _ => false,
+    };
+
+    if transient {
+        RpcError::Unavailable(error.to_string()).into()
+    } else {
+        error.into()
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use async_trait::async_trait;
+    use color_eyre::eyre;
+    use starknet::core::types::{
+        BlockId, ContractClass, EventFilter, EventsPage, FieldElement, FunctionCall,
+    };
+    use std::collections::HashMap;
+
+    use super::RpcProvider;
+    use crate::common::errors::RpcError;
+
+    /// Answers calls from a fixed `(contract, selector, calldata) -> result` table, any other
+    /// call fails like a reverted one would. An `unreachable` mock fails every request.
+    #[derive(Debug, Default)]
+    pub struct MockProvider {
+        pub block_number: u64,
+        pub calls: HashMap<(FieldElement, FieldElement, Vec<FieldElement>), Vec<FieldElement>>,
+        pub unreachable: bool,
+    }
+
+    impl MockProvider {
+        pub fn with_call(
+            mut self,
+            contract_address: FieldElement,
+            selector: FieldElement,
+            calldata: Vec<FieldElement>,
+            result: Vec<FieldElement>,
+        ) -> Self {
+            self.calls.insert((contract_address, selector, calldata), result);
+            self
+        }
+    }
+
+    #[async_trait]
+    impl RpcProvider for MockProvider {
+        async fn block_number(&self) -> eyre::Result<u64> {
+            if self.unreachable {
+                return Err(RpcError::Unavailable("connection refused".to_string()).into());
+            }
+            Ok(self.block_number)
+        }
+
+        async fn get_events(
+            &self,
+            _filter: EventFilter,
+            _continuation_token: Option<String>,
+            _chunk_size: u64,
+        ) -> eyre::Result<EventsPage> {
+            Ok(EventsPage { events: vec![], continuation_token: None })
+        }
+
+        async fn call(
+            &self,
+            request: FunctionCall,
+            _block_id: &BlockId,
+        ) -> eyre::Result<Vec<FieldElement>> {
+            if self.unreachable {
+                return Err(RpcError::Unavailable("connection refused".to_string()).into());
+            }
+            let key = (request.contract_address, request.entry_point_selector, request.calldata);
+            let result = self.calls.get(&key).cloned();
+            result.ok_or_else(|| RpcError::Starknet("entry point not found".to_string()).into())
+        }
+
+        async fn get_class_at(
+            &self,
+            _block_id: &BlockId,
+            contract_address: FieldElement,
+        ) -> eyre::Result<ContractClass> {
+            Err(RpcError::Starknet(format!("no class at {contract_address:#x}")).into())
+        }
+
+        async fn get_class_hash_at(
+            &self,
+            _block_id: &BlockId,
+            contract_address: FieldElement,
+        ) -> eyre::Result<FieldElement> {
+            Err(RpcError::Starknet(format!("no class at {contract_address:#x}")).into())
+        }
+    }
+}
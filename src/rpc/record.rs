@@ -0,0 +1,179 @@
+//! Records RPC traffic into an archive and replays it without a network, so an indexing run can
+//! be reproduced exactly
+//!
+//! The archive is a JSON lines file of `{"request": ..., "response": ...}` pairs, requests
+//! stripped of their id. Replaying a request answers it with its recorded responses in order,
+//! the last one is repeated once they run out, e.g. for the head polled over and over.
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use super::transport::RawTransport;
+use crate::common::errors::TransportError;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Exchange {
+    request: Value,
+    response: Value,
+}
+
+/// Identifies a request regardless of its id
+fn request_key(request: &Value) -> Value {
+    json!({"method": request["method"], "params": request["params"]})
+}
+
+/// Appends exchanges to an archive, shared by the recording transports of every endpoint
+pub struct Archive(Mutex<File>);
+
+impl Archive {
+    /// Opens the archive at `path` for appending, creating it if needed
+    ///
+    /// # Errors
+    /// This function returns an error if the file can't be opened
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(Mutex::new(file)))
+    }
+
+    fn append(&self, exchange: &Exchange) -> Result<(), TransportError> {
+        let line = serde_json::to_string(exchange)?;
+        let mut file = self.0.lock().unwrap();
+        writeln!(file, "{line}").map_err(|e| TransportError::Archive(e.to_string()))
+    }
+}
+
+/// Sends requests on and records every response into the archive, failed requests aren't
+/// recorded
+pub struct RecordingTransport<T> {
+    transport: T,
+    archive: Arc<Archive>,
+}
+
+impl<T: RawTransport> RecordingTransport<T> {
+    pub fn new(transport: T, archive: Arc<Archive>) -> Self {
+        Self { transport, archive }
+    }
+}
+
+#[async_trait]
+impl<T: RawTransport> RawTransport for RecordingTransport<T> {
+    async fn send(&self, request: Value) -> Result<Value, TransportError> {
+        let response = self.transport.send(request.clone()).await?;
+
+        let mut recorded = response.clone();
+        if let Some(response) = recorded.as_object_mut() {
+            response.remove("id");
+        }
+        self.archive.append(&Exchange { request: request_key(&request), response: recorded })?;
+
+        Ok(response)
+    }
+}
+
+/// Answers requests from an archive, requests that weren't recorded fail
+pub struct ReplayTransport {
+    responses: Mutex<HashMap<String, VecDeque<Value>>>,
+}
+
+impl ReplayTransport {
+    /// Loads every exchange of the archive at `path`
+    ///
+    /// # Errors
+    /// This function returns an error if the file can't be read or a line isn't an exchange
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut responses: HashMap<String, VecDeque<Value>> = HashMap::new();
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let exchange: Exchange = serde_json::from_str(&line)?;
+            let key = request_key(&exchange.request).to_string();
+            responses.entry(key).or_default().push_back(exchange.response);
+        }
+
+        Ok(Self { responses: Mutex::new(responses) })
+    }
+}
+
+#[async_trait]
+impl RawTransport for ReplayTransport {
+    async fn send(&self, request: Value) -> Result<Value, TransportError> {
+        let mut responses = self.responses.lock().unwrap();
+        let recorded = responses.get_mut(&request_key(&request).to_string());
+
+        let mut response = match recorded {
+            Some(recorded) if recorded.len() > 1 => recorded.pop_front().unwrap(),
+            Some(recorded) if !recorded.is_empty() => recorded[0].clone(),
+            _ => {
+                let method = request["method"].as_str().unwrap_or_default();
+                return Err(TransportError::Archive(format!("{method} request wasn't recorded")));
+            }
+        };
+
+        if let Some(response) = response.as_object_mut() {
+            response.insert("id".to_string(), request["id"].clone());
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Answers every request with an increasing counter
+    #[derive(Default)]
+    struct CounterTransport(AtomicU64);
+
+    #[async_trait]
+    impl RawTransport for CounterTransport {
+        async fn send(&self, request: Value) -> Result<Value, TransportError> {
+            let count = self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(json!({"id": request["id"], "jsonrpc": "2.0", "result": count}))
+        }
+    }
+
+    fn request(id: u64, method: &str) -> Value {
+        json!({"id": id, "jsonrpc": "2.0", "method": method, "params": []})
+    }
+
+    #[tokio::test]
+    async fn test_replays_recorded_responses_in_order() {
+        let path = std::env::temp_dir().join(format!("shovel-archive-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recording = RecordingTransport::new(
+            CounterTransport::default(),
+            Arc::new(Archive::create(&path).unwrap()),
+        );
+        for _ in 0..2 {
+            recording.send(request(1, "starknet_blockNumber")).await.unwrap();
+        }
+        recording.send(request(1, "starknet_chainId")).await.unwrap();
+
+        let replay = ReplayTransport::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let result = |response: Value| response["result"].as_u64().unwrap();
+        assert_eq!(result(replay.send(request(7, "starknet_blockNumber")).await.unwrap()), 0);
+        assert_eq!(result(replay.send(request(8, "starknet_blockNumber")).await.unwrap()), 1);
+        // The last response is repeated
+        let response = replay.send(request(9, "starknet_blockNumber")).await.unwrap();
+        assert_eq!(response["id"], 9);
+        assert_eq!(result(response), 1);
+        assert_eq!(result(replay.send(request(1, "starknet_chainId")).await.unwrap()), 2);
+
+        assert!(replay.send(request(1, "starknet_syncing")).await.is_err());
+    }
+}
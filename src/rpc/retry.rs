@@ -0,0 +1,178 @@
+//! Retries transient RPC errors with exponential backoff, see `provider::is_transient`
+use async_trait::async_trait;
+use color_eyre::eyre;
+use rand::Rng;
+use starknet::core::types::{
+    BlockId, ContractClass, EventFilter, EventsPage, FieldElement, FunctionCall,
+};
+use std::{future::Future, time::Duration};
+
+use super::provider::{is_transient, RpcProvider};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts made before giving up, including the first one
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry, doubled for every retry after it
+    pub base_delay: Duration,
+    /// Upper bound of any delay
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs `attempt` until it succeeds, fails with a permanent error or runs out of attempts
+    ///
+    /// # Errors
+    /// This function returns the error of the last attempt
+    pub async fn run<T, F, Fut>(&self, attempt: F) -> eyre::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            match attempt().await {
+                Err(e) if is_transient(&e) && attempts < self.max_attempts => {
+                    let delay = self.delay(attempts);
+                    println!("[rpc] attempt #{attempts} failed, retrying in {delay:?}: {e}");
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Random delay before retry #`retry`, up to `base_delay * 2^(retry - 1)` ("full jitter")
+    fn delay(&self, retry: u32) -> Duration {
+        let ceiling = self.base_delay.saturating_mul(2_u32.saturating_pow(retry - 1));
+        let ceiling = ceiling.min(self.max_delay);
+
+        rand::thread_rng().gen_range(Duration::ZERO..=ceiling)
+    }
+}
+
+/// Wraps a provider so that every call is retried according to a `RetryPolicy`
+pub struct RetryProvider<P> {
+    provider: P,
+    policy: RetryPolicy,
+}
+
+impl<P: RpcProvider> RetryProvider<P> {
+    pub fn new(provider: P, policy: RetryPolicy) -> Self {
+        Self { provider, policy }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.provider
+    }
+}
+
+#[async_trait]
+impl<P: RpcProvider> RpcProvider for RetryProvider<P> {
+    async fn block_number(&self) -> eyre::Result<u64> {
+        self.policy.run(|| self.provider.block_number()).await
+    }
+
+    async fn get_events(
+        &self,
+        filter: EventFilter,
+        continuation_token: Option<String>,
+        chunk_size: u64,
+    ) -> eyre::Result<EventsPage> {
+        self.policy
+            .run(|| {
+                self.provider.get_events(filter.clone(), continuation_token.clone(), chunk_size)
+            })
+            .await
+    }
+
+    async fn call(
+        &self,
+        request: FunctionCall,
+        block_id: &BlockId,
+    ) -> eyre::Result<Vec<FieldElement>> {
+        self.policy.run(|| self.provider.call(request.clone(), block_id)).await
+    }
+
+    async fn get_class_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<ContractClass> {
+        self.policy.run(|| self.provider.get_class_at(block_id, contract_address)).await
+    }
+
+    async fn get_class_hash_at(
+        &self,
+        block_id: &BlockId,
+        contract_address: FieldElement,
+    ) -> eyre::Result<FieldElement> {
+        self.policy.run(|| self.provider.get_class_hash_at(block_id, contract_address)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::provider::mock::MockProvider;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy { max_delay: Duration::from_secs(1), ..RetryPolicy::default() };
+
+        for retry in 1..40 {
+            assert!(policy.delay(retry) <= Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors_only() {
+        let attempts = AtomicU32::new(0);
+        let unreachable = MockProvider { unreachable: true, ..MockProvider::default() };
+        let result = policy()
+            .run(|| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                unreachable.block_number()
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        let attempts = AtomicU32::new(0);
+        let request = FunctionCall {
+            contract_address: FieldElement::ONE,
+            entry_point_selector: FieldElement::ONE,
+            calldata: vec![],
+        };
+        let reachable = MockProvider::default();
+        let result = policy()
+            .run(|| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                reachable.call(request.clone(), &BlockId::Number(1))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+}
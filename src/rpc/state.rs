@@ -0,0 +1,119 @@
+//! On-chain token state reads, used to bootstrap tokens whose history we didn't index
+use color_eyre::eyre;
+use starknet::{
+    core::types::{BlockId, FieldElement},
+    macros::selector,
+};
+
+use crate::{
+    common::types::CairoUint256,
+    rpc::provider::{call_first, RpcProvider},
+};
+
+/// Gets the owner of an ERC721 token, `ownerOf(uint256)` | `owner_of(uint256)`
+pub async fn owner_of(
+    address: FieldElement,
+    token_id: CairoUint256,
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<FieldElement> {
+    let selectors = [selector!("ownerOf"), selector!("owner_of")];
+    let calldata = [token_id.low, token_id.high];
+    let result = call_first(address, &selectors, &calldata, block_id, rpc).await?;
+
+    result.first().copied().ok_or(eyre::eyre!("empty ownerOf response"))
+}
+
+/// Gets the ERC1155 balance of `account` for a token id,
+/// `balanceOf(account, uint256)` | `balance_of(account, uint256)`
+pub async fn balance_of(
+    address: FieldElement,
+    account: FieldElement,
+    token_id: CairoUint256,
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<CairoUint256> {
+    let selectors = [selector!("balanceOf"), selector!("balance_of")];
+    let calldata = [account, token_id.low, token_id.high];
+    let result = call_first(address, &selectors, &calldata, block_id, rpc).await?;
+
+    into_uint256(&result)
+}
+
+/// Gets the total supply of an ERC721Enumerable contract, `totalSupply()` | `total_supply()`
+pub async fn total_supply(
+    address: FieldElement,
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<CairoUint256> {
+    let selectors = [selector!("totalSupply"), selector!("total_supply")];
+    let result = call_first(address, &selectors, &[], block_id, rpc).await?;
+
+    into_uint256(&result)
+}
+
+/// Gets the token id at given index of an ERC721Enumerable contract,
+/// `tokenByIndex(uint256)` | `token_by_index(uint256)`
+pub async fn token_by_index(
+    address: FieldElement,
+    index: CairoUint256,
+    block_id: &BlockId,
+    rpc: &impl RpcProvider,
+) -> eyre::Result<CairoUint256> {
+    let selectors = [selector!("tokenByIndex"), selector!("token_by_index")];
+    let calldata = [index.low, index.high];
+    let result = call_first(address, &selectors, &calldata, block_id, rpc).await?;
+
+    into_uint256(&result)
+}
+
+/// Reads a uint256 out of a call result, a single felt is taken as the low part
+fn into_uint256(result: &[FieldElement]) -> eyre::Result<CairoUint256> {
+    match result {
+        [low, high, ..] => Ok(CairoUint256::new(*low, *high)),
+        [low] => Ok(CairoUint256::new(*low, FieldElement::ZERO)),
+        [] => eyre::bail!("empty uint256 response"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::provider::mock::MockProvider;
+
+    #[tokio::test]
+    async fn test_owner_of_falls_back_to_snake_case() {
+        let contract = FieldElement::from(1_u32);
+        let token_id = CairoUint256::new(FieldElement::from(7_u32), FieldElement::ZERO);
+        let owner = FieldElement::from(42_u32);
+        let rpc = MockProvider::default().with_call(
+            contract,
+            selector!("owner_of"),
+            vec![token_id.low, token_id.high],
+            vec![owner],
+        );
+
+        let result = owner_of(contract, token_id, &BlockId::Number(1), &rpc).await.unwrap();
+        assert_eq!(result, owner);
+
+        let other_token = CairoUint256::new(FieldElement::from(8_u32), FieldElement::ZERO);
+        assert!(owner_of(contract, other_token, &BlockId::Number(1), &rpc).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_balance_of_single_felt() {
+        let contract = FieldElement::from(1_u32);
+        let account = FieldElement::from(2_u32);
+        let token_id = CairoUint256::new(FieldElement::from(7_u32), FieldElement::ZERO);
+        let rpc = MockProvider::default().with_call(
+            contract,
+            selector!("balanceOf"),
+            vec![account, token_id.low, token_id.high],
+            vec![FieldElement::from(5_u32)],
+        );
+
+        let result =
+            balance_of(contract, account, token_id, &BlockId::Number(1), &rpc).await.unwrap();
+        assert_eq!(result, CairoUint256::new(FieldElement::from(5_u32), FieldElement::ZERO));
+    }
+}
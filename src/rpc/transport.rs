@@ -0,0 +1,360 @@
+//! JSON-RPC transport that keeps every endpoint within its request quota
+//!
+//! `ShovelTransport` plugs into starknet's `JsonRpcClient` and hands requests as plain JSON to a
+//! `RawTransport`, so the wire can be swapped without touching the client.
+//!
+//! `LimitedTransport` sits in front of batching, so every call counts against the rate limits
+//! whether it's batched or not, and waits in the task that made the call. That task can measure
+//! the wait with `measure_limiter_wait`.
+use async_trait::async_trait;
+use reqwest::{header, Client, StatusCode, Url};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use starknet::providers::jsonrpc::{JsonRpcMethod, JsonRpcResponse, JsonRpcTransport};
+use std::{
+    cell::Cell,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::common::errors::TransportError;
+
+// Wait before retrying a 429 response that doesn't say how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+// How often rate limiter wait times are logged
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+// Time a request can take once the rate limiter let it through
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+tokio::task_local! {
+    // Time calls of the current task spent waiting for rate limits
+    static LIMITER_WAIT: Cell<Duration>;
+}
+
+/// Runs `future` and returns its output along with the time its calls spent waiting for rate
+/// limits, e.g. to tell the latency of an endpoint apart from our own throttling
+pub async fn measure_limiter_wait<F: Future>(future: F) -> (F::Output, Duration) {
+    LIMITER_WAIT
+        .scope(Cell::new(Duration::ZERO), async move {
+            let output = future.await;
+            (output, LIMITER_WAIT.with(Cell::get))
+        })
+        .await
+}
+
+/// Sends a JSON-RPC request and returns the response, both as plain JSON
+#[async_trait]
+pub trait RawTransport: Send + Sync {
+    async fn send(&self, request: Value) -> Result<Value, TransportError>;
+}
+
+#[derive(Serialize)]
+struct JsonRpcRequest<P> {
+    id: u64,
+    jsonrpc: &'static str,
+    method: JsonRpcMethod,
+    params: P,
+}
+
+pub struct ShovelTransport(Box<dyn RawTransport>);
+
+impl ShovelTransport {
+    pub fn new(transport: impl RawTransport + 'static) -> Self {
+        Self(Box::new(transport))
+    }
+}
+
+#[async_trait]
+impl JsonRpcTransport for ShovelTransport {
+    type Error = TransportError;
+
+    async fn send_request<P, R>(
+        &self,
+        method: JsonRpcMethod,
+        params: P,
+    ) -> Result<JsonRpcResponse<R>, Self::Error>
+    where
+        P: Serialize + Send,
+        R: DeserializeOwned,
+    {
+        let request = JsonRpcRequest { id: 1, jsonrpc: "2.0", method, params };
+        let response = self.0.send(serde_json::to_value(&request)?).await?;
+        Ok(serde_json::from_value(response)?)
+    }
+}
+
+/// Limits of a single endpoint, `None` means unlimited
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RateLimits {
+    pub requests_per_second: Option<u32>,
+    pub max_in_flight: Option<u32>,
+}
+
+#[derive(Debug)]
+struct WaitMetrics {
+    since: Instant,
+    requests: u32,
+    total_wait: Duration,
+    max_wait: Duration,
+}
+
+impl WaitMetrics {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            requests: 0,
+            total_wait: Duration::ZERO,
+            max_wait: Duration::ZERO,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    // Set by a 429 response, nothing is sent before then
+    paused_until: Option<Instant>,
+}
+
+/// Token bucket holding up to one second worth of requests, plus a cap on in-flight requests,
+/// shared by the transports of an endpoint
+pub struct RateLimiter {
+    url: String,
+    requests_per_second: Option<f64>,
+    bucket: Mutex<Bucket>,
+    in_flight: Option<Semaphore>,
+    metrics: Mutex<WaitMetrics>,
+}
+
+impl RateLimiter {
+    pub fn new(url: String, limits: RateLimits) -> Self {
+        let requests_per_second = limits.requests_per_second.map(|rps| f64::from(rps.max(1)));
+
+        Self {
+            url,
+            requests_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: requests_per_second.unwrap_or_default(),
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+            in_flight: limits.max_in_flight.map(|max| Semaphore::new(max.max(1) as usize)),
+            metrics: Mutex::new(WaitMetrics::new()),
+        }
+    }
+
+    /// Waits until a request can be sent without going over the limits
+    ///
+    /// Returns the in-flight permit to hold while the request is sent, if in-flight requests are
+    /// capped, and the time waited
+    async fn acquire(&self) -> (Option<SemaphorePermit<'_>>, Duration) {
+        let started_at = Instant::now();
+
+        while let Some(wait) = self.take_token() {
+            tokio::time::sleep(wait).await;
+        }
+        let permit = match &self.in_flight {
+            Some(semaphore) => Some(semaphore.acquire().await.expect("semaphore is never closed")),
+            None => None,
+        };
+
+        let waited = started_at.elapsed();
+        self.record_wait(waited);
+        (permit, waited)
+    }
+
+    /// Takes a token, or returns how long to wait before there may be one
+    fn take_token(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(paused_until) = bucket.paused_until {
+            if paused_until > now {
+                return Some(paused_until - now);
+            }
+            bucket.paused_until = None;
+        }
+
+        let requests_per_second = self.requests_per_second?;
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * requests_per_second).min(requests_per_second);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / requests_per_second))
+        }
+    }
+
+    /// Stops sending requests for `delay`, e.g. after the endpoint told us to back off
+    fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.paused_until = Some(bucket.paused_until.map_or(until, |paused| paused.max(until)));
+    }
+
+    /// Adds up wait times and logs them once every `METRICS_INTERVAL`
+    fn record_wait(&self, wait: Duration) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.requests += 1;
+        metrics.total_wait += wait;
+        metrics.max_wait = metrics.max_wait.max(wait);
+
+        if metrics.since.elapsed() >= METRICS_INTERVAL {
+            println!(
+                "[rpc] {}: {} requests waited {:?} for rate limits (average {:?}, max {:?})",
+                self.url,
+                metrics.requests,
+                metrics.total_wait,
+                metrics.total_wait / metrics.requests,
+                metrics.max_wait
+            );
+            *metrics = WaitMetrics::new();
+        }
+    }
+}
+
+/// Sends every request on once the rate limiter lets it through, within `REQUEST_TIMEOUT`
+pub struct LimitedTransport<T> {
+    transport: T,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<T: RawTransport> LimitedTransport<T> {
+    pub fn new(transport: T, limiter: Arc<RateLimiter>) -> Self {
+        Self { transport, limiter }
+    }
+}
+
+#[async_trait]
+impl<T: RawTransport> RawTransport for LimitedTransport<T> {
+    async fn send(&self, request: Value) -> Result<Value, TransportError> {
+        let (_permit, waited) = self.limiter.acquire().await;
+        // Calls made outside of `measure_limiter_wait` aren't measured
+        let _ = LIMITER_WAIT.try_with(|wait| wait.set(wait.get() + waited));
+
+        tokio::time::timeout(REQUEST_TIMEOUT, self.transport.send(request))
+            .await
+            .map_err(|_| TransportError::Timeout(REQUEST_TIMEOUT))?
+    }
+}
+
+/// Sends requests over HTTP, pausing the rate limiter of the endpoint when it answers 429
+pub struct HttpRawTransport {
+    client: Client,
+    url: Url,
+    limiter: Arc<RateLimiter>,
+}
+
+impl HttpRawTransport {
+    pub fn new(url: Url, limiter: Arc<RateLimiter>) -> Self {
+        Self { client: Client::new(), url, limiter }
+    }
+}
+
+#[async_trait]
+impl RawTransport for HttpRawTransport {
+    async fn send(&self, request: Value) -> Result<Value, TransportError> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = retry_after(response.headers()).unwrap_or(DEFAULT_RETRY_AFTER);
+                self.limiter.pause(retry_after);
+                Err(TransportError::RateLimited(retry_after))
+            }
+            status if !status.is_success() => Err(TransportError::Status(status)),
+            _ => Ok(serde_json::from_slice(&response.bytes().await?)?),
+        }
+    }
+}
+
+/// Reads a `Retry-After` header given in seconds, HTTP dates aren't supported
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let seconds = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_rate() {
+        let limiter = RateLimiter::new(
+            "test".to_string(),
+            RateLimits { requests_per_second: Some(2), max_in_flight: None },
+        );
+
+        // A full bucket lets a second worth of requests through at once
+        assert!(limiter.take_token().is_none());
+        assert!(limiter.take_token().is_none());
+        let wait = limiter.take_token().unwrap();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_pause_blocks_unlimited_endpoint() {
+        let limiter = RateLimiter::new("test".to_string(), RateLimits::default());
+        assert!(limiter.take_token().is_none());
+
+        limiter.pause(Duration::from_secs(5));
+        let wait = limiter.take_token().unwrap();
+        assert!(wait > Duration::from_secs(4) && wait <= Duration::from_secs(5));
+    }
+
+    /// Answers every request with itself
+    struct EchoTransport;
+
+    #[async_trait]
+    impl RawTransport for EchoTransport {
+        async fn send(&self, request: Value) -> Result<Value, TransportError> {
+            Ok(request)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_measures_limiter_wait() {
+        let limiter = Arc::new(RateLimiter::new(
+            "test".to_string(),
+            RateLimits { requests_per_second: Some(10), max_in_flight: None },
+        ));
+        let transport = LimitedTransport::new(EchoTransport, limiter);
+
+        // A second worth of requests goes through at once, the one after it waits
+        let (sent, waited) = measure_limiter_wait(async {
+            for _ in 0..11 {
+                transport.send(Value::Null).await.unwrap();
+            }
+            11
+        })
+        .await;
+        assert_eq!(sent, 11);
+        assert!(waited >= Duration::from_millis(50), "{waited:?}");
+
+        let ((), waited) = measure_limiter_wait(async {}).await;
+        assert_eq!(waited, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(header::RETRY_AFTER, "3".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+
+        headers.insert(header::RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+}